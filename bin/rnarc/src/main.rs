@@ -0,0 +1,343 @@
+use std::fs::File;
+use std::path::Path;
+
+use clap::{Parser, Subcommand};
+use nscripter_formats::archive::*;
+
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Arguments {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Rewrite an archive as a different archive type, the types are picked by the file extensions (.sar, .nsa, .ns2).
+    Convert {
+        /// Archive to read.
+        #[arg(long)]
+        from: String,
+
+        /// Archive to write.
+        #[arg(long)]
+        to: String,
+
+        /// Offset of data within the archive being read.
+        #[arg(long, default_value_t = 0)]
+        offset: u32,
+
+        /// Overwrite the output archive if it exists.
+        #[arg(short, long, default_value_t = false)]
+        force: bool,
+
+        /// Compress WAV files with Bzip2 (NSA only).
+        #[arg(long, default_value_t = false)]
+        bzip2: bool,
+
+        /// Compress BMP files with SPB (NSA only).
+        #[arg(long, default_value_t = false)]
+        spb: bool,
+
+        /// Compress BMP files with LZSS when they're not being SPB compressed (NSA only).
+        #[arg(long, default_value_t = false)]
+        lzss: bool,
+    },
+
+    /// Extract whatever can still be read from a damaged archive, skipping entries that can't be and reporting why.
+    Salvage {
+        /// Archive to read, the type is picked by the file extension (.sar, .nsa, .ns2).
+        #[arg(long)]
+        from: String,
+
+        /// Directory to extract into.
+        #[arg(long)]
+        to: String,
+
+        /// Offset of data within the archive being read.
+        #[arg(long, default_value_t = 0)]
+        offset: u32,
+
+        /// Extract into the output directory even if it exists.
+        #[arg(short, long, default_value_t = false)]
+        force: bool,
+
+        /// When the header is broken, empty, or entries are skipped, also look through the parts of the archive no extracted
+        /// entry covers for BMP, WAV/AVI and Ogg files and write them out as salvaged_0000.bmp and so on.
+        #[arg(long, default_value_t = false)]
+        scan: bool,
+    },
+
+    /// List an archive's entries with their stored size and compression.
+    List {
+        /// Archive to read, the type is picked by the file extension (.sar, .nsa, .ns2).
+        archive: String,
+
+        /// Offset of data within the archive.
+        #[arg(long, default_value_t = 0)]
+        offset: u32,
+
+        /// Rather than each entry, list how many entries there are of each extension and how much they take up.
+        #[arg(long, default_value_t = false)]
+        summary: bool,
+    },
+
+    /// Check an archive holds exactly the files of a directory, extracting every entry and comparing it with the file of
+    /// the same name, and list the entries that are missing, extra or different. Exits with 1 if there are any.
+    Verify {
+        /// Archive to check, the type is picked by the file extension (.sar, .nsa, .ns2).
+        #[arg(long)]
+        archive: String,
+
+        /// Directory the archive was built from.
+        #[arg(long)]
+        dir: String,
+
+        /// Offset of data within the archive.
+        #[arg(long, default_value_t = 0)]
+        offset: u32,
+    },
+
+    /// Print the archive's header as hex and ASCII, sixteen bytes a line, from the start of the file to where the
+    /// entries' data starts, with the key table undone as it is when the header's read.
+    HexdumpHeader {
+        /// Archive to read, the type is picked by the file extension (.sar, .nsa, .ns2).
+        #[arg(long)]
+        archive: String,
+
+        /// Offset of data within the archive.
+        #[arg(long, default_value_t = 0)]
+        offset: u32,
+    },
+
+    /// List every entry of every archive in a directory and the directories under it, by reading their headers, into a
+    /// JSON catalog of each entry's archive, name, size, compression and decompressed size.
+    Index {
+        /// Directory to look for archives in, they're recognized by their extension (.sar, .nsa, .ns2).
+        dir: String,
+
+        /// JSON file to write the catalog to.
+        #[arg(long)]
+        out: String,
+    },
+}
+
+fn archive_type_from_path(path: &Path) -> Option<ArchiveType> {
+    let extension = path.extension()?.to_str()?.to_lowercase();
+
+    match extension.as_str() {
+        "sar" => Some(ArchiveType::SAR),
+        "nsa" => Some(ArchiveType::NSA),
+        "ns2" => Some(ArchiveType::NS2),
+        _ => None
+    }
+}
+
+fn convert(from: &Path, to: &Path, offset: u32, force: bool, options: &CreateOptions) -> Result<(), String> {
+    let source_type = archive_type_from_path(from).ok_or(format!("Can't tell what type of archive {} is from its extension", from.display()))?;
+    let target_type = archive_type_from_path(to).ok_or(format!("Can't tell what type of archive to write {} as from its extension", to.display()))?;
+
+    if to.exists() && !force {
+        return Err(format!("{} exists, pass --force or -f to overwrite it.", to.display()));
+    }
+
+    let file = File::open(from).map_err(|error| format!("Couldn't open {}: {}", from.display(), error))?;
+    let mut source = Archive::try_open_file_with_options(file, source_type, offset, &nscripter_formats::default_keytable(), &ReadOptions::default())
+        .map_err(|error| format!("Couldn't read {}: {}", from.display(), error))?;
+
+    let out = File::create(to).map_err(|error| format!("Couldn't create {}: {}", to.display(), error))?;
+    if let Err(error) = Archive::convert(&mut source, out, target_type, options) {
+        let _ = std::fs::remove_file(to);
+        return Err(format!("Failed to convert {} to {}: {}", from.display(), to.display(), error));
+    }
+
+    println!("Converted {} entries from {:?} to {:?}", source.index.entries.len(), source_type, target_type);
+    Ok(())
+}
+
+fn write_salvaged(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    std::fs::create_dir_all(path.parent().unwrap())?;
+    std::fs::write(path, data)
+}
+
+fn salvage(from: &Path, to: &Path, offset: u32, force: bool, scan: bool) -> Result<(), String> {
+    let source_type = archive_type_from_path(from).ok_or(format!("Can't tell what type of archive {} is from its extension", from.display()))?;
+
+    if to.exists() && !force {
+        return Err(format!("{} exists, pass --force or -f to extract into it anyway.", to.display()));
+    }
+
+    let file = File::open(from).map_err(|error| format!("Couldn't open {}: {}", from.display(), error))?;
+    let file_size = file.metadata().map_err(|error| format!("Couldn't read {}: {}", from.display(), error))?.len() as usize;
+    let (mut source, header_error) = Archive::try_salvage(file, source_type, offset, &nscripter_formats::default_keytable());
+
+    if let Some(error) = &header_error {
+        println!("Only {} entries of the header could be read, it stops with: {}", source.index.entries.len(), error);
+    }
+
+    // Where each extracted entry is in the file, the scan only keeps what it finds outside of them.
+    let mut extracted = Vec::new();
+
+    for i in 0..source.index.entries.len() {
+        let entry = &source.index.entries[i];
+        let name = entry.name.clone();
+
+        if entry.offset.saturating_add(entry.size) > file_size {
+            println!("Skipping {name}, it runs past the end of the archive");
+            continue;
+        }
+
+        let Some(path) = sanitize_entry_path(&name, to) else {
+            println!("Skipping {name:?}, it would be written outside of {}", to.display());
+            continue;
+        };
+
+        let range = entry.offset..entry.offset + entry.size;
+        let info = entry.info();
+        let result = source.try_extract(info).map_err(|error| error.to_string())
+            .and_then(|data| write_salvaged(&path, &data).map_err(|error| error.to_string()));
+
+        match result {
+            Ok(()) => extracted.push(range),
+            Err(error) => println!("Skipping {name}: {error}")
+        }
+    }
+
+    println!("Extracted {} of {} entries from {}", extracted.len(), source.index.entries.len(), from.display());
+
+    if scan && (header_error.is_some() || source.index.entries.is_empty() || extracted.len() < source.index.entries.len()) {
+        let data = std::fs::read(from).map_err(|error| format!("Couldn't read {}: {}", from.display(), error))?;
+        let region = data.get(offset as usize..).unwrap_or_default();
+        let found: Vec<_> = nscripter_formats::salvage::find_embedded_files(region).into_iter()
+            .filter(|embedded| !extracted.iter().any(|range| range.contains(&(offset as usize + embedded.offset))))
+            .collect();
+
+        for (i, embedded) in found.iter().enumerate() {
+            let path = to.join(format!("salvaged_{:04}.{}", i, embedded.extension));
+            write_salvaged(&path, &region[embedded.offset..embedded.offset + embedded.size]).map_err(|error| format!("Couldn't write {}: {}", path.display(), error))?;
+            println!("Found a {} at {}, writing it to {}", embedded.extension, offset as usize + embedded.offset, path.display());
+        }
+
+        println!("Found {} more files by scanning what the extracted entries don't cover", found.len());
+    }
+
+    Ok(())
+}
+
+fn list(archive: &Path, offset: u32, summary: bool) -> Result<(), String> {
+    let archive_type = archive_type_from_path(archive).ok_or(format!("Can't tell what type of archive {} is from its extension", archive.display()))?;
+    let file = File::open(archive).map_err(|error| format!("Couldn't open {}: {}", archive.display(), error))?;
+    let source = Archive::try_open_file_with_options(file, archive_type, offset, &nscripter_formats::default_keytable(), &ReadOptions::default())
+        .map_err(|error| format!("Couldn't read {}: {}", archive.display(), error))?;
+
+    if !summary {
+        for entry in &source.index.entries {
+            println!("{} {} ({:?})", entry.name, entry.size, entry.compression);
+        }
+        return Ok(());
+    }
+
+    for (extension, (count, size)) in source.index.extension_histogram() {
+        let extension = if extension.is_empty() { "(none)".to_string() } else { format!(".{extension}") };
+        println!("{extension}: {count} entries, {size} bytes");
+    }
+    println!("{} entries in total", source.index.entries.len());
+    Ok(())
+}
+
+fn verify(archive: &Path, dir: &Path, offset: u32) -> Result<(), String> {
+    let archive_type = archive_type_from_path(archive).ok_or(format!("Can't tell what type of archive {} is from its extension", archive.display()))?;
+    if !dir.is_dir() {
+        return Err(format!("{} isn't a directory", dir.display()));
+    }
+
+    let file = File::open(archive).map_err(|error| format!("Couldn't open {}: {}", archive.display(), error))?;
+    let mut source = Archive::try_open_file_with_options(file, archive_type, offset, &nscripter_formats::default_keytable(), &ReadOptions::default())
+        .map_err(|error| format!("Couldn't read {}: {}", archive.display(), error))?;
+
+    let discrepancies = source.verify_against_dir(dir);
+    for discrepancy in &discrepancies {
+        match discrepancy {
+            Discrepancy::Missing { name } => println!("Missing from {}: {}", dir.display(), name),
+            Discrepancy::Extra { path } => println!("Missing from {}: {}", archive.display(), path),
+            Discrepancy::Differs { name } => println!("Differs: {name}"),
+            Discrepancy::Unreadable { name, error } => println!("Couldn't extract {name}: {error}")
+        }
+    }
+
+    if !discrepancies.is_empty() {
+        return Err(format!("{} doesn't match {}, {} differences", archive.display(), dir.display(), discrepancies.len()));
+    }
+
+    println!("{} matches {}, {} entries", archive.display(), dir.display(), source.index.entries.len());
+    Ok(())
+}
+
+fn hexdump_header(archive: &Path, offset: u32) -> Result<(), String> {
+    let archive_type = archive_type_from_path(archive).ok_or(format!("Can't tell what type of archive {} is from its extension", archive.display()))?;
+    let file = File::open(archive).map_err(|error| format!("Couldn't open {}: {}", archive.display(), error))?;
+    let mut source = Archive::try_open_file_with_options(file, archive_type, offset, &nscripter_formats::default_keytable(), &ReadOptions::default())
+        .map_err(|error| format!("Couldn't read {}: {}", archive.display(), error))?;
+
+    let header = source.try_header_bytes().map_err(|error| format!("Couldn't read the header of {}: {}", archive.display(), error))?;
+    for (line, bytes) in header.chunks(16).enumerate() {
+        let hex: Vec<String> = bytes.iter().map(|byte| format!("{byte:02x}")).collect();
+        let ascii: String = bytes.iter().map(|&byte| if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' }).collect();
+        println!("{:08x}: {:<47}  {}", line * 16, hex.join(" "), ascii);
+    }
+
+    Ok(())
+}
+
+fn json_string(value: &str) -> String {
+    let mut escaped = String::from("\"");
+    for character in value.chars() {
+        match character {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            character if character.is_control() => escaped.push_str(&format!("\\u{:04x}", character as u32)),
+            character => escaped.push(character)
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+fn index(dir: &Path, out: &Path) -> Result<(), String> {
+    if !dir.is_dir() {
+        return Err(format!("{} isn't a directory", dir.display()));
+    }
+
+    let catalog = nscripter_formats::vfs::catalog_archives(dir, &nscripter_formats::default_keytable());
+    let entries: Vec<String> = catalog.iter().map(|entry| format!(
+        "  {{\"archive\": {}, \"name\": {}, \"size\": {}, \"compression\": \"{:?}\", \"decompressed_size\": {}}}",
+        json_string(&entry.archive.to_string_lossy()), json_string(&entry.name), entry.size, entry.compression,
+        entry.decompressed_size.map_or("null".to_string(), |size| size.to_string())
+    )).collect();
+
+    std::fs::write(out, format!("[\n{}\n]\n", entries.join(",\n"))).map_err(|error| format!("Couldn't write {}: {}", out.display(), error))?;
+    println!("Catalogued {} entries from {}", catalog.len(), dir.display());
+    Ok(())
+}
+
+fn main() {
+    let arguments = Arguments::parse();
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).format_timestamp(None).init();
+
+    let result = match &arguments.command {
+        Command::Convert { from, to, offset, force, bzip2, spb, lzss } => {
+            let options = CreateOptions { bzip2: *bzip2, spb: *spb, lzss: *lzss, ..Default::default() };
+            convert(Path::new(from), Path::new(to), *offset, *force, &options)
+        }
+        Command::Salvage { from, to, offset, force, scan } => salvage(Path::new(from), Path::new(to), *offset, *force, *scan),
+        Command::List { archive, offset, summary } => list(Path::new(archive), *offset, *summary),
+        Command::Verify { archive, dir, offset } => verify(Path::new(archive), Path::new(dir), *offset),
+        Command::HexdumpHeader { archive, offset } => hexdump_header(Path::new(archive), *offset),
+        Command::Index { dir, out } => index(Path::new(dir), Path::new(out)),
+    };
+
+    if let Err(error) = result {
+        println!("{error}");
+        std::process::exit(1);
+    }
+}
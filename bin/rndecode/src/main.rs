@@ -1,25 +1,35 @@
-use std::{fs::File, io::Write, path::Path};
+use std::{collections::HashSet, fs::File, io::{Read, Write}, path::{Path, PathBuf}};
 
 use clap::Parser;
 use nscripter_formats::archive::*;
-use nscripter_formats::image::decode_spb;
+use nscripter_formats::KeyTable;
+use nscripter_formats::image::{spb_channel_stats, try_indexed_png, Image, MaskMode};
+use nscripter_formats::script::{decode_script, file_name_to_decode_info, Encoding};
+use nscripter_formats::shiftjis::ShiftJisTable;
 
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(version, about, long_about = None, args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Arguments {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Name of the path to read
-    #[arg(short, long)]
-    path: String,
+    #[arg(short, long, required = true)]
+    path: Option<String>,
 
     /// Name of the directory to output files.
-    #[arg(short, long)]
-    output: String,
+    #[arg(short, long, required = true)]
+    output: Option<String>,
 
-    /// Offset of data within archive
+    /// Offset of data within archive. When a directory is read without one, it's taken from the game's ons.cfg or
+    /// found by searching the first archive for its header, if the archives don't open as they are.
     #[arg(long, default_value_t = 0)]
     offset: u32,
     
     /// This will determine if we should list out File by File what we're extracting.
+    ///
+    /// Also turns on debug logging from reading and writing archives, which otherwise only logs what it works around.
+    /// RUST_LOG, if set, takes precedence.
     #[arg(short, long, default_value_t = false)]
     verbose: bool,
     
@@ -28,65 +38,723 @@ struct Arguments {
     /// Otherwise, we'll fail out when trying to overwrite a file.
     #[arg(short, long, default_value_t = false)]
     force: bool,
+
+    /// Unicode normalization applied to entry names when writing them to disk, the names stored in the archive are unaffected.
+    #[arg(long, value_enum, default_value_t = NormalizeNames::None)]
+    normalize_names: NormalizeNames,
+
+    /// Rather than stopping at the first entry that fails to extract, report it and move on. A summary of the failures
+    /// is printed at the end, and we'll exit with a nonzero code if there were any.
+    #[arg(long, default_value_t = false)]
+    continue_on_error: bool,
+
+    /// Treat the path as a file with an archive appended to it, usually the game's executable. We'll search backwards
+    /// from the end of the file for a SAR or NSA header, report the offset it was found at, and extract it.
+    #[arg(long, default_value_t = false)]
+    in_exe: bool,
+
+    /// Rather than copying loose files we don't otherwise process, list the ones whose type we couldn't determine from
+    /// their contents or name. Handy for spotting assets in a format we don't know about yet.
+    #[arg(long, default_value_t = false)]
+    report_unknown: bool,
+
+    /// Also write out the decoded text of any scripts we extract, recognized by their names, next to them as <name>.decoded.txt.
+    #[arg(long, default_value_t = false)]
+    decode_scripts: bool,
+
+    /// Entry names that aren't valid Shift-JIS are read with replacement characters, and the entries reported, rather
+    /// than failing to open the archive. The rest of the archive can then still be extracted.
+    #[arg(long, default_value_t = false)]
+    lossy_names: bool,
+
+    /// Decode entry names and scripts with Microsoft's CP932 table rather than standard Shift-JIS. They only differ on
+    /// the single bytes 0xA0 and 0xFD to 0xFF, which some games made on Windows have in their text.
+    #[arg(long, default_value_t = false)]
+    cp932: bool,
+
+    /// File to take the key table archives are obfuscated with from, usually the game's executable. Without it
+    /// archives are read as they are.
+    #[arg(long)]
+    key_file: Option<String>,
+
+    /// Also write SPB images out as PNG sprites next to their BMPs, with transparency taken from the mask half of the
+    /// image (alpha) or the color of its top left or top right pixel, as ONScripter would display them.
+    #[arg(long, value_enum)]
+    sprite_alpha: Option<SpriteAlpha>,
+
+    /// Also write images we extract, SPBs and BMPs, out as PNGs next to them. Images with 256 colors or fewer are
+    /// written with a palette, which for most backgrounds is a good deal smaller, and the rest as truecolor.
+    #[arg(long, default_value_t = false, conflicts_with = "sprite_alpha")]
+    png_indexed: bool,
+
+    /// Directory holding an earlier extraction to compare against, laid out as the output directory would be. Only
+    /// entries that are new or whose contents differ from the file there are extracted, and each of them is listed.
+    #[arg(long)]
+    diff_against: Option<String>,
+
+    /// Stop extracting archive entries once the next one would take the total written past this many bytes. The
+    /// entries left out are listed at the end, so a large archive can be sampled without filling the disk.
+    #[arg(long)]
+    max_total_size: Option<u64>,
+
+    /// Carry on an extraction that was interrupted: the output directory is kept, and entries already there with the
+    /// size they decompress to are skipped. Anything missing or cut short is extracted again.
+    #[arg(long, default_value_t = false, conflicts_with = "force")]
+    resume: bool,
+
+    /// Name each extracted entry's file after this template rather than its name in the archive, keeping the
+    /// directories it's in. {index} is the entry's position in the archive, zero padded to N digits with {index:0N},
+    /// {name} its file name, {stem} its file name without the extension and {ext} the extension alone. For sortable
+    /// output, try "{index:04}_{name}".
+    #[arg(long, value_parser = parse_name_template)]
+    name_template: Option<String>,
+
+    /// Only extract entries whose data starts at or after this many bytes into the archive's file, such as content
+    /// appended to it at a known point.
+    #[arg(long)]
+    after_offset: Option<usize>,
+
+    /// Write every entry straight into its archive's output directory under its file name alone, leaving out the
+    /// directories it's in, for SAR, NSA and NS2 archives alike. Entries sharing a file name are written over each
+    /// other, leaving the last, which is warned about.
+    #[arg(long, default_value_t = false)]
+    flatten: bool,
+
+    /// As --flatten, but only the first entry with each file name, ignoring case, is extracted, the rest are listed as
+    /// skipped rather than written over it.
+    #[arg(long, default_value_t = false)]
+    unique_basename: bool,
+
+    /// Replace characters Windows doesn't allow in file names, such as : and ?, in entry names with _, and rename
+    /// entries named after devices like CON. Each archive's renamed entries are listed in a .name-mapping.txt file
+    /// next to its output directory, a line per entry with its name in the archive and the name on disk, tab
+    /// separated. Passing it to rnencode's --name-mapping stores them under their original names again.
+    #[arg(long, default_value_t = false)]
+    safe_names: bool,
 }
 
-fn extract_files(path : &Path, archive_type : ArchiveType, offset : u32, output_dir : &Path, verbose: bool) {
-    let file = std::fs::File::open(&path).unwrap();
-    let mut reader : Archive = Archive::open_file(file, archive_type, offset, nscripter_formats::default_keytable());
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Decode a single loose .spb or .nbz file to exactly the file asked for, rather than extracting everything under a
+    /// path. Without --format the decoded data is written as it is, the BMP an SPB holds or whatever an NBZ holds.
+    DecodeFile {
+        /// The .spb or .nbz file to decode.
+        #[arg(long = "in")]
+        input: String,
+
+        /// File to write, overwritten if it exists.
+        #[arg(long)]
+        out: String,
+
+        /// Convert the decoded image to this format, which needs the decoded data to be a BMP. rgb is the bare pixels,
+        /// three bytes each, top row first.
+        #[arg(long, value_enum)]
+        format: Option<ImageFormat>,
+
+        /// File to take the key table the file is obfuscated with from, usually the game's executable.
+        #[arg(long)]
+        key_file: Option<String>,
+    },
+
+    /// Print how a loose .spb file's encoder spent its bits: for each channel, how many runs of four bytes repeat the
+    /// last byte, are stored whole, or are stored as differences of each bit width.
+    SpbStats {
+        /// The .spb file to look at.
+        #[arg(long = "in")]
+        input: String,
+
+        /// File to take the key table the file is obfuscated with from, usually the game's executable.
+        #[arg(long)]
+        key_file: Option<String>,
+    },
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum ImageFormat {
+    Bmp,
+    Png,
+    Rgb,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum SpriteAlpha {
+    Alpha,
+    TopLeft,
+    TopRight,
+}
+
+impl From<SpriteAlpha> for MaskMode {
+    fn from(value: SpriteAlpha) -> Self {
+        match value {
+            SpriteAlpha::Alpha => MaskMode::Alpha,
+            SpriteAlpha::TopLeft => MaskMode::TopLeft,
+            SpriteAlpha::TopRight => MaskMode::TopRight,
+        }
+    }
+}
+
+// Writes the BMP an SPB decoded to as a PNG sprite alongside it.
+fn write_sprite(bmp : &[u8], bmp_path : &Path, sprite_alpha : SpriteAlpha, arguments : &Arguments) -> Result<(), Box<dyn std::error::Error>> {
+    let png_path = bmp_path.with_extension("png");
+
+    if arguments.verbose {
+        println!("Writing sprite {} to {}", bmp_path.to_str().unwrap(), png_path.to_str().unwrap());
+    }
+
+    let png = Image::from_bmp_bytes(bmp)?.to_sprite(sprite_alpha.into()).to_png()?;
+    std::fs::write(png_path, png)?;
+    Ok(())
+}
+
+// Writes a BMP we extracted as a PNG alongside it, paletted if it can be without losing any colors.
+fn write_png(bmp : &[u8], bmp_path : &Path, arguments : &Arguments) -> Result<(), Box<dyn std::error::Error>> {
+    let png_path = bmp_path.with_extension("png");
+    let image = Image::from_bmp_bytes(bmp)?;
+
+    let png = match try_indexed_png(&image) {
+        Some(png) => png,
+        None => image.to_png()?
+    };
+
+    if arguments.verbose {
+        println!("Writing {} to {}", bmp_path.to_str().unwrap(), png_path.to_str().unwrap());
+    }
+
+    std::fs::write(png_path, png)?;
+    Ok(())
+}
+
+// A piece of a --name-template, either text kept as it is or one of the {} tokens.
+enum TemplatePart<'a> {
+    Text(&'a str),
+    Index(usize),
+    Name,
+    Stem,
+    Extension
+}
+
+fn template_parts(template : &str) -> Result<Vec<TemplatePart<'_>>, String> {
+    let mut parts = Vec::new();
+    let mut rest = template;
+
+    while let Some(start) = rest.find(['{', '}']) {
+        if rest[start..].starts_with('}') {
+            return Err(format!("there's a }} without a {{ before it in {template}"));
+        }
+        let Some(length) = rest[start..].find('}') else {
+            return Err(format!("there's a {{ without a }} after it in {template}"));
+        };
+
+        if start > 0 {
+            parts.push(TemplatePart::Text(&rest[..start]));
+        }
+
+        let part = match &rest[start + 1..start + length] {
+            "index" => TemplatePart::Index(0),
+            "name" => TemplatePart::Name,
+            "stem" => TemplatePart::Stem,
+            "ext" => TemplatePart::Extension,
+            token => match token.strip_prefix("index:0").map(str::parse::<usize>) {
+                Some(Ok(width)) => TemplatePart::Index(width),
+                _ => return Err(format!("{{{token}}} isn't one of {{index}}, {{index:0N}}, {{name}}, {{stem}} or {{ext}}"))
+            }
+        };
+        parts.push(part);
+
+        rest = &rest[start + length + 1..];
+    }
+
+    if !rest.is_empty() {
+        parts.push(TemplatePart::Text(rest));
+    }
+
+    Ok(parts)
+}
+
+fn parse_name_template(template : &str) -> Result<String, String> {
+    if !template_parts(template)?.iter().any(|part| !matches!(part, TemplatePart::Text(_))) {
+        return Err("the template needs at least one of {index}, {name}, {stem} or {ext}, or every entry would get the same name".to_string());
+    }
+
+    Ok(template.to_string())
+}
+
+// The file name a --name-template gives the entry at index, which has the file name name. The template was checked
+// when the arguments were parsed.
+fn format_entry_name(template : &str, index : usize, name : &str) -> String {
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, extension),
+        _ => (name, "")
+    };
+
+    template_parts(template).unwrap().iter().map(|part| match part {
+        TemplatePart::Text(text) => text.to_string(),
+        TemplatePart::Index(width) => format!("{index:0width$}"),
+        TemplatePart::Name => name.to_string(),
+        TemplatePart::Stem => stem.to_string(),
+        TemplatePart::Extension => extension.to_string()
+    }).collect()
+}
+
+// Where under the output directory the entry at index, named entry_name, is written, with --name-template,
+// the layout and --safe-names applied.
+fn output_entry_name(entry_name : &str, index : usize, arguments : &Arguments) -> String {
+    let entry_name = extract_layout(arguments).entry_path(entry_name);
+    let (directory, file_name) = match entry_name.rfind(['\\', '/']) {
+        Some(separator) => entry_name.split_at(separator + 1),
+        None => ("", entry_name)
+    };
+
+    let name = match &arguments.name_template {
+        Some(template) => format!("{directory}{}", format_entry_name(template, index, file_name)),
+        None => format!("{directory}{file_name}")
+    };
+
+    if arguments.safe_names { safe_os_filename(&name).0 } else { name }
+}
+
+// The entry at index's name in the archive and where it's written, if --safe-names means they differ.
+fn renamed_entry(reader : &Archive, i : usize, arguments : &Arguments) -> Option<(String, String)> {
+    let entry_name = &reader.index.entries[i].name;
+    let normalized = normalize_name(entry_name, arguments.normalize_names.into());
+
+    if !arguments.safe_names || !safe_os_filename(&normalized).1 {
+        return None;
+    }
+    Some((entry_name.clone(), output_entry_name(&normalized, i, arguments)))
+}
+
+// The file names, lowercased, that --unique-basename has let through from an archive, and the entries it's left out
+// since their file name was already taken.
+#[derive(Default)]
+struct SeenBasenames {
+    seen : HashSet<String>,
+    skipped : Vec<String>
+}
+
+impl SeenBasenames {
+    // Whether the entry is the first with its file name, if not it's noted as skipped.
+    fn first(&mut self, entry_name : &str) -> bool {
+        let basename = entry_name.rsplit(['\\', '/']).next().unwrap_or(entry_name).to_lowercase();
+        let first = self.seen.insert(basename);
+
+        if !first {
+            self.skipped.push(entry_name.to_string());
+        }
+        first
+    }
+}
+
+fn extract_layout(arguments : &Arguments) -> ExtractLayout {
+    if arguments.flatten || arguments.unique_basename { ExtractLayout::Flatten } else { ExtractLayout::PreserveTree }
+}
+
+fn output_path(arguments : &Arguments) -> &Path {
+    Path::new(arguments.output.as_deref().unwrap())
+}
+
+fn shift_jis_table(arguments : &Arguments) -> ShiftJisTable {
+    if arguments.cp932 { ShiftJisTable::Cp932 } else { ShiftJisTable::Whatwg }
+}
+
+fn key_table(arguments : &Arguments) -> KeyTable {
+    key_table_from(arguments.key_file.as_deref())
+}
+
+fn key_table_from(key_file : Option<&str>) -> KeyTable {
+    let Some(key_file) = key_file else {
+        return nscripter_formats::default_keytable();
+    };
+
+    match KeyTable::from_file(Path::new(key_file)) {
+        Some(key_table) => key_table,
+        None => {
+            println!("Couldn't find a key table in {key_file}");
+            std::process::exit(1);
+        }
+    }
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum NormalizeNames {
+    None,
+    Nfc,
+    Nfkc,
+}
+
+impl From<NormalizeNames> for NameNormalization {
+    fn from(value: NormalizeNames) -> Self {
+        match value {
+            NormalizeNames::None => NameNormalization::None,
+            NormalizeNames::Nfc => NameNormalization::Nfc,
+            NormalizeNames::Nfkc => NameNormalization::Nfkc,
+        }
+    }
+}
+
+// How much --max-total-size has let be extracted so far, and the entries it's left out since it ran out.
+#[derive(Default)]
+struct SizeBudget {
+    written : u64,
+    skipped : Vec<String>,
+}
+
+impl SizeBudget {
+    // Whether the entry fits, counting it against the budget if it does. Once one entry doesn't fit, none after it
+    // are extracted either, so what's extracted is always the start of the archives.
+    fn take(&mut self, max_total_size : u64, size : u64) -> bool {
+        if !self.skipped.is_empty() || self.written.saturating_add(size) > max_total_size {
+            return false;
+        }
+
+        self.written += size;
+        true
+    }
+}
+
+fn extract_entry(reader : &mut Archive, i : usize, path : &Path, output_dir : &Path, arguments : &Arguments) -> Result<(), Box<dyn std::error::Error>> {
+    let info = reader.index.entries[i].info();
+
+    if matches!(info.compression, Compression::Spb) {
+        println!("Extracting {}", &reader.index.entries[i].name);
+    }
+    
+    let data = reader.try_extract(info)?;
+
+    let entry = &reader.index.entries[i];
+    let entry_name = normalize_name(&entry.name, arguments.normalize_names.into());
+    let entry_path = Path::new(&entry_name);
+    let new_path = sanitize_entry_path(&output_entry_name(&entry_name, i, arguments), output_dir).ok_or_else(|| format!("{entry_name} would be written outside of the output directory"))?;
+
+    if let Some(reference_dir) = &arguments.diff_against {
+        let reference_path = Path::new(reference_dir).join(new_path.strip_prefix(output_path(arguments))?);
+
+        if matches_file(&data, &reference_path)? {
+            return Ok(());
+        }
+        println!("Changed: {}", new_path.to_str().unwrap());
+    }
+
+    std::fs::create_dir_all(new_path.parent().unwrap())?;
+    
+    if arguments.verbose {
+        println!("Extracting file {} from archive {} to {}", entry_path.to_str().unwrap(), path.to_str().unwrap(), new_path.to_str().unwrap());
+    }
+
+    let mut file = File::create(&new_path)?;
+    file.write_all(&data)?;
+
+    if let (Some(sprite_alpha), Compression::Spb) = (arguments.sprite_alpha, entry.compression) {
+        write_sprite(&data, &new_path, sprite_alpha, arguments)?;
+    }
+
+    if arguments.png_indexed && detect_file_type(&data) == ".bmp" {
+        write_png(&data, &new_path, arguments)?;
+    }
+
+    if arguments.decode_scripts {
+        if let Ok((encoding, obfuscation)) = file_name_to_decode_info(entry_path) {
+            let encoding = match encoding {
+                Encoding::ShiftJIS if arguments.cp932 => Encoding::Cp932,
+                encoding => encoding
+            };
+            let text = decode_script(data, encoding, obfuscation, reader.key_table())?;
+            let text_path = new_path.with_file_name(format!("{}.decoded.txt", new_path.file_name().unwrap().to_str().unwrap()));
+
+            if arguments.verbose {
+                println!("Decoding script {} to {}", entry_path.to_str().unwrap(), text_path.to_str().unwrap());
+            }
+
+            std::fs::write(text_path, text)?;
+        }
+    }
+
+    Ok(())
+}
+
+// Whether an interrupted run already wrote the entry out whole, going by its size, as files are written in one go.
+fn already_extracted(reader : &mut Archive, i : usize, output_dir : &Path, arguments : &Arguments) -> bool {
+    let entry_name = normalize_name(&reader.index.entries[i].name, arguments.normalize_names.into());
+    let Some(path) = sanitize_entry_path(&output_entry_name(&entry_name, i, arguments), output_dir) else {
+        return false;
+    };
+    let Ok(metadata) = std::fs::metadata(path) else {
+        return false;
+    };
+
+    reader.try_decompressed_size(reader.index.entries[i].info()).is_ok_and(|size| metadata.len() == size as u64)
+}
+
+fn extract_files(path : &Path, archive_type : ArchiveType, offset : u32, output_dir : &Path, arguments : &Arguments, failures : &mut Vec<String>, budget : &mut SizeBudget) {
+    let file = std::fs::File::open(path).unwrap();
+    let options = ReadOptions { lossy_names: arguments.lossy_names, shift_jis: shift_jis_table(arguments) };
+    // Without an offset to read at, archives that had bytes put in front of them in transfer are still found.
+    let opened = match offset {
+        0 => Archive::try_open_auto(file, archive_type, &key_table(arguments), &options),
+        offset => Archive::try_open_file_with_options(file, archive_type, offset, &key_table(arguments), &options)
+    };
+    let mut reader : Archive = match opened {
+        Ok(reader) => reader,
+        Err(error) => {
+            println!("Couldn't open {}: {}", path.to_str().unwrap(), error);
+            std::process::exit(1);
+        }
+    };
+
+    if let Some(alignment) = reader.index.alignment() {
+        println!("Archive {} has its entries aligned to {} bytes, rnencode --align {} keeps them that way", path.to_str().unwrap(), alignment, alignment);
+    }
+
+    for name in reader.index.duplicate_names() {
+        println!("Archive {} has more than one entry named {}, only the last will be left on disk", path.to_str().unwrap(), name);
+    }
+
+    if extract_layout(arguments) == ExtractLayout::Flatten && !arguments.unique_basename {
+        let mut flattened = HashSet::new();
+        let collisions = reader.index.entries.iter().filter(|entry| !flattened.insert(ExtractLayout::Flatten.entry_path(&entry.name))).count();
+
+        if collisions > 0 {
+            println!("Archive {} has {} entries whose file name an earlier entry already has, flattening leaves only the last of each on disk, --unique-basename keeps the first", path.to_str().unwrap(), collisions);
+        }
+    }
+
+    if let Some(offset) = arguments.after_offset {
+        println!("Extracting the {} of {} entries of {} whose data starts at or after byte {}", reader.entries_after(offset).len(), reader.index.entries.len(), path.to_str().unwrap(), offset);
+    }
+
+    let mut resumed = 0;
+    let mut basenames = SeenBasenames::default();
+    let mut renamed = Vec::new();
 
     for i in 0..reader.index.entries.len() {
-        let info = reader.index.entries[i].info();
+        if arguments.after_offset.is_some_and(|offset| reader.index.entries[i].offset < offset) {
+            continue;
+        }
 
-        if matches!(info.compression, Compression::Spb) {
-            println!("Extracting {}", &reader.index.entries[i].name);
+        if arguments.unique_basename && !basenames.first(&reader.index.entries[i].name) {
+            continue;
         }
-        
-        let data = reader.extract(info);
 
-        let entry = &reader.index.entries[i];
-        let entry_name = entry.name.clone();
-        let entry_path = Path::new(&entry_name);
-        let new_path = output_dir.join(entry_path);
+        if arguments.resume && already_extracted(&mut reader, i, output_dir, arguments) {
+            renamed.extend(renamed_entry(&reader, i, arguments));
+            resumed += 1;
+            continue;
+        }
 
-        std::fs::create_dir_all(&new_path.parent().unwrap()).unwrap();
-        
-        if verbose {
-            println!("Extracting file {} from archive {} to {}", entry_path.to_str().unwrap(), path.to_str().unwrap(), new_path.to_str().unwrap());
+        if let Some(max_total_size) = arguments.max_total_size {
+            let size = reader.try_decompressed_size(reader.index.entries[i].info()).unwrap_or(usize::MAX);
+
+            if !budget.take(max_total_size, size as u64) {
+                budget.skipped.push(format!("{} in {}", reader.index.entries[i].name, path.to_str().unwrap()));
+                continue;
+            }
         }
 
-        let mut file = File::create(&new_path).unwrap();
-        file.write_all(&data).unwrap();
+        match extract_entry(&mut reader, i, path, output_dir, arguments) {
+            Ok(()) => renamed.extend(renamed_entry(&reader, i, arguments)),
+            Err(error) => {
+                let failure = format!("{} in {}: {}", reader.index.entries[i].name, path.to_str().unwrap(), error);
+
+                println!("Failed to extract {failure}");
+                if !arguments.continue_on_error {
+                    std::process::exit(1);
+                }
+
+                failures.push(failure);
+            }
+        }
+    }
+
+    if resumed > 0 {
+        println!("Skipped {} entries of {} that were already extracted", resumed, path.to_str().unwrap());
+    }
+
+    if !basenames.skipped.is_empty() {
+        println!("Skipped {} entries of {} whose file name was already extracted:", basenames.skipped.len(), path.to_str().unwrap());
+        for name in &basenames.skipped {
+            println!("  {name}");
+        }
+    }
+
+    // Renamed entries are listed next to the extracted directory, like trailing data, so they don't end up packed
+    // into a rebuilt archive.
+    if !renamed.is_empty() {
+        let mapping_path = output_dir.with_file_name(format!("{}.name-mapping.txt", output_dir.file_name().unwrap().to_str().unwrap()));
+        println!("Renamed {} entries of {} to names safe on disk, writing the names they had to {}", renamed.len(), path.to_str().unwrap(), mapping_path.to_str().unwrap());
+
+        let mapping : String = renamed.iter().map(|(original, safe)| format!("{original}\t{safe}\n")).collect();
+        std::fs::write(&mapping_path, mapping).unwrap();
+    }
+
+    // Anything after the last entry gets written next to the extracted directory, so rnencode's
+    // --preserve-trailing can put it back for a byte-exact rebuild.
+    let trailing = reader.trailing_bytes();
+    if !trailing.is_empty() {
+        let trailing_path = output_dir.with_file_name(format!("{}.trailing", output_dir.file_name().unwrap().to_str().unwrap()));
+        println!("Archive {} has {} bytes of trailing data, writing them to {}", path.to_str().unwrap(), trailing.len(), trailing_path.to_str().unwrap());
+
+        let mut file = File::create(&trailing_path).unwrap();
+        file.write_all(&trailing).unwrap();
     }
 }
 
 
-fn detect_file_type(data: &Vec<u8>) -> String {
-    if data[0] == b'R' && data[1] == b'I' && data[2] == b'F' && data[3] == b'F' {
-        return ".wav".to_string()
-    } else if data[0] == b'B' && data[1] == b'M' {
-        return ".bmp".to_string()
+fn detect_file_type(data: &[u8]) -> String {
+    let extension = if data.starts_with(b"RIFF") && data.get(8..12) == Some(b"AVI ") {
+        ".avi"
+    } else if data.starts_with(b"RIFF") {
+        ".wav"
+    } else if data.starts_with(b"BM") {
+        ".bmp"
+    } else if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        ".png"
+    } else if data.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        ".jpg"
+    } else if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+        ".gif"
+    } else if data.starts_with(b"OggS") {
+        ".ogg"
+    } else if data.starts_with(b"ID3") || (data.len() >= 2 && data[0] == 0xFF && (data[1] & 0xE0) == 0xE0) {
+        ".mp3"
+    } else if data.starts_with(b"MThd") {
+        ".mid"
     } else {
-        return "".to_string()
+        ""
+    };
+
+    extension.to_string()
+}
+
+// Whether a loose file is something we recognize, either from its contents or as one of the script names. Only the
+// first 12 bytes are read, as that's as far as detect_file_type looks.
+fn is_known_file(path: &Path) -> std::io::Result<bool> {
+    let mut start = Vec::with_capacity(12);
+    File::open(path)?.take(12).read_to_end(&mut start)?;
+
+    Ok(!detect_file_type(&start).is_empty() || nscripter_formats::script::file_name_to_decode_info(path).is_ok())
+}
+
+fn process_embedded_archive(path: &Path, arguments : &Arguments, failures : &mut Vec<String>, budget : &mut SizeBudget) {
+    let file_name = path.file_name().unwrap().to_str().unwrap().to_lowercase();
+    let mut file = File::open(path).unwrap();
+
+    let Some((archive_type, offset)) = Archive::find_embedded_archive(&mut file, &key_table(arguments)) else {
+        println!("Couldn't find a SAR or NSA archive within {}", path.to_str().unwrap());
+        std::process::exit(1);
+    };
+
+    println!("Found {:?} archive at offset {} within {}", archive_type, offset, path.to_str().unwrap());
+
+    let output_dir = output_path(arguments).join(file_name);
+    extract_files(path, archive_type, offset, &output_dir, arguments, failures, budget);
+}
+
+// Loose files are read as the game would see them, so .nbz and .spb files come back decompressed.
+fn read_loose_file(path: &Path, arguments : &Arguments) -> Result<Vec<u8>, nscripter_formats::Error> {
+    let mut source = nscripter_formats::open_any(path, &key_table(arguments))?;
+    source.read(path.file_name().unwrap().to_str().unwrap())
+}
+
+fn decode_file(input : &Path, out : &Path, format : Option<ImageFormat>, key_file : Option<&str>) -> Result<(), Box<dyn std::error::Error>> {
+    let file_name = input.file_name().unwrap().to_str().unwrap();
+    if !file_name.to_lowercase().ends_with(".spb") && !file_name.to_lowercase().ends_with(".nbz") {
+        return Err(format!("{} isn't a .spb or .nbz file", input.to_str().unwrap()).into());
+    }
+
+    let mut source = nscripter_formats::open_any(input, &key_table_from(key_file))?;
+    let decoded_data = source.read(file_name)?;
+
+    let file_type = match detect_file_type(&decoded_data).as_str() {
+        "" => "unknown".to_string(),
+        extension => extension[1..].to_string()
+    };
+    if format.is_some() && file_type != "bmp" {
+        return Err(format!("it holds a {file_type} file rather than an image").into());
+    }
+
+    let output = match format {
+        None => {
+            println!("Decoded {} to a {} file", input.to_str().unwrap(), file_type);
+            decoded_data
+        }
+        Some(ImageFormat::Bmp) => {
+            Image::from_bmp_bytes(&decoded_data)?;
+            decoded_data
+        }
+        Some(ImageFormat::Png) => Image::from_bmp_bytes(&decoded_data)?.to_png()?,
+        Some(ImageFormat::Rgb) => Image::from_bmp_bytes(&decoded_data)?.to_rgb()
+    };
+
+    std::fs::write(out, output)?;
+    Ok(())
+}
+
+fn print_spb_stats(input : &Path, key_file : Option<&str>) -> Result<(), Box<dyn std::error::Error>> {
+    let key_table = key_table_from(key_file);
+    let data : Vec<u8> = std::fs::read(input)?.into_iter().map(|byte| key_table.apply(byte)).collect();
+    let stats = spb_channel_stats(&data)?;
+
+    for (name, channel) in ["Blue", "Green", "Red"].iter().zip(stats) {
+        let bit_compressed : Vec<String> = (1..8).map(|bits| format!("{bits}:{}", channel.bit_compressed[bits])).collect();
+        println!("{name}: {} bits, stamp4 {}, read4 {}, differences by bit width {}", channel.bits, channel.stamp4, channel.read4, bit_compressed.join(" "));
+    }
+
+    Ok(())
+}
+
+// Files that aren't named like archives are only looked at if they aren't something else we recognize.
+fn detect_archive_type(path: &Path, arguments : &Arguments) -> Result<Option<ArchiveType>, ArchiveError> {
+    if is_known_file(path)? {
+        return Ok(None);
     }
 
+    let file = File::open(path)?;
+    Archive::detect_archive_type(&file, &key_table(arguments))
 }
 
-fn process_file(path: &Path, arguments : &Arguments) {
+fn archive_type_from_name(file_name: &str) -> Option<ArchiveType> {
+    ARCHIVE_BASES.iter()
+        .find(|(archive_type, base)| archive_sequence_names(base, archive_type).iter().any(|name| name == file_name))
+        .map(|(archive_type, _)| *archive_type)
+}
+
+// Archives that are still extracted, but that the game won't read, as one numbered before them is missing.
+fn report_sequence_gaps(dir: &Path) {
+    let found : Vec<PathBuf> = ARCHIVE_BASES.iter()
+        .flat_map(|(archive_type, base)| find_archive_sequence(dir, base, archive_type).unwrap())
+        .collect();
+
+    for path in std::fs::read_dir(dir).unwrap() {
+        let path = path.unwrap().path();
+        let file_name = path.file_name().unwrap().to_str().unwrap().to_lowercase();
+
+        if archive_type_from_name(&file_name).is_some() && !found.contains(&path) {
+            println!("{} won't be read by ONScripter, as an archive numbered before it is missing", path.to_str().unwrap());
+        }
+    }
+}
+
+// A loose file that couldn't be read, which like an entry that failed to extract stops everything unless we're
+// continuing on errors.
+fn report_unreadable(path: &Path, error: &dyn std::fmt::Display, arguments : &Arguments, failures : &mut Vec<String>) {
+    let failure = format!("{}: {}", path.to_str().unwrap(), error);
+
+    println!("Couldn't read {failure}");
+    if !arguments.continue_on_error {
+        std::process::exit(1);
+    }
+
+    failures.push(failure);
+}
+
+fn process_file(path: &Path, arguments : &Arguments, failures : &mut Vec<String>, budget : &mut SizeBudget) {
     let file_name = path.file_name().unwrap().to_str().unwrap().to_lowercase();
-    let output_dir = Path::new(&arguments.output);
+    let output_dir = output_path(arguments);
     
-    // Technically some of these can spread between different archives, and if they're not named sequentially there could be issues,
-    // and if they're not just numbers between "arc" and the archive type that's also wrong, but for now lets just assume this is
-    // fine.
-    let archive_type : ArchiveType = if file_name.starts_with("arc") && file_name.ends_with(".ns2") {
-        ArchiveType::NS2
-    } else if file_name.starts_with("arc") && file_name.ends_with(".nsa") {
-        ArchiveType::NSA
-    } else if file_name.starts_with("arc") && file_name.ends_with(".sar") {
-        ArchiveType::SAR
+    let archive_type : ArchiveType = if let Some(archive_type) = archive_type_from_name(&file_name) {
+        archive_type
     } else if file_name.ends_with(".nbz") {
-        let file = File::open(&path).unwrap();
-        let decoded_data = extract_bz2(file, nscripter_formats::default_keytable());
+        let decoded_data = read_loose_file(path, arguments).unwrap();
         let file_ext = detect_file_type(&decoded_data);
         
         let new_path = output_dir.join(format!("{}{}", path.file_stem().to_owned().unwrap().to_str().unwrap(), file_ext));
@@ -98,17 +766,37 @@ fn process_file(path: &Path, arguments : &Arguments) {
         }
         return;
     } else if file_name.ends_with(".spb") {
-        let data = std::fs::read(&path).unwrap();
-        let decoded_data = decode_spb(data).unwrap();
+        let decoded_data = read_loose_file(path, arguments).unwrap();
         
-        let new_path = output_dir.join(path.file_stem().to_owned().unwrap().to_str().unwrap());
+        let new_path = output_dir.join(format!("{}.bmp", path.file_stem().to_owned().unwrap().to_str().unwrap()));
         let mut file = File::create(&new_path).unwrap();                
         file.write_all(&decoded_data).unwrap();
 
+        if let Some(sprite_alpha) = arguments.sprite_alpha {
+            write_sprite(&decoded_data, &new_path, sprite_alpha, arguments).unwrap();
+        }
+
         if arguments.verbose {
             println!("Decoding loose spb image {} to {}", path.to_str().unwrap(), new_path.to_str().unwrap());
         }
         return;
+    } else if let Some(archive_type) = match detect_archive_type(path, arguments) {
+        Ok(archive_type) => archive_type,
+        Err(error) => {
+            report_unreadable(path, &error, arguments, failures);
+            return;
+        }
+    } {
+        println!("{} isn't named like an archive, but reads as a {:?} archive", path.to_str().unwrap(), archive_type);
+        archive_type
+    }
+    else if arguments.report_unknown {
+        match is_known_file(path) {
+            Ok(true) => {}
+            Ok(false) => println!("Couldn't determine the type of {}", path.to_str().unwrap()),
+            Err(error) => report_unreadable(path, &error, arguments, failures)
+        }
+        return;
     }
     else {
         let new_path = output_dir.join(path.file_name().to_owned().unwrap().to_str().unwrap());
@@ -116,12 +804,12 @@ fn process_file(path: &Path, arguments : &Arguments) {
         if arguments.verbose {
             println!("Copying loose file {} to {}", path.to_str().unwrap(), new_path.to_str().unwrap());
         }
-        std::fs::copy(&path, new_path).unwrap();
+        std::fs::copy(path, new_path).unwrap();
         return;
     };
     
     let output_dir = output_dir.join(file_name);
-    extract_files(&path, archive_type, arguments.offset, &output_dir, arguments.verbose);
+    extract_files(path, archive_type, arguments.offset, &output_dir, arguments, failures, budget);
 }
 
 
@@ -174,30 +862,235 @@ fn spb_test() {
 }
  */
 
+// Logging goes to stderr, so that only our own progress ends up on stdout.
+fn init_logging(verbose : bool) {
+    let level = if verbose { "debug" } else { "info" };
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(level)).format_timestamp(None).init();
+}
+
 fn main() {
-    let arguments = Arguments::parse();
-    let output_dir = Path::new(&arguments.output);
-    let path = Path::new(&arguments.path);
+    let mut arguments = Arguments::parse();
+    init_logging(arguments.verbose);
+
+    if let Some(Command::DecodeFile { input, out, format, key_file }) = &arguments.command {
+        if let Err(error) = decode_file(Path::new(input), Path::new(out), *format, key_file.as_deref()) {
+            println!("Couldn't decode {input}: {error}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(Command::SpbStats { input, key_file }) = &arguments.command {
+        if let Err(error) = print_spb_stats(Path::new(input), key_file.as_deref()) {
+            println!("Couldn't read {input}: {error}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // Only the subcommands go without a path and output, which clap makes sure of.
+    let dir = Path::new(arguments.path.as_deref().unwrap());
+    if arguments.offset == 0 && !arguments.in_exe && dir.is_dir() {
+        if let Some(offset) = nscripter_formats::vfs::detect_offset_from_dir(dir, &key_table(&arguments)) {
+            println!("Archives in {} start {} bytes in, reading them at that offset", dir.to_str().unwrap(), offset);
+            arguments.offset = offset;
+        }
+    }
+
+    let output_dir = output_path(&arguments);
+    let path = Path::new(arguments.path.as_deref().unwrap());
 
-    if output_dir.exists() {
+    if output_dir.exists() && !arguments.resume {
         if !arguments.force {
-            println!("{} exists, if you wish to delete it's contents and write out the archive from scratch, pass --force or -f.", arguments.output);
+            println!("{} exists, if you wish to delete it's contents and write out the archive from scratch, pass --force or -f.", output_dir.to_str().unwrap());
             return;
         } else {
             std::fs::remove_dir_all(output_dir).unwrap();
         }
     }
     
-    std::fs::create_dir(output_dir).unwrap();
+    if !output_dir.exists() {
+        std::fs::create_dir(output_dir).unwrap();
+    }
+
+    let mut failures : Vec<String> = Vec::new();
+    let mut budget = SizeBudget::default();
 
-    if path.is_dir() {
+    if arguments.in_exe {
+        process_embedded_archive(path, &arguments, &mut failures, &mut budget);
+    } else if path.is_dir() {
+        report_sequence_gaps(path);
         let paths = std::fs::read_dir(path).unwrap();
 
         for path in paths {
             let path = path.unwrap().path();
-            process_file(&path, &arguments);
+            process_file(&path, &arguments, &mut failures, &mut budget);
         }
     } else {
-        process_file(&path, &arguments);
+        process_file(path, &arguments, &mut failures, &mut budget);
+    }
+
+    if !budget.skipped.is_empty() {
+        println!("Stopped after extracting {} bytes, {} entries were skipped to stay within --max-total-size:", budget.written, budget.skipped.len());
+        for skipped in &budget.skipped {
+            println!("  {skipped}");
+        }
+    }
+
+    if !failures.is_empty() {
+        println!("{} entries failed to extract:", failures.len());
+        for failure in &failures {
+            println!("  {failure}");
+        }
+
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An NSA archive of files, stored as they are, written to arc.nsa in dir.
+    fn nsa(dir : &Path, files : &[(&str, &[u8])]) -> PathBuf {
+        let source = dir.join("src");
+        for (name, data) in files {
+            let path = source.join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, data).unwrap();
+        }
+
+        let path = dir.join("arc.nsa");
+        let entries = files.iter().map(|(name, _)| PathBuf::from(name)).collect();
+        Archive::create_nsa_archive(File::create(&path).unwrap(), &source, entries, &KeyTable::identity(), &CreateOptions::default()).unwrap();
+        path
+    }
+
+    // Renames an entry by writing over its name in the archive's header, for names that can't be made from files.
+    fn rename_entry(path : &Path, from : &str, to : &str) {
+        assert_eq!(from.len(), to.len());
+        let mut bytes = std::fs::read(path).unwrap();
+        let start = bytes.windows(from.len()).position(|window| window == from.as_bytes()).unwrap();
+        bytes[start..start + to.len()].copy_from_slice(to.as_bytes());
+        std::fs::write(path, bytes).unwrap();
+    }
+
+    // Extracts the archive at path into dir/out as rndecode would with the extra arguments, giving the failures
+    // --continue-on-error collected and what --max-total-size left out.
+    fn extract(path : &Path, dir : &Path, extra : &[&str]) -> (PathBuf, Vec<String>, SizeBudget) {
+        let output_dir = dir.join("out");
+        let arguments = Arguments::parse_from(["rndecode", "-p", path.to_str().unwrap(), "-o", output_dir.to_str().unwrap()].iter().chain(extra));
+        let mut failures = Vec::new();
+        let mut budget = SizeBudget::default();
+
+        extract_files(path, ArchiveType::NSA, 0, &output_dir, &arguments, &mut failures, &mut budget);
+        (output_dir, failures, budget)
+    }
+
+    #[test]
+    fn size_budgets_extract_the_entries_that_fit_before_it_runs_out() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = nsa(dir.path(), &[("a.txt", &[1; 4]), ("b.txt", &[2; 4]), ("c.txt", &[3; 4]), ("d.txt", &[4; 1])]);
+
+        let (output_dir, _, budget) = extract(&path, dir.path(), &["--max-total-size", "10"]);
+        assert_eq!(budget.written, 8);
+        assert_eq!(budget.skipped, [format!("c.txt in {}", path.to_str().unwrap()), format!("d.txt in {}", path.to_str().unwrap())]);
+
+        // d.txt would still fit, but what's extracted is kept to the start of the archive.
+        assert_eq!(std::fs::read(output_dir.join("a.txt")).unwrap(), [1; 4]);
+        assert_eq!(std::fs::read(output_dir.join("b.txt")).unwrap(), [2; 4]);
+        assert!(!output_dir.join("c.txt").exists());
+        assert!(!output_dir.join("d.txt").exists());
+    }
+
+    #[test]
+    fn backslashes_in_entry_names_make_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = nsa(dir.path(), &[("bg/title.bmp", b"title"), ("bg/sub/a.txt", b"a")]);
+        rename_entry(&path, "bg/title.bmp", "bg\\title.bmp");
+        rename_entry(&path, "bg/sub/a.txt", "bg\\sub\\a.txt");
+
+        let (output_dir, _, _) = extract(&path, dir.path(), &[]);
+        assert_eq!(std::fs::read(output_dir.join("bg").join("title.bmp")).unwrap(), b"title");
+        assert_eq!(std::fs::read(output_dir.join("bg").join("sub").join("a.txt")).unwrap(), b"a");
+        assert!(!output_dir.join("bg\\title.bmp").exists());
+    }
+
+    #[test]
+    fn entries_that_climb_out_of_the_output_directory_are_not_written() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = nsa(dir.path(), &[("ab/evil.txt", b"evil"), ("xy\\evil.txt", b"evil"), ("fine.txt", b"fine")]);
+        rename_entry(&path, "ab/evil.txt", "../evil.txt");
+        rename_entry(&path, "xy\\evil.txt", "..\\evil.txt");
+
+        let (output_dir, failures, _) = extract(&path, dir.path(), &["--continue-on-error"]);
+        assert_eq!(failures.len(), 2);
+        assert!(failures.iter().all(|failure| failure.contains("outside of the output directory")));
+        assert!(!dir.path().join("evil.txt").exists());
+        assert_eq!(std::fs::read(output_dir.join("fine.txt")).unwrap(), b"fine");
+    }
+
+    #[test]
+    fn name_templates_name_files_by_index_and_name() {
+        assert_eq!(format_entry_name("{index:04}_{name}", 7, "title.bmp"), "0007_title.bmp");
+        assert_eq!(format_entry_name("{stem}-{index}.{ext}", 12, "voice.01.wav"), "voice.01-12.wav");
+        assert_eq!(format_entry_name("{index:03}{stem}[{ext}]", 3, ".hidden"), "003.hidden[]");
+
+        assert!(parse_name_template("{index:02}_{name}").is_ok());
+        assert!(parse_name_template("all the same").is_err());
+        assert!(parse_name_template("{size}").is_err());
+        assert!(parse_name_template("{name").is_err());
+        assert!(parse_name_template("name}").is_err());
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = nsa(dir.path(), &[("bg/title.bmp", b"title"), ("a.txt", b"a")]);
+        let (output_dir, _, _) = extract(&path, dir.path(), &["--name-template", "{index:03}_{name}"]);
+        assert_eq!(std::fs::read(output_dir.join("bg").join("000_title.bmp")).unwrap(), b"title");
+        assert_eq!(std::fs::read(output_dir.join("001_a.txt")).unwrap(), b"a");
+    }
+
+    #[test]
+    fn unique_basenames_keep_the_first_entry_with_each_file_name() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = nsa(dir.path(), &[("bg/a.bmp", b"first"), ("cg/A.BMP", b"second"), ("cg/b.bmp", b"b"), ("a.bmp", b"third")]);
+
+        let (output_dir, _, _) = extract(&path, dir.path(), &["--unique-basename"]);
+        let mut written : Vec<_> = std::fs::read_dir(&output_dir).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+        written.sort();
+        assert_eq!(written, ["a.bmp", "b.bmp"]);
+        assert_eq!(std::fs::read(output_dir.join("a.bmp")).unwrap(), b"first");
+
+        let mut basenames = SeenBasenames::default();
+        let firsts : Vec<bool> = ["bg/a.bmp", "cg\\A.BMP", "cg/b.bmp", "a.bmp"].iter().map(|name| basenames.first(name)).collect();
+        assert_eq!(firsts, [true, false, true, false]);
+        assert_eq!(basenames.skipped, ["cg\\A.BMP", "a.bmp"]);
+    }
+
+    #[test]
+    fn safe_names_rename_entries_windows_cant_write_and_map_them_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = nsa(dir.path(), &[("what?.txt", b"what"), ("bg/a:b.txt", b"ab"), ("con.txt", b"con"), ("fine.txt", b"fine")]);
+
+        let (output_dir, _, _) = extract(&path, dir.path(), &["--safe-names"]);
+        assert_eq!(std::fs::read(output_dir.join("what_.txt")).unwrap(), b"what");
+        assert_eq!(std::fs::read(output_dir.join("bg").join("a_b.txt")).unwrap(), b"ab");
+        assert_eq!(std::fs::read(output_dir.join("_con.txt")).unwrap(), b"con");
+        assert_eq!(std::fs::read(output_dir.join("fine.txt")).unwrap(), b"fine");
+
+        let mapping = std::fs::read_to_string(dir.path().join("out.name-mapping.txt")).unwrap();
+        assert_eq!(mapping, "what?.txt\twhat_.txt\nbg/a:b.txt\tbg/a_b.txt\ncon.txt\t_con.txt\n");
+    }
+
+    #[test]
+    fn known_files_are_recognized_by_their_start() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut wav = b"RIFF\0\0\0\0WAVE".to_vec();
+        wav.resize(1 << 20, 0);
+        std::fs::write(dir.path().join("voice.dat"), wav).unwrap();
+        std::fs::write(dir.path().join("other.dat"), b"nothing in particular").unwrap();
+
+        assert!(is_known_file(&dir.path().join("voice.dat")).unwrap());
+        assert!(!is_known_file(&dir.path().join("other.dat")).unwrap());
+        assert!(is_known_file(&dir.path().join("missing.dat")).is_err());
     }
 }
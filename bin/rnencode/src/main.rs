@@ -1,9 +1,13 @@
+use std::collections::HashMap;
 use std::path::PathBuf;
-use std::{fs::File, io::Write, path::Path};
+use std::io::Write;
+use std::{fs::{File, OpenOptions}, path::Path};
 
 use clap::Parser;
 use nscripter_formats::archive::*;
-use nscripter_formats::image::decode_spb;
+use nscripter_formats::KeyTable;
+use nscripter_formats::cache::CompressionCache;
+use nscripter_formats::image::{encode_spb, verify_spb, Image};
 use walkdir::WalkDir;
 
 #[derive(Parser, Debug)]
@@ -17,73 +21,513 @@ struct Arguments {
     #[arg(short, long)]
     output: String,
 
-    /// Offset of data within archive
-    #[arg(long, default_value_t = 0)]
-    offset: u32,
-    
     /// This will determine if we should list out File by File what we're extracting.
+    ///
+    /// Also turns on debug logging from reading and writing archives, which otherwise only logs what it works around.
+    /// RUST_LOG, if set, takes precedence.
     #[arg(short, long, default_value_t = false)]
     verbose: bool,
-    
+
     /// This will ensure we entirely overwrite the output directory. All existing files within will be deleted, not just ones that might be overwritten.
-    /// 
+    ///
     /// Otherwise, we'll fail out when trying to overwrite a file.
     #[arg(short, long, default_value_t = false)]
     force: bool,
+
+    /// Compress WAV files with Bzip2 (NSA only). WAVs whose audio is already compressed, such as ADPCM, are stored as-is.
+    #[arg(long, default_value_t = false)]
+    bzip2: bool,
+
+    /// Bzip2 block size in units of 100k, from 1 to 9. Bzip2 output only depends on this and the bzip2 version
+    /// Cargo.lock pins, so archives built with the same block size come out byte for byte the same.
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..=9), default_value_t = 9)]
+    bzip2_block: u32,
+
+    /// How many bytes at a time to copy files into SAR archives with. Larger buffers can be faster for archives of
+    /// large files, such as voice or BGM.
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..), default_value_t = 65536)]
+    copy_buffer_size: u64,
+
+    /// Compress every file nothing else compresses with zstd (NSA only). Stock ONScripter can't read zstd, this is for
+    /// engines that have added it. Needs rnencode built with the zstd feature.
+    #[arg(long, default_value_t = false)]
+    zstd: bool,
+
+    /// Compress BMP files with SPB (NSA only).
+    #[arg(long, default_value_t = false)]
+    spb: bool,
+
+    /// Compress BMP files with LZSS when they're not being SPB compressed (NSA only).
+    #[arg(long, default_value_t = false)]
+    lzss: bool,
+
+    /// List the compression each file would be given, without writing an archive.
+    #[arg(long, default_value_t = false)]
+    dry_run: bool,
+
+    /// File whose bytes are appended after the last entry, such as the .trailing file rndecode writes for archives with trailing data.
+    #[arg(long)]
+    preserve_trailing: Option<String>,
+
+    /// Rather than building an archive, write each file out on its own into the output directory: WAVs as .nbz with --bzip2,
+    /// BMPs as .spb with --spb, and anything else copied as-is. These are the loose files rndecode knows how to decode.
+    #[arg(long, default_value_t = false)]
+    loose: bool,
+
+    /// Print the layout and size the archive would have, without writing it.
+    #[arg(long, default_value_t = false)]
+    plan: bool,
+
+    /// File to keep compressed entries in between builds, so rebuilding an archive only recompresses the files that
+    /// have changed since the last one. It's created if it doesn't exist yet (NSA only).
+    #[arg(long)]
+    cache: Option<String>,
+
+    /// Compress entries on this many threads, 0 uses one per core, printing progress as entries are written. Without
+    /// it entries are compressed one at a time. Not used alongside --cache (NSA only).
+    #[arg(long)]
+    threads: Option<usize>,
+
+    /// What to write in each NSA entry's decompressed size field. ONScripter needs the actual size, zero is for
+    /// readers that expect it the way some other packers write it.
+    #[arg(long, value_enum, default_value_t = DecompressedSizeField::Actual)]
+    decompressed_size: DecompressedSizeField,
+
+    /// File listing compression for particular entries, one `<path> <none|spb|lzss|bzip2|zstd>` per line with paths
+    /// relative to the input directory, overriding what would otherwise be picked for them (NSA only).
+    #[arg(long)]
+    compression_list: Option<String>,
+
+    /// File of entries to store under names other than their files', one `<name in archive>\t<path>` per line with
+    /// paths relative to the input directory, such as the .name-mapping.txt rndecode's --safe-names writes, so the
+    /// entries it renamed get their original names back (SAR and NSA only).
+    #[arg(long)]
+    name_mapping: Option<String>,
+
+    /// Start each entry's data on a multiple of this many bytes, padding with zeroes in between, to match archives laid
+    /// out by CD-ROM sector. rndecode reports the alignment of archives that have one (NSA only).
+    #[arg(long, default_value_t = 0)]
+    align: usize,
+
+    /// Write the archive to standard output rather than to the output path, whose extension still picks the archive
+    /// type. Entries are compressed twice, once to lay out the header, as the output can't be gone back over (NSA only).
+    #[arg(long, default_value_t = false)]
+    stdout: bool,
+
+    /// File to write each entry's original size, stored size, ratio and compression to once the archive is built, as
+    /// JSON if it ends in .json and CSV otherwise. A ratio above 1 means compression made the entry bigger.
+    #[arg(long)]
+    report: Option<String>,
+
+    /// File to take the key table to obfuscate the archive with from, usually the game's executable. The archive then
+    /// reads back with the same file passed to rndecode's --key-file, as ONScripter reads it with the same key.
+    #[arg(long)]
+    key_file: Option<String>,
+
+    /// Decode each image again after SPB encoding it and fail if it doesn't give back the original pixels, rather than
+    /// writing an image that displays wrong. Slows down --spb by a decode per image (also applies to --loose).
+    #[arg(long, default_value_t = false)]
+    verify_spb: bool,
 }
 
-fn detect_file_type(data: &Vec<u8>) -> String {
-    if data[0] == b'R' && data[1] == b'I' && data[2] == b'F' && data[3] == b'F' {
-        return ".wav".to_string()
-    } else if data[0] == b'B' && data[1] == b'M' {
-        return ".bmp".to_string()
-    } else {
-        return "".to_string()
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum DecompressedSizeField {
+    Actual,
+    Zero,
+}
+
+impl From<DecompressedSizeField> for DecompressedSize {
+    fn from(value: DecompressedSizeField) -> Self {
+        match value {
+            DecompressedSizeField::Actual => DecompressedSize::Actual,
+            DecompressedSizeField::Zero => DecompressedSize::Zero,
+        }
     }
+}
+
+fn key_table(arguments : &Arguments) -> KeyTable {
+    let Some(key_file) = &arguments.key_file else {
+        return nscripter_formats::default_keytable();
+    };
 
+    match KeyTable::from_file(Path::new(key_file)) {
+        Some(key_table) => key_table,
+        None => {
+            eprintln!("Couldn't find a key table in {key_file}");
+            std::process::exit(1);
+        }
+    }
 }
 
-fn archive_directory(archive_dir: &Path, output_file: &Path)
-{
+fn read_compression_list(list_path: &Path, entries: &[PathBuf]) -> Result<HashMap<PathBuf, Compression>, String> {
+    let list = std::fs::read_to_string(list_path).map_err(|error| format!("Couldn't read {}: {}", list_path.display(), error))?;
+    let mut overrides = HashMap::new();
+
+    for (line_number, line) in list.lines().enumerate().map(|(i, line)| (i + 1, line.trim())) {
+        if line.is_empty() {
+            continue;
+        }
+
+        // Split on the last space, so paths can have spaces in them.
+        let Some((entry, scheme)) = line.rsplit_once(char::is_whitespace) else {
+            return Err(format!("Line {line_number} of {} isn't <path> <compression>: {line}", list_path.display()));
+        };
+
+        let compression = match scheme.to_lowercase().as_str() {
+            "none" => Compression::None,
+            "spb" => Compression::Spb,
+            "lzss" => Compression::Lzss,
+            "bzip2" => Compression::Bzip2,
+            "zstd" => Compression::Zstd,
+            _ => return Err(format!("Line {line_number} of {} has unknown compression {scheme}, expected none, spb, lzss, bzip2 or zstd", list_path.display()))
+        };
+
+        let entry = PathBuf::from(entry.trim_end());
+        if !entries.contains(&entry) {
+            return Err(format!("Line {line_number} of {} lists {}, which isn't in the input directory", list_path.display(), entry.display()));
+        }
+
+        overrides.insert(entry, compression);
+    }
+
+    Ok(overrides)
+}
+
+fn read_name_mapping(mapping_path: &Path, entries: &[PathBuf]) -> Result<HashMap<PathBuf, String>, String> {
+    let mapping = std::fs::read_to_string(mapping_path).map_err(|error| format!("Couldn't read {}: {}", mapping_path.display(), error))?;
+    let mut names = HashMap::new();
+
+    for (line_number, line) in mapping.lines().enumerate().map(|(i, line)| (i + 1, line)) {
+        if line.is_empty() {
+            continue;
+        }
+
+        // Split on the tab rather than any whitespace, as the names are exactly as they were in the archive.
+        let Some((name, entry)) = line.split_once('\t') else {
+            return Err(format!("Line {line_number} of {} isn't <name>\t<path>: {line}", mapping_path.display()));
+        };
+
+        let entry = PathBuf::from(entry);
+        if !entries.contains(&entry) {
+            return Err(format!("Line {line_number} of {} lists {}, which isn't in the input directory", mapping_path.display(), entry.display()));
+        }
+
+        names.insert(entry, name.to_string());
+    }
+
+    Ok(names)
+}
+
+fn create_options(arguments : &Arguments, entries : &[PathBuf]) -> CreateOptions {
+    let overrides = match &arguments.compression_list {
+        Some(list_path) => read_compression_list(Path::new(list_path), entries).unwrap_or_else(|error| {
+            println!("{error}");
+            std::process::exit(1);
+        }),
+        None => HashMap::new()
+    };
+
+    let names = match &arguments.name_mapping {
+        Some(mapping_path) => read_name_mapping(Path::new(mapping_path), entries).unwrap_or_else(|error| {
+            println!("{error}");
+            std::process::exit(1);
+        }),
+        None => HashMap::new()
+    };
+
+    CreateOptions {
+        bzip2: arguments.bzip2,
+        spb: arguments.spb,
+        lzss: arguments.lzss,
+        threads: arguments.threads.unwrap_or(0),
+        decompressed_size: arguments.decompressed_size.into(),
+        overrides,
+        names,
+        alignment: arguments.align,
+        verify_spb: arguments.verify_spb,
+        bzip2_block_size: arguments.bzip2_block,
+        zstd: arguments.zstd,
+        copy_buffer_size: arguments.copy_buffer_size as usize
+    }
+}
+
+// Prints how many entries have been written and roughly how long the rest will take, assuming the entries left take
+// as long on average as the ones so far.
+fn print_progress(done: usize, total: usize, started: std::time::Instant) {
+    if done == 0 {
+        return;
+    }
+
+    let elapsed = started.elapsed().as_secs_f64();
+    let remaining = elapsed / done as f64 * (total - done) as f64;
+    println!("Written {done}/{total} entries, {elapsed:.1}s elapsed, about {remaining:.1}s left");
+}
+
+fn collect_entries(archive_dir: &Path) -> Vec<PathBuf> {
     let mut entries_to_archive : Vec<PathBuf> = Vec::new();
-    for entry in WalkDir::new(&archive_dir) {
+    for entry in WalkDir::new(archive_dir) {
         let entry = entry.unwrap();
         let entry_fullpath = entry.path();
 
-        if std::fs::metadata(&entry_fullpath).unwrap().is_dir() {
+        if std::fs::metadata(entry_fullpath).unwrap().is_dir() {
             continue;
         }
 
-        let entry = entry_fullpath.strip_prefix(&archive_dir).unwrap();
+        let entry = entry_fullpath.strip_prefix(archive_dir).unwrap();
 
         entries_to_archive.push(entry.to_owned());
         //println!("{}", entry.display());
     }
 
-    let file = File::create(&output_file).unwrap();
-    Archive::create_sar_archive(file, archive_dir, entries_to_archive, 0, nscripter_formats::default_keytable());
+    entries_to_archive
+}
+
+fn output_archive_type(output_file: &Path) -> ArchiveType {
+    let file_name = output_file.file_name().unwrap().to_str().unwrap().to_lowercase();
+
+    if file_name.ends_with(".nsa") {
+        ArchiveType::NSA
+    } else if file_name.ends_with(".ns2") {
+        ArchiveType::NS2
+    } else {
+        ArchiveType::SAR
+    }
+}
+
+fn print_compression_plan(archive_dir: &Path, arguments : &Arguments) {
+    let entries = collect_entries(archive_dir);
+    let options = create_options(arguments, &entries);
+
+    for entry in entries {
+        let plan = Archive::plan_entry_compression(archive_dir, &entry, &options);
+        println!("{}: {:?} ({:?})", entry.display(), plan.compression, plan.reason);
+    }
+}
+
+fn print_archive_plan(archive_dir: &Path, output_file: &Path, arguments : &Arguments) {
+    let entries = collect_entries(archive_dir);
+    let options = create_options(arguments, &entries);
+
+    match plan_archive(archive_dir, &entries, &output_archive_type(output_file), &options) {
+        Ok(plan) => {
+            for entry in &plan.entries {
+                println!("{}: offset {}, {} -> {} bytes ({:?})", entry.name, entry.offset, entry.original_size, entry.size, entry.compression);
+            }
+            println!("{} entries, header {} bytes, archive {} bytes", plan.entries.len(), plan.header_size, plan.total_size);
+        }
+        Err(error) => {
+            println!("Can't create {}: {}", output_file.to_str().unwrap(), error);
+        }
+    }
+}
+
+fn archive_directory(archive_dir: &Path, output_file: &Path, arguments : &Arguments)
+{
+    let entries_to_archive = collect_entries(archive_dir);
+    let options = create_options(arguments, &entries_to_archive);
+
+    // NS2 has no entry count in its header, so unlike SAR and NSA it isn't limited to u16::MAX entries.
+    let result = match output_archive_type(output_file) {
+        ArchiveType::SAR => {
+            let file = File::create(output_file).unwrap();
+            Archive::create_sar_archive(file, archive_dir, entries_to_archive, &key_table(arguments), &options)
+        }
+        ArchiveType::NSA => {
+            let file = File::create(output_file).unwrap();
+
+            match &arguments.cache {
+                Some(cache_path) => {
+                    let cache_path = Path::new(cache_path);
+                    let mut cache = CompressionCache::load(cache_path).unwrap();
+                    let result = Archive::create_nsa_archive_cached(file, archive_dir, entries_to_archive, &key_table(arguments), &options, &mut cache);
+
+                    if result.is_ok() {
+                        println!("Reused {} cached entries, compressed {}", cache.hits, cache.misses);
+                        cache.save(cache_path).unwrap();
+                    }
+                    result
+                }
+                None if arguments.threads.is_some() => {
+                    let started = std::time::Instant::now();
+                    Archive::create_nsa_archive_parallel(file, archive_dir, entries_to_archive, &key_table(arguments), &options, |done, total| {
+                        print_progress(done, total, started);
+                    })
+                }
+                None => Archive::create_nsa_archive(file, archive_dir, entries_to_archive, &key_table(arguments), &options)
+            }
+        }
+        ArchiveType::NS2 => {
+            let file = File::create(output_file).unwrap();
+            Archive::create_ns2_archive(file, archive_dir, entries_to_archive, &key_table(arguments))
+        }
+    };
+
+    let reports = match result {
+        Ok(reports) => reports,
+        Err(error) => {
+            println!("Failed to create {}: {}", output_file.to_str().unwrap(), error);
+            let _ = std::fs::remove_file(output_file);
+            std::process::exit(1);
+        }
+    };
+    write_report(&reports, arguments);
+
+    if let Some(trailing_path) = &arguments.preserve_trailing {
+        let trailing = std::fs::read(trailing_path).unwrap();
+        let mut file = OpenOptions::new().append(true).open(output_file).unwrap();
+        file.write_all(&trailing).unwrap();
+    }
+}
+
+fn json_string(value: &str) -> String {
+    let mut escaped = String::from("\"");
+    for character in value.chars() {
+        match character {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            character if character.is_control() => escaped.push_str(&format!("\\u{:04x}", character as u32)),
+            character => escaped.push(character)
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn write_report(reports: &[EntryReport], arguments : &Arguments) {
+    let Some(report_path) = &arguments.report else {
+        return;
+    };
+
+    let text = if report_path.to_lowercase().ends_with(".json") {
+        let entries: Vec<String> = reports.iter().map(|report| format!(
+            "  {{\"name\": {}, \"original_size\": {}, \"stored_size\": {}, \"ratio\": {:.4}, \"compression\": \"{:?}\"}}",
+            json_string(&report.name), report.original_size, report.stored_size, report.ratio(), report.compression
+        )).collect();
+        format!("[\n{}\n]\n", entries.join(",\n"))
+    } else {
+        let mut text = String::from("name,original_size,stored_size,ratio,compression\n");
+        for report in reports {
+            text += &format!("{},{},{},{:.4},{:?}\n", csv_field(&report.name), report.original_size, report.stored_size, report.ratio(), report.compression);
+        }
+        text
+    };
+
+    if let Err(error) = std::fs::write(report_path, text) {
+        eprintln!("Couldn't write the report to {report_path}: {error}");
+        std::process::exit(1);
+    }
+}
+
+// Nothing else can go to stdout here, so anything to report goes to stderr.
+fn write_archive_to_stdout(archive_dir: &Path, output_file: &Path, arguments : &Arguments) {
+    if !matches!(output_archive_type(output_file), ArchiveType::NSA) {
+        eprintln!("Only NSA archives can be written to stdout.");
+        std::process::exit(1);
+    }
+
+    let entries_to_archive = collect_entries(archive_dir);
+    let options = create_options(arguments, &entries_to_archive);
+    let mut stdout = std::io::stdout().lock();
+
+    let reports = match Archive::create_nsa_archive_streaming(&mut stdout, archive_dir, entries_to_archive, &key_table(arguments), &options) {
+        Ok(reports) => reports,
+        Err(error) => {
+            eprintln!("Failed to write the archive: {error}");
+            std::process::exit(1);
+        }
+    };
+    write_report(&reports, arguments);
+
+    if let Some(trailing_path) = &arguments.preserve_trailing {
+        let trailing = std::fs::read(trailing_path).unwrap();
+        stdout.write_all(&trailing).unwrap();
+    }
+}
+
+fn encode_loose_files(input_dir: &Path, output_dir: &Path, arguments : &Arguments) {
+    for entry in collect_entries(input_dir) {
+        let fullpath = input_dir.join(&entry);
+        let plan = Archive::plan_compression(&fullpath, arguments.bzip2, arguments.spb, false);
+        let data = std::fs::read(&fullpath).unwrap();
+
+        let (new_path, encoded_data) = match plan.compression {
+            Compression::Bzip2 => (output_dir.join(&entry).with_extension("nbz"), compress_bz2_with_block_size(&data, arguments.bzip2_block)),
+            Compression::Spb => match Image::from_bmp_bytes(&data).and_then(encode_spb) {
+                Ok(encoded) => {
+                    if arguments.verify_spb {
+                        if let Err(error) = Image::from_bmp_bytes(&data).and_then(|image| verify_spb(&image, &encoded)) {
+                            println!("Couldn't encode {}: {}", fullpath.to_str().unwrap(), error);
+                            std::process::exit(1);
+                        }
+                    }
+                    (output_dir.join(&entry).with_extension("spb"), encoded)
+                },
+                Err(_) => (output_dir.join(&entry), data)
+            },
+            _ => (output_dir.join(&entry), data)
+        };
+
+        if arguments.verbose {
+            println!("Encoding loose file {} to {}", fullpath.to_str().unwrap(), new_path.to_str().unwrap());
+        }
+
+        std::fs::create_dir_all(new_path.parent().unwrap()).unwrap();
+        let mut file = File::create(&new_path).unwrap();
+        file.write_all(&encoded_data).unwrap();
+    }
+}
+
+// Logging goes to stderr, so that only our own progress ends up on stdout.
+fn init_logging(verbose : bool) {
+    let level = if verbose { "debug" } else { "info" };
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(level)).format_timestamp(None).init();
 }
 
 fn main() {
     let arguments = Arguments::parse();
+    init_logging(arguments.verbose);
     let output = Path::new(&arguments.output);
     let path = Path::new(&arguments.path);
 
+    if arguments.dry_run {
+        print_compression_plan(path, &arguments);
+        return;
+    }
+
+    if arguments.plan {
+        print_archive_plan(path, output, &arguments);
+        return;
+    }
+
+    if arguments.stdout {
+        write_archive_to_stdout(path, output, &arguments);
+        return;
+    }
+
     if output.exists() {
         if !arguments.force {
             println!("{} exists, if you wish to delete it's contents and write out the archive from scratch, pass --force or -f.", arguments.output);
             return;
         } else if output.is_dir() {
-            std::fs::remove_dir_all(&output).unwrap();
+            std::fs::remove_dir_all(output).unwrap();
         } else {
-            std::fs::remove_file(&output).unwrap();
+            let _ = std::fs::remove_file(output);
         }
     }
-    
+
     //std::fs::create_dir(&output).unwrap();
 
-    if path.is_dir() {
-        archive_directory(&path, &output);
-    } else {
+    if path.is_dir() && arguments.loose {
+        encode_loose_files(path, output, &arguments);
+    } else if path.is_dir() {
+        archive_directory(path, output, &arguments);
     }
 }
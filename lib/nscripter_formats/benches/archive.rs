@@ -0,0 +1,94 @@
+use std::path::{Path, PathBuf};
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use nscripter_formats::archive::*;
+use nscripter_formats::KeyTable;
+
+// Data that compresses about as well as game assets do, repetitive enough for Bzip2 to be worth it but not so much
+// that it's trivial.
+fn asset(size : usize, seed : u32) -> Vec<u8> {
+    let mut state = seed;
+    (0..size).map(|i| {
+        state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+        if i % 4 == 0 { (state >> 16) as u8 } else { (i / 64) as u8 }
+    }).collect()
+}
+
+// Writes files to dir, returning their paths within it for the create functions.
+fn write_files(dir : &Path, files : impl Iterator<Item = (String, Vec<u8>)>) -> Vec<PathBuf> {
+    files.map(|(name, data)| {
+        std::fs::write(dir.join(&name), data).unwrap();
+        PathBuf::from(name)
+    }).collect()
+}
+
+fn pack_bzip2(c : &mut Criterion) {
+    let source = tempfile::tempdir().unwrap();
+    let entries = write_files(source.path(), (0..32).map(|i| (format!("voice{i}.wav"), asset(256 * 1024, i))));
+    let serial = CreateOptions { bzip2 : true, ..Default::default() };
+    let parallel = CreateOptions { threads : 0, ..serial.clone() };
+
+    let mut group = c.benchmark_group("pack 32 Bzip2 entries of 256 KiB");
+    group.sample_size(10);
+    group.bench_function("create_nsa_archive", |b| b.iter(|| {
+        Archive::create_nsa_archive(tempfile::tempfile().unwrap(), source.path(), entries.clone(), &KeyTable::identity(), &serial).unwrap()
+    }));
+    group.bench_function("create_nsa_archive_parallel", |b| b.iter(|| {
+        Archive::create_nsa_archive_parallel(tempfile::tempfile().unwrap(), source.path(), entries.clone(), &KeyTable::identity(), &parallel, |_, _| {}).unwrap()
+    }));
+    group.finish();
+}
+
+fn open_many_entries(c : &mut Criterion) {
+    let source = tempfile::tempdir().unwrap();
+    let entries = write_files(source.path(), (0..20_000).map(|i| (format!("voice_ch{:02}_{i:05}.ogg", i / 1000), Vec::new())));
+    let path = source.path().join("arc.nsa");
+    Archive::create_nsa_archive(std::fs::File::create(&path).unwrap(), source.path(), entries, &KeyTable::identity(), &CreateOptions::default()).unwrap();
+
+    // Most of the time goes to reading the entries' names out of the header.
+    c.bench_function("open an NSA of 20000 entries", |b| b.iter(|| {
+        let file = std::fs::File::open(&path).unwrap();
+        Archive::try_open_file_with_options(file, ArchiveType::NSA, 0, &KeyTable::identity(), &ReadOptions::default()).unwrap()
+    }));
+}
+
+fn extract_every_entry(c : &mut Criterion) {
+    let source = tempfile::tempdir().unwrap();
+    let entries = write_files(source.path(), (0..5000).map(|i| (format!("se{i:04}.dat"), asset(1024, i))));
+    let path = source.path().join("arc.nsa");
+    Archive::create_nsa_archive(std::fs::File::create(&path).unwrap(), source.path(), entries, &KeyTable::identity(), &CreateOptions::default()).unwrap();
+    let mut archive = Archive::open_file(std::fs::File::open(&path).unwrap(), ArchiveType::NSA, 0, &KeyTable::identity());
+
+    let mut group = c.benchmark_group("extract 5000 entries of 1 KiB");
+    group.bench_function("extract", |b| b.iter(|| {
+        for i in 0..archive.index.entries.len() {
+            criterion::black_box(archive.extract(archive.index.entries[i].info()));
+        }
+    }));
+    group.bench_function("extract_into", |b| b.iter(|| {
+        let mut buffer = Vec::new();
+        for i in 0..archive.index.entries.len() {
+            archive.extract_into(archive.index.entries[i].info(), &mut buffer);
+            criterion::black_box(&buffer);
+        }
+    }));
+    group.finish();
+}
+
+fn pack_large_wav(c : &mut Criterion) {
+    let source = tempfile::tempdir().unwrap();
+    let entries = write_files(source.path(), std::iter::once(("bgm.wav".to_string(), asset(32 * 1024 * 1024, 1))));
+
+    let mut group = c.benchmark_group("pack a 32 MiB WAV into a SAR");
+    group.sample_size(10);
+    for copy_buffer_size in [4 * 1024, 64 * 1024, 1024 * 1024] {
+        let options = CreateOptions { copy_buffer_size, ..Default::default() };
+        group.bench_function(format!("{} KiB copy buffer", copy_buffer_size / 1024), |b| b.iter(|| {
+            Archive::create_sar_archive(tempfile::tempfile().unwrap(), source.path(), entries.clone(), &KeyTable::identity(), &options).unwrap()
+        }));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, pack_bzip2, open_many_entries, extract_every_entry, pack_large_wav);
+criterion_main!(benches);
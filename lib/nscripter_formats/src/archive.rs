@@ -1,25 +1,44 @@
-use core::panic;
-use std::{collections::HashMap, fs::File, io::{ErrorKind, Read, Seek, SeekFrom, Write}, path::{Path, PathBuf}};
+use std::{collections::{BTreeMap, HashMap}, fs::File, io::{ErrorKind, Read, Seek, SeekFrom, Write}, path::{Path, PathBuf}};
 
-use crate::image::decode_spb;
+use crate::image::{decode_spb, decoded_spb_size, encode_spb, verify_spb, Image};
+use crate::cache::CompressionCache;
+use crate::shiftjis::ShiftJisTable;
+use crate::KeyTable;
+
+fn report_malformed_name(index : usize, name : &str) {
+    log::warn!("Entry {index} has a name that isn't valid Shift-JIS, reading it as {name}");
+}
+
+// An entry's name as it's stored in a header, an error rather than a lossy encoding if Shift-JIS can't hold it.
+fn encode_shiftjis(name : &str) -> Result<Vec<u8>, ArchiveError> {
+    let (encoded, _, errors) = encoding_rs::SHIFT_JIS.encode(name);
+    if errors {
+        return Err(ArchiveError::UnencodableName { name : name.to_string() });
+    }
+
+    Ok(encoded.into_owned())
+}
+
+fn decode_shiftjis_name(buffer : &[u8], options : &ReadOptions) -> Result<(String, bool), ArchiveError> {
+    let (res, errors) = crate::shiftjis::decode(buffer, options.shift_jis);
+    if errors && !options.lossy_names {
+        return Err(ArchiveError::MalformedName { name : res });
+    }
+
+    Ok((res, errors))
+}
 
 pub struct FileHelper {
     pub file : File,
-    pub key_table : [u8; 256],
+    pub key_table : KeyTable,
     pub position : usize
 }
 
 #[allow(dead_code)]
 impl FileHelper {
-    fn read_buffer<const N: usize>(&mut self) -> [u8; N] {
+    fn read_buffer<const N: usize>(&mut self) -> std::io::Result<[u8; N]> {
         let mut buffer = [0u8; N];
-        
-        let res = self.file.read_exact(&mut buffer);
-        match res {
-            Err(error) if error.kind() == ErrorKind::UnexpectedEof => panic!("Unexpected end of file"),
-            _ => {}
-        }
-        res.expect("Unexpected error during read");
+        self.file.read_exact(&mut buffer)?;
 
         for byte in &mut buffer {
             *byte = self.key_table[*byte as usize];
@@ -27,178 +46,409 @@ impl FileHelper {
 
         self.position += N;
 
-        buffer
+        Ok(buffer)
     }
     
-    fn write_buffer(&mut self, buffer: &[u8]) {
-        self.file.write_all(buffer).unwrap();
+    fn write_buffer(&mut self, buffer: &[u8]) -> std::io::Result<()> {
+        self.file.write_all(buffer)?;
         self.position += buffer.len();
+        Ok(())
+    }
+
+    // The inverse of reading through the key table, so that doing so gets back buffer. Everything readers pass
+    // through the table has to be written with this: headers, and entries that aren't SPB or Bzip2.
+    fn write_buffer_through_keytable(&mut self, buffer : &[u8]) -> std::io::Result<()> {
+        let inverse = self.key_table.invert();
+        let encoded : Vec<u8> = buffer.iter().map(|&byte| inverse.apply(byte)).collect();
+        self.write_buffer(&encoded)
+    }
+
+    // Entry data, through the key table only if readers will read it through the table.
+    fn write_entry_data(&mut self, data : &[u8], compression : Compression, name : &str) -> std::io::Result<()> {
+        if is_keyed(compression, name) {
+            self.write_buffer_through_keytable(data)
+        } else {
+            self.write_buffer(data)
+        }
     }
 
-    fn read_u8(&mut self) -> u8 {
+    fn read_u8(&mut self) -> std::io::Result<u8> {
         const SIZE : usize = std::mem::size_of::<u8>();
-        let buffer = self.read_buffer::<SIZE>();
-        u8::from_be_bytes(buffer)
+        let buffer = self.read_buffer::<SIZE>()?;
+        Ok(u8::from_be_bytes(buffer))
     }
 
-    fn read_u16_be(&mut self) -> u16 {
+    fn read_u16_be(&mut self) -> std::io::Result<u16> {
         const SIZE : usize = std::mem::size_of::<u16>();
-        let buffer = self.read_buffer::<SIZE>();
-        u16::from_be_bytes(buffer)
+        let buffer = self.read_buffer::<SIZE>()?;
+        Ok(u16::from_be_bytes(buffer))
     }
 
-    fn read_u32_be(&mut self) -> u32 {
+    fn read_u32_be(&mut self) -> std::io::Result<u32> {
         const SIZE : usize = std::mem::size_of::<u32>();
-        let buffer = self.read_buffer::<SIZE>();
-        u32::from_be_bytes(buffer)
+        let buffer = self.read_buffer::<SIZE>()?;
+        Ok(u32::from_be_bytes(buffer))
     }
     
-    fn read_u32_le(&mut self) -> u32 {
+    fn read_u32_le(&mut self) -> std::io::Result<u32> {
         const SIZE : usize = std::mem::size_of::<u32>();
-        let buffer = self.read_buffer::<SIZE>();
-        u32::from_le_bytes(buffer)
+        let buffer = self.read_buffer::<SIZE>()?;
+        Ok(u32::from_le_bytes(buffer))
     }
     
-    fn write_u8_be(&mut self, value : u8) {
-        self.write_buffer(&value.to_be_bytes());
+    fn write_u8_be(&mut self, value : u8) -> std::io::Result<()> {
+        self.write_buffer_through_keytable(&value.to_be_bytes())
     }
     
-    fn write_u16_be(&mut self, value : u16) {
-        self.write_buffer(&value.to_be_bytes());
+    fn write_u16_be(&mut self, value : u16) -> std::io::Result<()> {
+        self.write_buffer_through_keytable(&value.to_be_bytes())
     }
     
-    fn write_u32_be(&mut self, value : u32) {
-        self.write_buffer(&value.to_be_bytes());
+    fn write_u32_be(&mut self, value : u32) -> std::io::Result<()> {
+        self.write_buffer_through_keytable(&value.to_be_bytes())
+    }
+
+    fn write_u32_le(&mut self, value : u32) -> std::io::Result<()> {
+        self.write_buffer_through_keytable(&value.to_le_bytes())
     }
 
-    fn write_u32_le(&mut self, value : u32) {
-        self.write_buffer(&value.to_le_bytes());
+    // With lossy names set, bytes that aren't valid Shift-JIS become replacement characters rather than being an
+    // error, the returned bool says whether that happened.
+    fn read_shiftjis(&mut self, options : &ReadOptions) -> Result<(String, bool), ArchiveError> {
+        let buffer = self.read_until(0)?;
+        decode_shiftjis_name(&buffer, options)
     }
 
-    fn read_shiftjis(&mut self) -> String {
+    // The bytes up to the next terminator, read through the key table, leaving the file just after the terminator.
+    // Names are read a chunk at a time and searched in memory, which is far quicker for headers of thousands of
+    // entries than reading them a byte at a time, and the file is then put back to where the name ended.
+    fn read_until(&mut self, terminator : u8) -> std::io::Result<Vec<u8>> {
+        const CHUNK_SIZE : usize = 256;
+
         let mut buffer : Vec<u8> = Vec::new();
-            
+        let mut chunk = [0u8; CHUNK_SIZE];
+
         loop {
-            let byte = self.read_u8();
-            
-            if byte == 0 {
-                break;
+            let read = self.file.read(&mut chunk)?;
+            if read == 0 {
+                return Err(std::io::Error::new(ErrorKind::UnexpectedEof, "the file ends partway through a name"));
             }
-            
-            buffer.push(byte);
-        }
-        
-        use encoding_rs::SHIFT_JIS;
-        let (res, _enc, errors) = SHIFT_JIS.decode(&buffer);
-        if errors {
-            panic!("Couldn't read a string from this file.");
-        }
 
-        res.to_string()
-    }
+            let chunk = &mut chunk[..read];
+            for byte in chunk.iter_mut() {
+                *byte = self.key_table[*byte as usize];
+            }
 
-    fn write_shiftjis(&mut self, value : &str) {
-        use encoding_rs::SHIFT_JIS;
-        let (res, _enc, errors) = SHIFT_JIS.encode(&value);
-        if errors {
-            panic!("Couldn't read a string from this file.");
+            match chunk.iter().position(|&byte| byte == terminator) {
+                Some(end) => {
+                    buffer.extend_from_slice(&chunk[..end]);
+                    self.file.seek(SeekFrom::Current(end as i64 + 1 - read as i64))?;
+                    self.position += end + 1;
+                    return Ok(buffer);
+                }
+                None => {
+                    buffer.extend_from_slice(chunk);
+                    self.position += read;
+                }
+            }
         }
+    }
 
-        self.write_buffer(res.as_ref());
-        self.write_buffer(b"\0");
+    fn write_shiftjis(&mut self, value : &str) -> Result<(), ArchiveError> {
+        let encoded = encode_shiftjis(value)?;
+        self.write_buffer_through_keytable(&encoded)?;
+        self.write_buffer_through_keytable(b"\0")?;
+        Ok(())
     }
 
-    fn read_quoted_shiftjis(&mut self) -> String {
-        let mut buffer : Vec<u8> = Vec::new();
+    fn write_quoted_shiftjis(&mut self, value : &str) -> Result<(), ArchiveError> {
+        let encoded = encode_shiftjis(value)?;
+        self.write_buffer_through_keytable(b"\"")?;
+        self.write_buffer_through_keytable(&encoded)?;
+        self.write_buffer_through_keytable(b"\"")?;
+        Ok(())
+    }
 
-        let first_byte = self.read_u8();
-        if first_byte != b'\"' {
-            panic!("Archive unexpectedly doesn't have a quoted string: {first_byte}.");
-        }
-            
+    fn write_file(&mut self, src: &mut File, buffer: &mut [u8]) -> std::io::Result<()> {
         loop {
-            let byte = self.read_u8();
-            
-            if byte == b'\"' {
-                break;
+            let size = src.read(buffer)?;
+            if size == 0 {
+                return Ok(());
             }
-            
-            buffer.push(byte);
-        }
 
-        use encoding_rs::SHIFT_JIS;
-        let (res, _enc, errors) = SHIFT_JIS.decode(&buffer);
-        if errors {
-            panic!("Couldn't read a string from this file.");
+            self.write_buffer_through_keytable(&buffer[0..size])?;
         }
+    }
 
-        res.to_string()
+    fn seek(&mut self, seek : SeekFrom) -> std::io::Result<()> {
+        self.position = self.file.seek(seek)? as usize;
+        Ok(())
     }
 
-    fn write_quoted_shiftjis(&mut self, value : &str) {
-        use encoding_rs::SHIFT_JIS;
-        let (res, _enc, errors) = SHIFT_JIS.encode(&value);
-        if errors {
-            panic!("Couldn't read a string from this file.");
-        }
-        
-        self.write_buffer(b"\"");
-        self.write_buffer(res.as_ref());
-        self.write_buffer(b"\"");
+    fn try_read_slice(&mut self, offset : usize, size : usize) -> std::io::Result<Vec<u8>> {
+        self.file.seek(SeekFrom::Start(offset as u64))?;
+        let mut buffer : Vec<u8> = vec![0; size];
+        self.file.read_exact(&mut buffer)?;
+
+        self.position += size;
+
+        Ok(buffer)
     }
 
-    fn write_file(&mut self, src: &mut File, buffer: &mut [u8; 64536])
-    {
-        loop {
-            match src.read(buffer) {
-                Ok(size) => {
-                    if size == 0 {
-                        return;
-                    }
+    // As try_read_slice, but onto the end of buffer, through the key table if keyed is set.
+    fn try_read_onto(&mut self, offset : usize, size : usize, buffer : &mut Vec<u8>, keyed : bool) -> std::io::Result<()> {
+        self.file.seek(SeekFrom::Start(offset as u64))?;
+        let start = buffer.len();
+        buffer.resize(start + size, 0);
+        self.file.read_exact(&mut buffer[start..])?;
 
-                    self.write_buffer(&buffer[0..size]);
-                },
-                Err(err) => {
-                    panic!("Error reading file: {}", err);
-                }
+        if keyed {
+            for byte in &mut buffer[start..] {
+                *byte = self.key_table[*byte as usize];
             }
         }
-    }
 
-    fn seek(&mut self, seek : SeekFrom) {
-        self.position = self.file.seek(seek).unwrap() as usize;
+        self.position += size;
+
+        Ok(())
     }
 
     fn read_slice(&mut self, offset : usize, size : usize) -> Vec<u8> {
-        self.file.seek(SeekFrom::Start(offset as u64)).unwrap();
-        let mut buffer : Vec<u8> = vec![0; size];
-        self.file.read(&mut buffer).unwrap();
-
-        self.position += size;
-
-        buffer
+        self.try_read_slice(offset, size).unwrap()
     }
 
-    fn read_slice_through_keytable(&mut self, offset : usize, size : usize) -> Vec<u8> {
-        let mut output = self.read_slice(offset, size);
+    fn try_read_slice_through_keytable(&mut self, offset : usize, size : usize) -> std::io::Result<Vec<u8>> {
+        let mut output = self.try_read_slice(offset, size)?;
         for byte in &mut output {
             *byte = self.key_table[*byte as usize];
         }
 
         // read_slice alters self.position, don't need to do it redundantly here.
 
-        output
+        Ok(output)
+    }
+}
+
+#[derive(Debug)]
+pub enum ArchiveError {
+    Io(std::io::Error),
+    Spb(crate::image::SpbError),
+    TooManyEntries { count : usize }, // SAR and NSA store their entry count as a u16.
+    RoundtripMismatch { compression : Compression }, // Decompressing an entry didn't give back what was compressed.
+    OffsetOverflow, // An offset or size goes beyond the 4 GiB an archive can address.
+    MalformedHeader { position : usize }, // The header has something that can't be there, at this byte of the file.
+    LayoutMismatch { name : String }, // An index being written has an entry whose offset or data doesn't fit its layout.
+    ChangedDuringWrite { name : String }, // A file compressed to a different size once its size was already written.
+    EntryNotFound { name : String },
+    NotSpb { name : String }, // The entry isn't an SPB image.
+    CantReplaceInPlace { name : String }, // The new data doesn't fit where the entry's old data is.
+    UnknownCompression { compression : u8 }, // The entry's compression byte isn't one we know how to decompress.
+    ZstdDisabled, // A Zstd entry was to be compressed or decompressed without the zstd feature.
+    // The header lists more entries than the rest of the file could hold even with the shortest names.
+    ImpossibleEntryCount { count : usize, fits : usize },
+    // Reading as a stream, an entry starts at this byte, before the end of the one before it.
+    NotStreamable { offset : usize },
+    InvalidEntryName { path : PathBuf }, // A file to be archived has a path that isn't valid UTF-8.
+    MalformedName { name : String }, // An entry's name isn't valid Shift-JIS, name has replacement characters for it.
+    UnencodableName { name : String } // A file to be archived has a name with characters Shift-JIS doesn't have.
+}
+
+impl std::fmt::Display for ArchiveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ArchiveError::Io(error) => write!(f, "{error}"),
+            ArchiveError::Spb(error) => write!(f, "couldn't decode SPB image: {error}"),
+            ArchiveError::TooManyEntries { count } => write!(f, "{count} entries is more than the {} this archive format can hold", u16::MAX),
+            ArchiveError::RoundtripMismatch { compression } => write!(f, "{compression:?} compressed data didn't decompress back to the original"),
+            ArchiveError::OffsetOverflow => write!(f, "an offset or size goes beyond the 4 GiB an archive can address"),
+            ArchiveError::MalformedHeader { position } => write!(f, "the header is malformed at byte {position}"),
+            ArchiveError::LayoutMismatch { name } => write!(f, "{name} isn't where the index's layout puts it or its data is a different size, recompute the layout before writing"),
+            ArchiveError::ChangedDuringWrite { name } => write!(f, "{name} changed while the archive was being written"),
+            ArchiveError::EntryNotFound { name } => write!(f, "{name} isn't in the archive"),
+            ArchiveError::NotSpb { name } => write!(f, "{name} isn't an SPB image"),
+            ArchiveError::CantReplaceInPlace { name } => write!(f, "the new data for {name} doesn't fit where its old data is, the archive has to be rebuilt to change it"),
+            ArchiveError::UnknownCompression { compression } => write!(f, "the entry is stored with compression {compression}, which isn't one we know"),
+            ArchiveError::ZstdDisabled => write!(f, "Zstd entries need nscripter_formats built with its zstd feature"),
+            ArchiveError::ImpossibleEntryCount { count, fits } => write!(f, "the header lists {count} entries, but the file only has room for {fits}"),
+            ArchiveError::NotStreamable { offset } => write!(f, "an entry at byte {offset} starts before the end of the one before it, so the archive can't be read as a stream"),
+            ArchiveError::InvalidEntryName { path } => write!(f, "{} can't be an entry name, it isn't valid UTF-8", path.display()),
+            ArchiveError::MalformedName { name } => write!(f, "the entry name {name} isn't valid Shift-JIS, read it with lossy names to open the archive anyway"),
+            ArchiveError::UnencodableName { name } => write!(f, "the entry name {name} has characters Shift-JIS can't store")
+        }
+    }
+}
+
+impl std::error::Error for ArchiveError {}
+
+impl From<std::io::Error> for ArchiveError {
+    fn from(error: std::io::Error) -> Self {
+        ArchiveError::Io(error)
+    }
+}
+
+// SAR, NSA and NS2 store offsets and sizes as u32s, from the start of the archive or of its data, so an archive can be
+// at most 4 GiB. Positions read from a header are summed within that limit, so a malformed one is an error rather than
+// wrapping around to a small offset pointing at the wrong data, and writing fails rather than truncating offsets once
+// an archive would grow past it.
+fn archive_position(parts : &[u32]) -> Result<u32, ArchiveError> {
+    parts.iter().try_fold(0u32, |sum, &part| sum.checked_add(part)).ok_or(ArchiveError::OffsetOverflow)
+}
+
+fn archive_u32(value : usize) -> Result<u32, ArchiveError> {
+    u32::try_from(value).map_err(|_| ArchiveError::OffsetOverflow)
+}
+
+// Refuses a header listing more entries than could fit between its entry count and data offset, at offset, and the
+// end of the file, entry_size being the least an entry can take: an empty name's terminator and the fields after it.
+// A corrupt count would otherwise have the header read well past the end of the file.
+fn check_entry_count(file : &mut FileHelper, offset : u32, count : u16, entry_size : usize) -> Result<(), ArchiveError> {
+    let remaining = (file.file.metadata()?.len() as usize).saturating_sub(offset as usize + 6);
+    let fits = remaining / entry_size;
+
+    if count as usize > fits {
+        return Err(ArchiveError::ImpossibleEntryCount { count : count as usize, fits });
     }
+    Ok(())
+}
+
+// Where a position within an archive that starts archive_offset bytes into the file is in the file.
+fn file_position(archive_offset : u32, position : u32) -> Result<usize, ArchiveError> {
+    (archive_offset as usize).checked_add(position as usize).ok_or(ArchiveError::OffsetOverflow)
 }
 
+// Compression::Zstd's byte, 'Z', well clear of the small numbers ONScripter and its forks use.
+pub const ZSTD_BYTE : u8 = 0x5A;
+
+// The byte NSA headers give each entry's compression as, see From<Compression> for u8.
 #[derive(Clone, Copy, Debug)] 
 pub enum Compression {
-    None = 0,
-    Spb = 1,
-    Lzss = 2, // Lempel–Ziv–Storer–Szymanski Compression
-    Bzip2 = 4, // Bzip2 Compression: sometimes embedded files have "nbz" extension, these are just Bzip2.
+    None,
+    Spb,
+    Lzss, // Lempel–Ziv–Storer–Szymanski Compression
+    Bzip2, // Bzip2 Compression: sometimes embedded files have "nbz" extension, these are just Bzip2.
+    // Not something ONScripter reads, only for forks that have added it or archives that are only ever read by this
+    // crate. Entries are a single zstd frame, stored without going through the key table, under byte ZSTD_BYTE.
+    // Compressing and decompressing them needs the zstd feature, without it they're listed but can't be extracted.
+    Zstd,
+    // A byte we don't know, such as 3, which some forks use for schemes of their own. The rest of the archive can
+    // still be read, only extracting the entry is an error.
+    Unknown(u8)
+}
+
+impl From<Compression> for u8 {
+    fn from(compression : Compression) -> u8 {
+        match compression {
+            Compression::None => 0,
+            Compression::Spb => 1,
+            Compression::Lzss => 2,
+            Compression::Bzip2 => 4,
+            Compression::Zstd => ZSTD_BYTE,
+            Compression::Unknown(byte) => byte
+        }
+    }
+}
+
+impl TryFrom<u8> for Compression {
+    type Error = u8;
+
+    fn try_from(value : u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Compression::None),
+            1 => Ok(Compression::Spb),
+            2 => Ok(Compression::Lzss),
+            4 => Ok(Compression::Bzip2),
+            ZSTD_BYTE => Ok(Compression::Zstd),
+            _ => Err(value)
+        }
+    }
+}
+
+// Why file_encoding_to_use settled on a particular compression for a file.
+#[derive(Clone, Copy, Debug)]
+pub enum CompressionReason {
+    MatchedMagic, // The file's leading bytes identified it (BMP, RIFF/WAV, already compressed data).
+    Extension,    // The contents didn't say anything, but the file extension did.
+    Override,     // The entry was given a compression in CreateOptions::overrides.
+    Default       // Nothing matched an enabled scheme, so it's stored as-is.
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct CompressionPlan {
+    pub compression : Compression,
+    pub reason : CompressionReason
 }
 
+// What NSA archives store in each entry's decompressed size field. ONScripter reads it to know how much to
+// decode, only working it out for itself for Bzip2 and SPB entries when it's zero, so it needs Actual. Readers
+// built on SAR support take the size from the stored length and some write 0 themselves, those accept either.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DecompressedSize {
+    #[default]
+    Actual,
+    Zero
+}
+
+// Which compression schemes archive creation is allowed to pick from. Only NSA archives can store
+// compressed entries, SAR archives ignore these.
+#[derive(Clone, Debug, Default)]
+pub struct CreateOptions {
+    pub bzip2 : bool,
+    pub spb : bool,
+    pub lzss : bool,
+    pub threads : usize, // For create_nsa_archive_parallel, 0 uses one per core.
+    pub decompressed_size : DecompressedSize,
+    // Compression for particular entries, keyed by their path within the archive, in place of what the enabled
+    // schemes would pick. SPB still falls back to storing the entry as-is if it isn't a BMP it can encode.
+    pub overrides : HashMap<PathBuf, Compression>,
+    // Names to store particular entries under, keyed by their path within the archive, for files whose name on disk
+    // isn't the one the archive should have, such as entries rndecode's --safe-names renamed. NS2 ignores these.
+    pub names : HashMap<PathBuf, String>,
+    // Entry data starts on a multiple of this many bytes from the start of the archive, padded with zeroes in between,
+    // as archives meant for CD-ROMs are laid out by sector. 0 or 1 packs entries tightly. NS2 can't be padded, as
+    // its entries are found by adding up the sizes before them, so it ignores this.
+    pub alignment : usize,
+    // Decode every SPB entry again once it's encoded and fail the write if it doesn't give back the BMP's pixels,
+    // rather than storing an image that displays wrong. It costs a decode per image, so it's off by default.
+    pub verify_spb : bool,
+    // The Bzip2 block size in units of 100k, 1 to 9, 0 uses 9 as bzip2's best compression does. See compress_bz2 for
+    // what it takes to make Bzip2 entries byte for byte the same from one build to the next.
+    pub bzip2_block_size : u32,
+    // How many bytes at a time entries are copied into SAR archives, 0 uses 64 KiB. A larger buffer means fewer reads
+    // and writes for large files.
+    pub copy_buffer_size : usize,
+    // Compress entries nothing else would with zstd, see Compression::Zstd for why the archive won't work with stock
+    // ONScripter. Data that's already compressed is still stored as-is.
+    pub zstd : bool
+}
+
+// How archives are read when opened.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ReadOptions {
+    // Entry names that aren't valid Shift-JIS get replacement characters rather than failing the open. Those
+    // entries are reported so they can be told apart, but their names won't match anything on disk.
+    pub lossy_names : bool,
+    // The table entry names are decoded with, see ShiftJisTable for how they differ.
+    pub shift_jis : ShiftJisTable
+}
+
+#[derive(Debug)]
+pub struct PlannedEntry {
+    pub name : String,
+    pub compression : Compression,
+    pub offset : usize,
+    pub size : usize,
+    pub original_size : usize
+}
+
+// The layout an archive would have if it were created from the given files, offsets are from the start of the file.
+#[derive(Debug)]
+pub struct ArchivePlan {
+    pub entries : Vec<PlannedEntry>,
+    pub header_size : usize,
+    pub total_size : usize
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum ArchiveType {
     SAR,
     NSA,
@@ -213,22 +463,213 @@ pub struct ArchiveEntry {
     pub compression : Compression
 }
 
+// How a decoded entry name is normalized before it's used as a path on disk. The entry's own name is
+// left untouched, so a repack can still reproduce it exactly.
+#[derive(Clone, Copy, Debug, Default)]
+pub enum NameNormalization {
+    #[default]
+    None,
+    Nfc,
+    Nfkc
+}
+
+pub fn normalize_name(name : &str, normalization : NameNormalization) -> String {
+    use unicode_normalization::UnicodeNormalization;
+
+    match normalization {
+        NameNormalization::None => name.to_string(),
+        NameNormalization::Nfc => name.nfc().collect(),
+        NameNormalization::Nfkc => name.nfkc().collect()
+    }
+}
+
+// How extracted entries are laid out under the output directory. PreserveTree makes a directory for each one in an
+// entry's name, Flatten writes every entry straight into the output directory under its file name alone, so entries
+// with the same file name in different directories end up at the same path.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ExtractLayout {
+    #[default]
+    PreserveTree,
+    Flatten
+}
+
+impl ExtractLayout {
+    // The part of an entry's name that's laid out under the output directory, for sanitize_entry_path to resolve.
+    pub fn entry_path(self, name : &str) -> &str {
+        match self {
+            ExtractLayout::PreserveTree => name,
+            ExtractLayout::Flatten => name.rsplit(['\\', '/']).next().unwrap_or(name)
+        }
+    }
+}
+
+// Where an entry should be extracted to within out_dir. Names come from whoever made the archive, so they're only
+// trusted so far: either separator starts a directory, as games were made on Windows, and "." and ".." are resolved.
+// Names that are absolute, climb out of out_dir, have a drive in them, or don't leave a file name give None.
+pub fn sanitize_entry_path(name : &str, out_dir : &Path) -> Option<PathBuf> {
+    let mut parts = Vec::new();
+
+    for (i, part) in name.split(['\\', '/']).enumerate() {
+        match part {
+            "" if i == 0 => return None,
+            "" | "." => continue,
+            ".." => { parts.pop()?; }
+            part if part.contains(':') => return None,
+            part => parts.push(part)
+        }
+    }
+
+    if parts.is_empty() {
+        return None;
+    }
+
+    Some(parts.iter().fold(out_dir.to_path_buf(), |path, part| path.join(part)))
+}
+
+// The entry name with everything Windows won't have in a file name made safe, and whether anything had to be. Each
+// part between separators is looked at alone: the characters <>:"|?* and control characters become '_', as do dots
+// and spaces ending a part, and device names such as CON or COM1, with or without an extension, get a '_' in front.
+// Separators and "." and ".." parts are left for sanitize_entry_path.
+pub fn safe_os_filename(name : &str) -> (String, bool) {
+    const RESERVED : [&str; 22] = ["CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7",
+        "COM8", "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9"];
+
+    let safe_part = |part : &str| -> String {
+        if part == "." || part == ".." {
+            return part.to_string();
+        }
+
+        let mut safe : String = part.chars()
+            .map(|character| if character.is_control() || "<>:\"|?*".contains(character) { '_' } else { character })
+            .collect();
+
+        let kept = safe.trim_end_matches(['.', ' ']).len();
+        let trimmed = safe.len() - kept;
+        safe.truncate(kept);
+        safe.extend(std::iter::repeat_n('_', trimmed));
+
+        let stem = safe.split('.').next().unwrap_or("");
+        if RESERVED.iter().any(|reserved| stem.eq_ignore_ascii_case(reserved)) {
+            safe.insert(0, '_');
+        }
+        safe
+    };
+
+    let mut safe = String::with_capacity(name.len());
+    let mut rest = name;
+    while let Some(separator) = rest.find(['\\', '/']) {
+        safe.push_str(&safe_part(&rest[..separator]));
+        safe.push_str(&rest[separator..separator + 1]);
+        rest = &rest[separator + 1..];
+    }
+    safe.push_str(&safe_part(rest));
+
+    let changed = safe != name;
+    (safe, changed)
+}
+
+// Whether the file at path holds exactly these bytes, such as an entry's extracted data against an earlier
+// extraction of it. The sizes are compared first and then the bytes themselves, a file that doesn't exist doesn't
+// match.
+pub fn matches_file(data : &[u8], path : &Path) -> std::io::Result<bool> {
+    match std::fs::metadata(path) {
+        Ok(metadata) if metadata.len() != data.len() as u64 => return Ok(false),
+        Ok(_) => {},
+        Err(error) if error.kind() == ErrorKind::NotFound => return Ok(false),
+        Err(error) => return Err(error)
+    }
+
+    Ok(std::fs::read(path)? == data)
+}
+
+// 64 bit FNV-1a, which unlike std's hasher is the same on every build, so content hashes can be kept and compared later.
+fn fnv1a(hash : u64, bytes : &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3))
+}
+
+// A hash of what an archive holds rather than how it's stored, its entries' names and decompressed data. Entries are
+// hashed on their own and XORed together, so archives with the same files hash the same whatever their order,
+// compression or type. SPB is the exception, the BMP it decodes to has the same pixels but not always the same bytes.
+pub fn try_archive_content_hash(path : &Path, archive_type : ArchiveType, offset : u32, key_table : &KeyTable) -> Result<u64, ArchiveError> {
+    let file = File::open(path)?;
+    let mut archive = Archive::try_open_file_with_options(file, archive_type, offset, key_table, &ReadOptions::default())?;
+
+    let mut hash = 0;
+    for i in 0..archive.index.entries.len() {
+        let name = archive.index.entries[i].name.clone();
+        let data = archive.try_extract(archive.index.entries[i].info())?;
+
+        // The name's length goes in between so the same bytes split differently between name and data don't match.
+        let name_hash = fnv1a(0xcbf29ce484222325, name.as_bytes());
+        hash ^= fnv1a(fnv1a(name_hash, &(name.len() as u64).to_le_bytes()), &data);
+    }
+
+    Ok(hash)
+}
+
+pub fn archive_content_hash(path : &Path, archive_type : ArchiveType, offset : u32, key_table : &KeyTable) -> u64 {
+    try_archive_content_hash(path, archive_type, offset, key_table).expect("Failed to hash the archive's contents")
+}
+
+// The file names ONScripter opens archives of a type under, in the order it opens them. base is arc for SAR and NSA,
+// giving arc.sar, and arc.nsa then arc1.nsa to arc9.nsa. NS2 archives are numbered 00.ns2 to 99.ns2, which is a base
+// of "". ONScripter stops at the first numbered archive that's missing, so arc2.nsa isn't read without arc1.nsa.
+pub fn archive_sequence_names(base : &str, archive_type : &ArchiveType) -> Vec<String> {
+    match archive_type {
+        ArchiveType::SAR => vec![format!("{base}.sar")],
+        ArchiveType::NSA => std::iter::once(format!("{base}.nsa")).chain((1..=9).map(|i| format!("{base}{i}.nsa"))).collect(),
+        ArchiveType::NS2 => (0..100).map(|i| format!("{base}{i:02}.ns2")).collect()
+    }
+}
+
+// Each type of archive with the base ONScripter names it with, in the order it searches them for a file.
+pub const ARCHIVE_BASES : [(ArchiveType, &str); 3] = [(ArchiveType::NS2, ""), (ArchiveType::NSA, "arc"), (ArchiveType::SAR, "arc")];
+
+// The archives in dir that ONScripter would open, in its order, matching names without regard to case. The sequence
+// ends at the first name that isn't there, anything numbered after it is left out.
+pub fn find_archive_sequence(dir : &Path, base : &str, archive_type : &ArchiveType) -> std::io::Result<Vec<PathBuf>> {
+    let mut files : HashMap<String, PathBuf> = HashMap::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if let Some(name) = path.file_name().and_then(|name| name.to_str()) {
+            files.insert(name.to_lowercase(), path.clone());
+        }
+    }
+
+    let sequence = archive_sequence_names(base, archive_type).into_iter()
+        .map_while(|name| files.get(&name.to_lowercase()).cloned())
+        .collect();
+
+    Ok(sequence)
+}
+
 pub struct ArchiveEntryInfo {
     pub offset : usize,
     pub size : usize,
-    _decompressed_size : Option<usize>,
+    decompressed_size : Option<usize>,
     pub compression : Compression
 }
 
 impl ArchiveEntry {
+    // An entry for an index being put together in memory, it gets its offset once the index's layout is recomputed.
+    // size is how much is stored, decompressed_size is only needed for NSA entries that are compressed.
+    pub fn new(name : String, size : usize, compression : Compression, decompressed_size : Option<usize>) -> ArchiveEntry {
+        ArchiveEntry { name, offset : 0, size, decompressed_size, compression }
+    }
+
     pub fn info(&self) -> ArchiveEntryInfo {
         ArchiveEntryInfo {
             offset : self.offset, 
             size : self.size, 
-            _decompressed_size : self.decompressed_size, 
+            decompressed_size : self.decompressed_size, 
             compression : self.compression, 
         }
     }
+
+    // The size the header gives for the entry once it's decompressed, only NSA headers have one.
+    pub fn decompressed_size(&self) -> Option<usize> {
+        self.decompressed_size
+    }
 }
 
 pub struct ArchiveIndex {
@@ -237,290 +678,3310 @@ pub struct ArchiveIndex {
     pub offset : usize
 }
 
-pub struct Archive {
-    file : FileHelper,
-    pub index : ArchiveIndex,
-    pub archive_type : ArchiveType,
-}
-
-pub fn extract_bz2(file: File, key_table : [u8; 256]) -> Vec<u8> {
-    let mut file = file;
-    let size = file.seek(SeekFrom::End(0)).unwrap();
-    file.seek(SeekFrom::Start(0)).unwrap();
-    let mut file_helper = FileHelper {file, key_table, position : 0};
-    let buffer = file_helper.read_slice(0, size as usize);
-
-    use bzip2_rs::DecoderReader;
-    let input = buffer;
+impl ArchiveIndex {
+    // When several entries share a name, the name maps to the first of them, the rest can still be reached by index.
+    pub fn new(entries : Vec<ArchiveEntry>, offset : usize) -> ArchiveIndex {
+        let mut entries_map : HashMap<String, usize> = HashMap::new();
+        for (i, entry) in entries.iter().enumerate() {
+            entries_map.entry(entry.name.clone()).or_insert(i);
+        }
 
-    // First 4 bytes are the original size, the decoder doesn't need this, so we can skip them.
-    let mut reader = DecoderReader::new(&input[4..]);
-    let mut buffer = Vec::new();
-    std::io::copy(&mut reader, &mut buffer).unwrap();
+        ArchiveIndex { entries, entries_map, offset }
+    }
 
-    return buffer;
-}
+    // Names used by more than one entry, each listed once.
+    pub fn duplicate_names(&self) -> Vec<String> {
+        let mut duplicates : Vec<String> = Vec::new();
 
-impl Archive {
-    /*
-    fn write_sar_header(&self, archive : &mut Archive) {
-        let mut offsets : Vec<(usize, usize)> = Vec::new();
-        archive.file.seek(SeekFrom::Start(archive.index.offset as u64));
+        for (i, entry) in self.entries.iter().enumerate() {
+            if self.entries_map[&entry.name] != i && !duplicates.contains(&entry.name) {
+                duplicates.push(entry.name.clone());
+            }
+        }
 
-        // TODO: Should check this cast
-        archive.file.write_u16_be(archive.index.entries.len() as u16);
+        duplicates
+    }
 
-        // Skip writing out the internal offset for now.
-        archive.file.seek(SeekFrom::Current(4));
+    // How many entries there are of each extension, lowercased, and their stored sizes added up. Entries without an
+    // extension are counted under "".
+    pub fn extension_histogram(&self) -> BTreeMap<String, (usize, usize)> {
+        let mut histogram : BTreeMap<String, (usize, usize)> = BTreeMap::new();
 
-        for entry in &archive.index.entries {
-            archive.file.write_shiftjis(&entry.name);
-            
-            // Skip writing out the internal offset for now.
-            offsets.push((archive.file.position, entry.size));
-            archive.file.seek(SeekFrom::Current(4));
+        for entry in &self.entries {
+            let file_name = entry.name.rsplit(['\\', '/']).next().unwrap_or("");
+            let extension = file_name.rsplit_once('.').map_or("", |(_, extension)| extension).to_lowercase();
 
-            // TODO: Should check this cast
-            archive.file.write_u32_be(entry.size as u32);
+            let (count, size) = histogram.entry(extension).or_default();
+            *count += 1;
+            *size += entry.size;
         }
 
-        let file_offset = archive.file.position;
-        archive.file.seek(SeekFrom::Start(archive.index.offset as u64 + 2));
-        archive.file.write_u32_be(file_offset);
+        histogram
+    }
 
-        for (offset_position, entry_size) in offsets {
-            archive.file.seek(SeekFrom::Start(archive.index.offset as u64 + 2));
-            archive.file.write_u32_be(file_offset);
+    // The largest power of two, from 16 up, that every entry's data starts on a multiple of, as archives padded out to
+    // CD-ROM sectors have. None for tightly packed archives, or ones with too few entries to tell. Offsets are from the
+    // start of the file, which for an archive embedded in another file isn't the start of the archive.
+    pub fn alignment(&self) -> Option<usize> {
+        if self.entries.len() < 2 {
+            return None;
         }
 
-        //archive.file.write_u32_be(archive.index.offset )
-    } 
-    */
-
+        let combined = self.entries.iter().fold(0, |bits, entry| bits | entry.offset);
+        if combined == 0 {
+            return None;
+        }
 
-    fn parse_sar_header(file : &mut FileHelper, offset : u32) -> ArchiveIndex {
-        let mut entries : Vec<ArchiveEntry> = Vec::new();
-        let num_of_entries = file.read_u16_be();
-        let file_offset = (file.read_u32_be() + offset) as usize; // Entries start at this address in the file
+        let alignment = 1 << combined.trailing_zeros();
+        (16 <= alignment).then_some(alignment)
+    }
 
-        for _ in 0..num_of_entries {
-            let name = file.read_shiftjis();
-            let compression = Compression::None;
-            let offset = file.read_u32_be() as usize + file_offset;
-            let size = file.read_u32_be() as usize;
-            let decompressed_size : Option<usize> = Some(size);
+    // Whether each entry's data starts at or after the end of the one before it, as every packer we know of lays them
+    // out, so they can all be read front to back without seeking backwards. Gaps between them are allowed.
+    pub fn is_sequential(&self) -> bool {
+        self.entries.windows(2).all(|pair| pair[0].offset + pair[0].size <= pair[1].offset)
+    }
 
-            entries.push(ArchiveEntry {
-                name, offset, size, decompressed_size, compression
-            });
-        }
+    // How many bytes the header describing these entries takes up in an archive of the given type, an error if a name
+    // can't be written as Shift-JIS.
+    pub fn header_size(&self, archive_type : ArchiveType) -> Result<usize, ArchiveError> {
+        self.entries.iter().try_fold(header_base_size(archive_type), |size, entry| Ok(size + header_entry_size(&entry.name, archive_type)?))
+    }
 
-        let mut entries_map : HashMap<String, usize> = HashMap::new();
-        for (i, entry) in entries.iter().enumerate() {
-            entries_map.insert(entry.name.clone(), i);
+    // Lays the entries out one after another in their current order, starting at header_size, such as after entries
+    // have been added, removed or had their data changed. The name lookup is rebuilt to match.
+    pub fn recompute_layout(&mut self, header_size : usize) {
+        let mut offset = header_size;
+        for entry in &mut self.entries {
+            entry.offset = offset;
+            offset += entry.size;
         }
 
-        ArchiveIndex{ entries, entries_map, offset : file_offset }
+        *self = ArchiveIndex::new(std::mem::take(&mut self.entries), header_size);
     }
-    
-    pub fn create_sar_archive(file: File, root_dir: &Path, entries : Vec<PathBuf>, offset : u32, key_table : [u8; 256]) -> bool {
-        let mut file_helper = FileHelper {file, key_table, position : 0};
 
-        if (u16::MAX as usize) < entries.len() {
-            return false;
+    // Writes the archive this index describes, data holding each entry's stored bytes in the same order as entries.
+    // The layout has to be the one recompute_layout gives with this type's header_size, as that's how the entries
+    // get written.
+    pub fn write_to(&self, file : File, archive_type : ArchiveType, key_table : &KeyTable, data : &[Vec<u8>]) -> Result<(), ArchiveError> {
+        let mut offset = self.header_size(archive_type)?;
+        for (i, entry) in self.entries.iter().enumerate() {
+            if entry.offset != offset || data.get(i).map(Vec::len) != Some(entry.size) {
+                return Err(ArchiveError::LayoutMismatch { name : entry.name.clone() });
+            }
+            offset += entry.size;
         }
 
-        let mut entry_offset_locations = Vec::new();
+        let names : Vec<String> = self.entries.iter().map(|entry| entry.name.clone()).collect();
+        let mut file_helper = FileHelper { file, key_table : *key_table, position : 0 };
 
-        println!("Entries: {}", entries.len());
+        write_archive(&mut file_helper, archive_type, &names, 0, |i| {
+            let entry = &self.entries[i];
+            Ok((entry.compression, data[i].clone(), entry.decompressed_size.unwrap_or(entry.size)))
+        })?;
 
-        file_helper.write_u16_be(entries.len() as u16);
-        file_helper.write_u32_be(0);
+        Ok(())
+    }
+}
 
-        for entry in &entries {
-            let fullpath = root_dir.join(entry);
-            let mut entry_file = std::fs::File::open(&fullpath).unwrap();
-            let entry_size = entry_file.seek(SeekFrom::End(0)).unwrap();
-            let entry_inner_path = entry.to_str().unwrap();
+// Bumped whenever the layout serialize writes changes, so indexes saved by another version are refused.
+const INDEX_MAGIC : &[u8; 8] = b"RNINDEX1";
 
-            file_helper.write_shiftjis(&entry_inner_path);
+fn take_bytes<'a>(bytes : &mut &'a [u8], count : usize) -> Result<&'a [u8], ArchiveError> {
+    if bytes.len() < count {
+        return Err(ArchiveError::Io(std::io::Error::new(ErrorKind::InvalidData, "saved index is cut short")));
+    }
 
-            // Note down where this offset value is for later.
-            entry_offset_locations.push(file_helper.position);            
-            file_helper.write_u32_be(0);
-            file_helper.write_u32_be(entry_size as u32);
+    let (taken, rest) = bytes.split_at(count);
+    *bytes = rest;
+    Ok(taken)
+}
 
-            println!("Entry {}, {}", &entry_inner_path, entry_size);
-        }
+fn take_u64(bytes : &mut &[u8]) -> Result<u64, ArchiveError> {
+    Ok(u64::from_le_bytes(take_bytes(bytes, 8)?.try_into().unwrap()))
+}
 
-        let end_of_header = file_helper.position;
-        println!("End of Header: {end_of_header}");
+fn take_usize(bytes : &mut &[u8]) -> Result<usize, ArchiveError> {
+    usize::try_from(take_u64(bytes)?).map_err(|_| ArchiveError::OffsetOverflow)
+}
 
-        file_helper.seek(SeekFrom::Start(2));
-        file_helper.write_u32_be(end_of_header as u32);
-        file_helper.seek(SeekFrom::Start(end_of_header as u64));
-        
-        // We only want to init this once for all files, so the buffer lives outside of the read_file_into_file call.
-        let mut buffer : [u8; 64536] = [0; 64536];
-        
-        for (entry_file_name, entry_offset_location) in entries.iter().zip(&entry_offset_locations) {
-            let fullpath = root_dir.join(&entry_file_name);
-            let mut entry_file = std::fs::File::open(&fullpath).unwrap();
-            let entry_offset = file_helper.position;
+impl ArchiveIndex {
+    // The index in a form deserialize can read back, so a game can save the index of each archive once and open them
+    // later with Archive::from_index without reading their headers again. Integers are little endian u64s, names
+    // are stored as their UTF-8 length and bytes, and each entry is:
+    //   name, offset, size, compression byte, 1 and the decompressed size or 0 if there isn't one
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut bytes = INDEX_MAGIC.to_vec();
+        bytes.extend_from_slice(&(self.offset as u64).to_le_bytes());
+        bytes.extend_from_slice(&(self.entries.len() as u64).to_le_bytes());
+
+        for entry in &self.entries {
+            bytes.extend_from_slice(&(entry.name.len() as u64).to_le_bytes());
+            bytes.extend_from_slice(entry.name.as_bytes());
+            bytes.extend_from_slice(&(entry.offset as u64).to_le_bytes());
+            bytes.extend_from_slice(&(entry.size as u64).to_le_bytes());
+            bytes.push(u8::from(entry.compression));
+
+            match entry.decompressed_size {
+                Some(size) => {
+                    bytes.push(1);
+                    bytes.extend_from_slice(&(size as u64).to_le_bytes());
+                }
+                None => bytes.push(0)
+            }
+        }
+
+        bytes
+    }
 
-            file_helper.seek(SeekFrom::Start(*entry_offset_location as u64));
-            file_helper.write_u32_be((entry_offset - end_of_header) as u32);
+    // Reads back what serialize wrote. Anything else, or an index cut short, is an InvalidData error.
+    pub fn deserialize(mut bytes : &[u8]) -> Result<ArchiveIndex, ArchiveError> {
+        let invalid = |message : &str| ArchiveError::Io(std::io::Error::new(ErrorKind::InvalidData, message.to_string()));
 
-            file_helper.seek(SeekFrom::Start(entry_offset as u64));
-            file_helper.write_file(&mut entry_file, &mut buffer);
+        if take_bytes(&mut bytes, INDEX_MAGIC.len())? != INDEX_MAGIC {
+            return Err(invalid("not a saved archive index"));
         }
-        
-        return true;
-    }
 
+        let offset = take_usize(&mut bytes)?;
+        let count = take_usize(&mut bytes)?;
+        let mut entries = Vec::new();
+
+        for _ in 0..count {
+            let name_length = take_usize(&mut bytes)?;
+            let name = std::str::from_utf8(take_bytes(&mut bytes, name_length)?).map_err(|_| invalid("saved index has a name that isn't UTF-8"))?.to_string();
+            let entry_offset = take_usize(&mut bytes)?;
+            let size = take_usize(&mut bytes)?;
+            let compression = take_bytes(&mut bytes, 1)?[0];
+            let compression = Compression::try_from(compression).unwrap_or(Compression::Unknown(compression));
+
+            let decompressed_size = match take_bytes(&mut bytes, 1)?[0] {
+                0 => None,
+                1 => Some(take_usize(&mut bytes)?),
+                _ => return Err(invalid("saved index is malformed"))
+            };
 
-    fn parse_nsa_header(file : &mut FileHelper, offset : u32) -> ArchiveIndex {
-        let mut entries : Vec<ArchiveEntry> = Vec::new();
-        let num_of_entries = file.read_u16_be();
-        let file_offset = (file.read_u32_be() + offset) as usize; // Entries start at this address in the file
-
-        println!("Number of entries: {num_of_entries}; File Offset {file_offset}");
-
-        for _ in 0..num_of_entries {
-            let name = file.read_shiftjis();
-
-            let compression = match file.read_u8() {
-                0 => {
-                    let lowercase_name = name.to_lowercase();
-                    if lowercase_name.ends_with(".nbz") {
-                        Compression::Bzip2
-                    } else if lowercase_name.ends_with(".spb") {
-                        Compression::Spb
-                    } else {
-                        Compression::None
-                    }
-                },
-                1 => Compression::Spb,
-                2 => Compression::Lzss,
-                4 => Compression::Bzip2,
-                _ => panic!("File is using an unknown Compression type.")
-            };
+            entries.push(ArchiveEntry { name, offset : entry_offset, size, decompressed_size, compression });
+        }
 
-            let offset = file.read_u32_be() as usize + file_offset;
-            let size = file.read_u32_be() as usize;
-            let mut decompressed_size : Option<usize> = Some(file.read_u32_be() as usize);
+        if !bytes.is_empty() {
+            return Err(invalid("saved index has data after its last entry"));
+        }
 
-            // ONScripter notes decompression of these just for the sake of filling this value as a
-            // large potential slowdown depending on the archive. We'll follow their lead in ignoring
-            // it until the entry is actually opened.
-            if matches!(compression, Compression::Bzip2) || matches!(compression, Compression::Spb) {
-                decompressed_size = None;
-            }
+        Ok(ArchiveIndex::new(entries, offset))
+    }
+}
 
-            entries.push(ArchiveEntry {
-                name, offset, size, decompressed_size, compression
-            });
+// Bytes following the end of the last entry's data, some archives carry padding or appended data here.
+#[derive(Clone, Copy, Debug)]
+pub struct TrailingRegion {
+    pub offset : usize,
+    pub size : usize
+}
+
+#[derive(Debug)]
+pub enum ValidationIssue {
+    EntryOutOfBounds { name : String, offset : usize, size : usize },
+    // The entry's data starts before the data of the entry listed before it.
+    OutOfOrder { name : String, offset : usize, previous : String, previous_offset : usize },
+    // The entry's data starts inside the data of the entry listed before it, sharing size bytes with it.
+    Overlap { name : String, previous : String, size : usize },
+    // Bytes no entry covers, between the end of an entry's data and the start of the next entry's. Padding up to the
+    // archive's alignment isn't reported.
+    Gap { offset : usize, size : usize },
+    // Not an error in itself, the engine never reads it, but a rebuild will lose it unless it's preserved.
+    TrailingData(TrailingRegion)
+}
+
+// Where an archive and the directory it should have been built from disagree, see Archive::verify_against_dir.
+#[derive(Debug)]
+pub enum Discrepancy {
+    Missing { name : String }, // The entry has no file in the directory.
+    Extra { path : String }, // The file, given by its path within the directory, isn't in the archive.
+    Differs { name : String }, // The entry extracts to something other than its file holds.
+    Unreadable { name : String, error : ArchiveError } // The entry couldn't be extracted to compare.
+}
+
+pub struct Archive {
+    file : FileHelper,
+    pub index : ArchiveIndex,
+    pub archive_type : ArchiveType,
+}
+
+pub fn extract_bz2(file: File, key_table : &KeyTable) -> Vec<u8> {
+    let mut file = file;
+    let size = file.seek(SeekFrom::End(0)).unwrap();
+    file.seek(SeekFrom::Start(0)).unwrap();
+    let mut file_helper = FileHelper {file, key_table : *key_table, position : 0};
+    let buffer = file_helper.read_slice(0, size as usize);
+
+    use bzip2::read::BzDecoder;
+    let input = buffer;
+
+    // First 4 bytes are the original size, the decoder doesn't need this, so we can skip them.
+    let mut reader = BzDecoder::new(&input[4..]);
+    let mut buffer = Vec::new();
+    std::io::copy(&mut reader, &mut buffer).unwrap();
+
+    buffer
+}
+
+// Produces the same layout as a loose .nbz file, which is also how Bzip2 entries are stored in archives:
+// the original size as a big endian u32, followed by the Bzip2 stream. The stream's bytes aren't guaranteed to
+// be the same across libbzip2 versions, so archives with Bzip2 entries are only reproducible on the same one.
+pub fn compress_bz2(data : &[u8]) -> Vec<u8> {
+    compress_bz2_with_block_size(data, 9)
+}
+
+// As compress_bz2, with blocks of block_size * 100k, clamped to 1 to 9. Bzip2 has no other state, so with the
+// bzip2 crate's version held by Cargo.lock and the same block size the output is the same on every run.
+pub fn compress_bz2_with_block_size(data : &[u8], block_size : u32) -> Vec<u8> {
+    use bzip2::write::BzEncoder;
+
+    let mut output = (data.len() as u32).to_be_bytes().to_vec();
+    let mut encoder = BzEncoder::new(&mut output, bzip2::Compression::new(block_size.clamp(1, 9)));
+    encoder.write_all(data).unwrap();
+    encoder.finish().unwrap();
+
+    output
+}
+
+// The entry is the file's path within the archive, which is what overrides are keyed by.
+fn file_encoding_to_use(fullpath : &Path, entry : &Path, options : &CreateOptions) -> CompressionPlan {
+    let mut magic = Vec::with_capacity(MAGIC_SIZE);
+    let _ = File::open(fullpath).and_then(|file| file.take(MAGIC_SIZE as u64).read_to_end(&mut magic));
+    encoding_to_use(entry, &magic, options)
+}
+
+const DEFAULT_COPY_BUFFER_SIZE : usize = 64 * 1024;
+
+// How much of the start of a file encoding_to_use is given, enough for a WAV's header to reach its fmt chunk.
+const MAGIC_SIZE : usize = 512;
+
+// Whether a WAV holds uncompressed samples, going by the format tag in its fmt chunk, which is what Bzip2 does well
+// on. ADPCM, MP3 and the like are already compressed and hardly shrink further. Float samples count as uncompressed,
+// and WAVE_FORMAT_EXTENSIBLE files are judged by the format their subformat GUID starts with. A header that ends
+// before the fmt chunk, or isn't a WAV at all, isn't PCM.
+fn wav_is_pcm(header : &[u8]) -> bool {
+    const PCM : u16 = 1;
+    const IEEE_FLOAT : u16 = 3;
+    const EXTENSIBLE : u16 = 0xFFFE;
+
+    let u16_at = |at : usize| header.get(at..at + 2).map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]));
+    let u32_at = |at : usize| header.get(at..at + 4).map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()) as usize);
+
+    if !header.starts_with(b"RIFF") || header.get(8..12) != Some(b"WAVE") {
+        return false;
+    }
+
+    // Chunks are padded to an even size.
+    let mut position = 12;
+    while let (Some(id), Some(size)) = (header.get(position..position + 4), u32_at(position + 4)) {
+        if id == b"fmt " {
+            let format = match u16_at(position + 8) {
+                Some(EXTENSIBLE) => u16_at(position + 8 + 24),
+                format => format
+            };
+            return matches!(format, Some(PCM | IEEE_FLOAT));
         }
 
-        let mut entries_map : HashMap<String, usize> = HashMap::new();
-        for (i, entry) in entries.iter().enumerate() {
-            entries_map.insert(entry.name.clone(), i);
+        position = position.saturating_add(8).saturating_add(size).saturating_add(size & 1);
+    }
+
+    false
+}
+
+// Picks the compression for an entry from its name and the first MAGIC_SIZE bytes of its data.
+fn encoding_to_use(path : &Path, magic : &[u8], options : &CreateOptions) -> CompressionPlan {
+    let plan = |compression, reason| CompressionPlan { compression, reason };
+
+    if let Some(&compression) = options.overrides.get(path) {
+        return plan(compression, CompressionReason::Override);
+    }
+
+    // Data that's already compressed barely shrinks and can even grow, so it's stored as-is whatever's enabled, even
+    // when its extension says otherwise, as with Ogg audio named .wav. Bzip2 is looked for both bare and after the
+    // size .nbz files start with.
+    const COMPRESSED_MAGICS : [&[u8]; 4] = [b"\x89PNG", b"OggS", b"\xFF\xD8\xFF", b"BZh"];
+    if COMPRESSED_MAGICS.iter().any(|compressed| magic.starts_with(compressed)) || magic.get(4..7) == Some(b"BZh") {
+        return plan(Compression::None, CompressionReason::MatchedMagic);
+    }
+
+    // SPB needs real BMP data to work from, so it's only ever chosen on the contents. Bzip2 and LZSS
+    // don't care what they're compressing, so we can trust the extension if the contents don't match.
+    if magic.starts_with(b"BM") {
+        if options.spb {
+            return plan(Compression::Spb, CompressionReason::MatchedMagic);
+        } else if options.lzss {
+            return plan(Compression::Lzss, CompressionReason::MatchedMagic);
         }
+    } else if magic.starts_with(b"RIFF") && options.bzip2 {
+        let compression = if wav_is_pcm(magic) { Compression::Bzip2 } else { Compression::None };
+        return plan(compression, CompressionReason::MatchedMagic);
+    }
 
-        ArchiveIndex{ entries, entries_map, offset : file_offset }
+    let extension = path.extension().and_then(|extension| extension.to_str()).unwrap_or("").to_lowercase();
+    if extension == "wav" && options.bzip2 {
+        return plan(Compression::Bzip2, CompressionReason::Extension);
+    } else if extension == "bmp" && options.lzss {
+        return plan(Compression::Lzss, CompressionReason::Extension);
     }
-    
-    fn parse_ns2_header(file : &mut FileHelper, offset : u32) -> ArchiveIndex {
-        let mut entries : Vec<ArchiveEntry> = Vec::new();
-        let offset_of_file_data = (file.read_u32_le() + offset) as usize; // Entries start at this address in the file
-        let mut file_offset = offset_of_file_data;
 
-        while file.position < (offset_of_file_data - 1) {
-            let name = file.read_quoted_shiftjis();
-            let size = file.read_u32_le() as usize;
-            //let decompressed_size = 0;
-            
-            let lowercase_name = name.to_lowercase();
-            let compression =  if lowercase_name.ends_with(".nbz") {
-                Compression::Bzip2
-            } else if lowercase_name.ends_with(".spb") {
-                Compression::Spb
-            } else {
-                Compression::None
+    if options.zstd {
+        return plan(Compression::Zstd, CompressionReason::Default);
+    }
+    plan(Compression::None, CompressionReason::Default)
+}
+
+// Returns the compression actually used alongside the data, as a BMP the SPB encoder can't read
+// gets stored as-is instead, but with options.verify_spb set, one it encodes wrongly is an error.
+pub(crate) fn compress_entry(data : &[u8], compression : Compression, options : &CreateOptions) -> Result<(Compression, Vec<u8>), ArchiveError> {
+    let compressed = match compression {
+        Compression::Spb => {
+            let Ok(image) = Image::from_bmp_bytes(data) else {
+                return Ok((Compression::None, data.to_vec()));
             };
-            
-            println!("{name}: {size}: {file_offset}");
-            
-            entries.push(ArchiveEntry {
-                name, offset: file_offset, size, decompressed_size: None, compression
-            });
+            let original = options.verify_spb.then(|| Image { pixel_buffer : image.pixel_buffer.clone(), ..image });
 
-            file_offset += size
-        }
-        
-        let unknown_value = file.read_u8();
-        println!("Header end byte: {unknown_value}");
-        
-        let mut entries_map : HashMap<String, usize> = HashMap::new();
-        for (i, entry) in entries.iter().enumerate() {
-            entries_map.insert(entry.name.clone(), i);
-        }
+            let Ok(encoded) = encode_spb(image) else {
+                return Ok((Compression::None, data.to_vec()));
+            };
+            if let Some(original) = original {
+                verify_spb(&original, &encoded).map_err(ArchiveError::Spb)?;
+            }
+
+            encoded
+        },
+        Compression::Lzss => {
+            let writer = lzss::VecWriter::with_capacity(data.len());
+            NsaLzss::compress_stack(lzss::SliceReader::new(data), writer).unwrap()
+        },
+        Compression::Bzip2 => match options.bzip2_block_size {
+            0 => compress_bz2(data),
+            block_size => compress_bz2_with_block_size(data, block_size)
+        },
+        Compression::Zstd => compress_zstd(data)?,
+        Compression::None => data.to_vec(),
+        Compression::Unknown(compression) => return Err(ArchiveError::UnknownCompression { compression })
+    };
+
+    Ok((compression, compressed))
+}
+
+// A single zstd frame at zstd's default level, which records the size it decompresses to.
+#[cfg(feature = "zstd")]
+pub fn compress_zstd(data : &[u8]) -> Result<Vec<u8>, ArchiveError> {
+    Ok(zstd::bulk::compress(data, 0)?)
+}
+
+#[cfg(not(feature = "zstd"))]
+pub fn compress_zstd(_data : &[u8]) -> Result<Vec<u8>, ArchiveError> {
+    Err(ArchiveError::ZstdDisabled)
+}
+
+#[cfg(feature = "zstd")]
+pub fn decompress_zstd(data : &[u8]) -> Result<Vec<u8>, ArchiveError> {
+    Ok(zstd::stream::decode_all(data)?)
+}
+
+#[cfg(not(feature = "zstd"))]
+pub fn decompress_zstd(_data : &[u8]) -> Result<Vec<u8>, ArchiveError> {
+    Err(ArchiveError::ZstdDisabled)
+}
 
-        ArchiveIndex{ entries, entries_map, offset : 0 }
+// Entries stored without compression are still decompressed by readers if they're named .nbz or .spb.
+pub(crate) fn compression_from_name(name : &str) -> Compression {
+    let lowercase_name = name.to_lowercase();
+    if lowercase_name.ends_with(".nbz") {
+        Compression::Bzip2
+    } else if lowercase_name.ends_with(".spb") {
+        Compression::Spb
+    } else {
+        Compression::None
     }
+}
 
-    fn parse_header(file : &mut FileHelper, archive_type : &ArchiveType, offset : u32) -> ArchiveIndex {
-        match archive_type {
-            ArchiveType::SAR => Self::parse_sar_header(file, offset),
-            ArchiveType::NSA => Self::parse_nsa_header(file, offset),
-            ArchiveType::NS2 => Self::parse_ns2_header(file, offset)
+// Whether readers pass an entry's stored bytes through the key table, which they do for everything but SPB and
+// Bzip2. Entries stored as-is but named .spb or .nbz are read as those, so they aren't either.
+fn is_keyed(compression : Compression, name : &str) -> bool {
+    let compression = match compression {
+        Compression::None => compression_from_name(name),
+        compression => compression
+    };
+
+    matches!(compression, Compression::None | Compression::Lzss)
+}
+
+fn try_nsa_compression(byte : u8, name : &str) -> Option<Compression> {
+    match byte {
+        0 => Some(compression_from_name(name)),
+        1 => Some(Compression::Spb),
+        2 => Some(Compression::Lzss),
+        4 => Some(Compression::Bzip2),
+        ZSTD_BYTE => Some(Compression::Zstd),
+        _ => None
+    }
+}
+
+fn nsa_compression(byte : u8, name : &str) -> Compression {
+    try_nsa_compression(byte, name).unwrap_or(Compression::Unknown(byte))
+}
+
+// An entry ready to be written out: the compression it's stored with, its stored data, and its decompressed size.
+type StoredEntry = (Compression, Vec<u8>, usize);
+
+// How an entry ended up being stored by one of the create functions, to see how well compression did.
+#[derive(Clone, Debug)]
+pub struct EntryReport {
+    pub name : String,
+    pub original_size : usize,
+    pub stored_size : usize,
+    pub compression : Compression
+}
+
+impl EntryReport {
+    // Stored size over original size, above 1 means compressing the entry made it bigger.
+    pub fn ratio(&self) -> f64 {
+        if self.original_size == 0 {
+            return 1.0;
         }
+
+        self.stored_size as f64 / self.original_size as f64
     }
+}
 
-    pub fn open_file(file : std::fs::File, archive_type : ArchiveType, offset : u32, key_table : [u8; 256]) -> Archive {
-        let mut file_helper = FileHelper {file, key_table, position : 0};
-        let index = Self::parse_header(&mut file_helper, &archive_type, offset);
+fn entry_name(entry : &Path) -> Result<String, ArchiveError> {
+    entry.to_str().map(str::to_string).ok_or_else(|| ArchiveError::InvalidEntryName { path : entry.to_path_buf() })
+}
 
-        Archive {
-            file : file_helper,
-            index,
-            archive_type,
+// The name an entry is stored under, the one options.names gives it if there is one, otherwise its path.
+fn stored_name(entry : &Path, options : &CreateOptions) -> Result<String, ArchiveError> {
+    match options.names.get(entry) {
+        Some(name) => Ok(name.clone()),
+        None => entry_name(entry)
+    }
+}
+
+fn entry_report(root_dir : &Path, entry : &Path, name : &str, compression : Compression, stored_size : usize) -> Result<EntryReport, ArchiveError> {
+    let original_size = std::fs::metadata(root_dir.join(entry))?.len() as usize;
+    Ok(EntryReport { name : name.to_string(), original_size, stored_size, compression })
+}
+
+// ONScripter allocates an entry's decompressed size to decode it into, for SPB that's the 24bpp BMP the decoder
+// writes, not the source BMP, which could be paletted or have a larger header.
+fn nsa_decompressed_size(compression : Compression, compressed : &[u8], original_size : usize, options : &CreateOptions) -> Result<usize, ArchiveError> {
+    if options.decompressed_size == DecompressedSize::Zero {
+        return Ok(0);
+    }
+
+    match compression {
+        Compression::Spb => decoded_spb_size(compressed).map_err(ArchiveError::Spb),
+        _ => Ok(original_size)
+    }
+}
+
+// Writes the file at path anew through write, which is given a temporary file next to it, and only once write has
+// succeeded moves it over path. Until then path is left as it was, so a failure or an interruption part way through
+// can't leave a half written archive behind, and the temporary file is removed if write fails.
+pub fn write_atomically<F>(path : &Path, write : F) -> Result<(), ArchiveError>
+    where F : FnOnce(File) -> Result<(), ArchiveError>
+{
+    let directory = path.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let temporary = tempfile::NamedTempFile::new_in(directory)?;
+
+    write(temporary.as_file().try_clone()?)?;
+    temporary.as_file().sync_all()?;
+    temporary.persist(path).map_err(|error| ArchiveError::Io(error.error))?;
+
+    Ok(())
+}
+
+// SPB data with the zeroes replace_with_spb pads a smaller image out with cut off. Decoding stops at the end of the
+// image, so it reads the same from any length that holds all of it, but the image can end in zero bytes of its own,
+// so the shortest length that still decodes is searched for between the data without any trailing zeroes and with
+// all of them.
+fn trim_spb_slack(mut data : Vec<u8>) -> Vec<u8> {
+    let trimmed = data.len() - data.iter().rev().take_while(|&&byte| byte == 0).count();
+    if trimmed == data.len() || decode_spb(data.clone()).is_err() {
+        return data;
+    }
+
+    let (mut shortest, mut longest) = (trimmed, data.len());
+    while shortest < longest {
+        let middle = (shortest + longest) / 2;
+        if decode_spb(data[..middle].to_vec()).is_ok() {
+            longest = middle;
+        } else {
+            shortest = middle + 1;
         }
     }
 
-    pub fn extract(&mut self, info : ArchiveEntryInfo) -> Vec<u8> {
-        let mut buffer : Vec<u8>;
+    data.truncate(longest);
+    data
+}
+
+// Reads and compresses a file for an NSA archive, returning what write_archive needs of it.
+fn compress_file_for_nsa(root_dir : &Path, entry : &Path, options : &CreateOptions) -> Result<StoredEntry, ArchiveError> {
+    let fullpath = root_dir.join(entry);
+    let plan = file_encoding_to_use(&fullpath, entry, options);
+    let data = std::fs::read(&fullpath)?;
+    let (compression, compressed) = compress_entry(&data, plan.compression, options)?;
+    let decompressed_size = nsa_decompressed_size(compression, &compressed, data.len(), options)?;
 
-        if matches!(info.compression, Compression::None) {
-            buffer = self.file.read_slice_through_keytable(info.offset, info.size);
-        } else if matches!(info.compression, Compression::Spb) {
-            buffer = decode_spb(self.file.read_slice(info.offset, info.size)).unwrap();
-        } else if matches!(info.compression, Compression::Lzss) {
-            buffer = self.file.read_slice_through_keytable(info.offset, info.size);
+    Ok((compression, compressed, decompressed_size))
+}
 
-            let input = buffer;
+// Compresses each of the entries on its own thread, taking them in turn from a shared counter so a thread that
+// lands on a large entry doesn't hold up the rest. The results are in the same order as the entries.
+fn compress_files_in_parallel(root_dir : &Path, entries : &[PathBuf], options : &CreateOptions, threads : usize) -> Vec<Result<StoredEntry, ArchiveError>> {
+    use std::sync::{atomic::{AtomicUsize, Ordering}, Mutex};
 
-            type Lzss = lzss::Lzss<8, 4, 0, { 1 << 8 }, { 2 << 8 }>;
-            let writer = lzss::VecWriter::with_capacity(input.len());
-            
-            buffer = Lzss::decompress_stack(
-                lzss::SliceReader::new(input.as_slice()),
+    let next = AtomicUsize::new(0);
+    let results : Vec<Mutex<Option<Result<StoredEntry, ArchiveError>>>> = entries.iter().map(|_| Mutex::new(None)).collect();
+
+    std::thread::scope(|scope| {
+        for _ in 0..threads.min(entries.len()) {
+            scope.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                if entries.len() <= i {
+                    break;
+                }
+
+                let result = compress_file_for_nsa(root_dir, &entries[i], options);
+                *results[i].lock().unwrap() = Some(result);
+            });
+        }
+    });
+
+    results.into_iter().map(|result| result.into_inner().unwrap().unwrap()).collect()
+}
+
+// The inverse of compress_entry, data is the entry as it's stored in the archive, after the key table has been
+// applied for the compressions that use it, such as extract_raw returns. decompressed_size trims LZSS output to the
+// size the header gives, see ArchiveEntry::decompressed_size.
+pub fn decompress_entry(data : Vec<u8>, compression : Compression, decompressed_size : Option<usize>) -> Result<Vec<u8>, ArchiveError> {
+    let buffer = match compression {
+        Compression::None => data,
+        Compression::Unknown(compression) => return Err(ArchiveError::UnknownCompression { compression }),
+        Compression::Spb => decode_spb(data).map_err(ArchiveError::Spb)?,
+        Compression::Lzss => {
+            let writer = lzss::VecWriter::with_capacity(decompressed_size.unwrap_or(data.len()));
+
+            let mut buffer = NsaLzss::decompress_stack(
+                lzss::SliceReader::new(data.as_slice()),
                 writer,
             ).unwrap();
-        } else if matches!(info.compression, Compression::Bzip2) {
-            buffer = self.file.read_slice(info.offset, info.size);
 
-            use bzip2_rs::DecoderReader;
-            let input = buffer;
+            if let Some(decompressed_size) = decompressed_size {
+                buffer.truncate(decompressed_size);
+            }
+            buffer
+        },
+        Compression::Bzip2 => {
+            use bzip2::read::BzDecoder;
 
             // First 4 bytes are the original size, the decoder doesn't need this, so we can skip them.
-            let mut reader = DecoderReader::new(&input[4..]);
-            buffer = Vec::new();
-            std::io::copy(&mut reader, &mut buffer).unwrap();
+            let Some(stream) = data.get(4..) else {
+                return Err(ArchiveError::Io(ErrorKind::UnexpectedEof.into()));
+            };
+            let mut reader = BzDecoder::new(stream);
+            let mut buffer = Vec::new();
+            std::io::copy(&mut reader, &mut buffer)?;
+            buffer
+        },
+        Compression::Zstd => decompress_zstd(&data)?
+    };
+
+    Ok(buffer)
+}
+
+fn shiftjis_length(value : &str) -> Result<usize, ArchiveError> {
+    Ok(encode_shiftjis(value)?.len())
+}
+
+fn header_base_size(archive_type : ArchiveType) -> usize {
+    match archive_type {
+        ArchiveType::SAR | ArchiveType::NSA => 2 + 4, // Entry count, data offset.
+        ArchiveType::NS2 => 4 + 1                     // Data offset, header end byte.
+    }
+}
+
+fn header_entry_size(name : &str, archive_type : ArchiveType) -> Result<usize, ArchiveError> {
+    let name_length = shiftjis_length(name)?;
+    Ok(match archive_type {
+        ArchiveType::SAR => name_length + 1 + 4 + 4,
+        ArchiveType::NSA => name_length + 1 + 1 + 4 + 4 + 4,
+        ArchiveType::NS2 => name_length + 2 + 4
+    })
+}
+
+// Only NSA archives get compressed entries, so for the other formats this is just a walk over the file sizes.
+// Compressed entries are run through their compressor to find out their stored size, nothing is written.
+pub fn plan_archive(root_dir : &Path, entries : &[PathBuf], archive_type : &ArchiveType, options : &CreateOptions) -> Result<ArchivePlan, ArchiveError> {
+    if !matches!(archive_type, ArchiveType::NS2) && (u16::MAX as usize) < entries.len() {
+        return Err(ArchiveError::TooManyEntries { count : entries.len() });
+    }
+
+    let mut planned_entries = Vec::new();
+    let mut header_size = header_base_size(*archive_type);
+
+    for entry in entries {
+        let fullpath = root_dir.join(entry);
+        let name = stored_name(entry, options)?;
+        let original_size = std::fs::metadata(&fullpath)?.len() as usize;
+
+        header_size += header_entry_size(&name, *archive_type)?;
+
+        let plan = match archive_type {
+            ArchiveType::NSA => file_encoding_to_use(&fullpath, entry, options),
+            _ => CompressionPlan { compression : Compression::None, reason : CompressionReason::Default }
+        };
+
+        let (compression, size) = if matches!(plan.compression, Compression::None) {
+            (Compression::None, original_size)
         } else {
-            buffer = Vec::new();
+            let (compression, compressed) = compress_entry(&std::fs::read(&fullpath)?, plan.compression, options)?;
+            (compression, compressed.len())
+        };
+
+        planned_entries.push(PlannedEntry { name, compression, offset : 0, size, original_size });
+    }
+
+    let alignment = if matches!(archive_type, ArchiveType::NSA) { options.alignment } else { 0 };
+    let mut offset = header_size;
+    for entry in &mut planned_entries {
+        offset += padding_for(offset, alignment);
+        entry.offset = offset;
+        offset += entry.size;
+    }
+
+    Ok(ArchivePlan { entries : planned_entries, header_size, total_size : offset })
+}
+
+fn is_plausible_name(name : &[u8]) -> bool {
+    let (_res, _enc, errors) = encoding_rs::SHIFT_JIS.decode(name);
+    !name.is_empty() && !errors && !name.iter().any(|byte| *byte < 0x20)
+}
+
+// An entry's name, as it's stored, and size.
+type Ns2HeaderEntry<'a> = (&'a [u8], u32);
+
+// Walks the entries of an NS2 header, header holding the archive from its start, and end being its data offset. The
+// name and size of each entry is returned, along with where the walk stopped, or where it found something that can't
+// be there as the error, after which there are no more entries.
+//
+// The header ends with an 'e', the last byte before the data and counted in its offset. It only marks the end and
+// entries are read up to it, so it isn't read at all. Some archives leave it out and start their data right after the
+// last size, and reading it there would read into the data, or past the end of the file. Tools that pretty-print the
+// header put whitespace or line breaks between entries, and between a name and its size. Bytes of a size can look
+// like whitespace too, so after a name as little of it is skipped as leaves the next entry starting where one can.
+fn walk_ns2_header(header : &[u8], end : usize) -> (Vec<Ns2HeaderEntry<'_>>, Result<usize, usize>) {
+    let header = &header[..end.min(header.len())];
+    let skip_whitespace = |position : usize| {
+        position + header.get(position..).unwrap_or(&[]).iter().take_while(|byte| byte.is_ascii_whitespace()).count()
+    };
+    let entry_can_start = |position : usize| {
+        let position = skip_whitespace(position);
+        // Past the end of what there is of the header, as when it's cut off, anything could follow.
+        end.saturating_sub(1).min(header.len()) <= position || header.get(position) == Some(&b'"')
+    };
+
+    let mut entries = Vec::new();
+    let mut position = skip_whitespace(4);
+    while position < end.saturating_sub(1) {
+        if header.get(position) != Some(&b'"') {
+            return (entries, Err(position));
+        }
+        let Some(name_length) = header[position + 1..].iter().position(|byte| *byte == b'"') else {
+            return (entries, Err(position));
+        };
+        let name = &header[position + 1..position + 1 + name_length];
+
+        let after_name = position + name_length + 2;
+        let size_position = (after_name..=skip_whitespace(after_name)).find(|&at| entry_can_start(at + 4)).unwrap_or(after_name);
+        let Some(size) = header.get(size_position..size_position + 4) else {
+            return (entries, Err(size_position));
+        };
+
+        entries.push((name, u32::from_le_bytes(size.try_into().unwrap())));
+        position = skip_whitespace(size_position + 4);
+    }
+
+    (entries, Ok(position))
+}
+
+// As header_is_consistent, for an NS2 header: a little endian data offset, then quoted names and sizes until the
+// 'e' that ends the header, or the data itself in archives that leave it out. The entries' sizes have to add up to
+// no more than the rest of the archive.
+fn ns2_header_is_consistent(data : &[u8], archive_size : usize) -> bool {
+    let Some(data_offset) = data.get(0..4).map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()) as usize) else {
+        return false;
+    };
+    if data_offset <= 4 || archive_size < data_offset {
+        return false;
+    }
+
+    let (entries, walked) = walk_ns2_header(data, data_offset);
+    let ends_header = |position : usize| position == data_offset || (position == data_offset - 1 && data.get(position) == Some(&b'e'));
+    let total_size = entries.iter().fold(0usize, |total, (_, size)| total.saturating_add(*size as usize));
+
+    matches!(walked, Ok(position) if ends_header(position))
+        && !entries.is_empty()
+        && entries.iter().all(|(name, _)| is_plausible_name(name))
+        && total_size <= archive_size - data_offset
+}
+
+// Walks a SAR or NSA header at the start of data without trusting any of it: every name has to be valid
+// Shift-JIS, every entry's data has to lie within the archive's size, counted from the start of data, and the
+// entries have to end exactly where the header says the data region starts. Random bytes essentially never pass,
+// so this is how archives at unknown offsets are found, and how SAR archives misnamed .nsa are told apart. data
+// only needs to hold the header.
+fn header_is_consistent(data : &[u8], archive_size : usize, archive_type : &ArchiveType) -> bool {
+    check_header(data, archive_size, archive_type) == HeaderCheck::Consistent
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum HeaderCheck {
+    Consistent,
+    Inconsistent,
+    Truncated // Everything in data was consistent, but it ends before the header does.
+}
+
+// As header_is_consistent, but telling a header data only holds the start of apart from one that's wrong, so headers
+// can be looked for a piece of a file at a time.
+fn check_header(data : &[u8], archive_size : usize, archive_type : &ArchiveType) -> HeaderCheck {
+    let read_u32_be = |at : usize| data.get(at..at + 4).map(|bytes| u32::from_be_bytes(bytes.try_into().unwrap()) as usize);
+
+    let (Some(count), Some(data_offset)) = (data.get(0..2).map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]])), read_u32_be(2)) else {
+        return HeaderCheck::Truncated;
+    };
+
+    if count == 0 || archive_size < data_offset {
+        return HeaderCheck::Inconsistent;
+    }
+
+    // Running out of data is only wrong if data was meant to hold the whole header.
+    let ran_out = if data.len() < data_offset { HeaderCheck::Truncated } else { HeaderCheck::Inconsistent };
+
+    let mut position = 6;
+    for _ in 0..count {
+        let Some(name_length) = data.get(position..data_offset.min(data.len())).and_then(|header| header.iter().position(|byte| *byte == 0)) else {
+            return ran_out;
+        };
+
+        if !is_plausible_name(&data[position..position + name_length]) {
+            return HeaderCheck::Inconsistent;
+        }
+        position += name_length + 1;
+
+        // Any compression byte is accepted, ones this crate doesn't know are read as Compression::Unknown.
+        if matches!(archive_type, ArchiveType::NSA) {
+            if data.get(position).is_none() {
+                return ran_out;
+            }
+            position += 1;
+        }
+
+        let (Some(offset), Some(size)) = (read_u32_be(position), read_u32_be(position + 4)) else {
+            return ran_out;
+        };
+        position += 8;
+
+        if matches!(archive_type, ArchiveType::NSA) {
+            position += 4; // Decompressed size.
+        }
+
+        if data_offset < position || archive_size < data_offset + offset + size {
+            return HeaderCheck::Inconsistent;
+        }
+    }
+
+    if position == data_offset { HeaderCheck::Consistent } else { HeaderCheck::Inconsistent }
+}
+
+// Writes an archive of the named entries, asking load for each entry's compression, stored data and decompressed size
+// in turn, so only one entry is in memory at a time. The header is written with placeholders first, which are filled
+// in as each entry is written. Compression is only recorded by NSA, the other formats store the data as it's given.
+// Entries are padded out to alignment as CreateOptions describes. Returns the compression and stored size of each entry.
+fn write_archive<F>(file : &mut FileHelper, archive_type : ArchiveType, names : &[String], alignment : usize, mut load : F) -> Result<Vec<(Compression, usize)>, ArchiveError>
+where F : FnMut(usize) -> Result<StoredEntry, ArchiveError> {
+    if !matches!(archive_type, ArchiveType::NS2) && (u16::MAX as usize) < names.len() {
+        return Err(ArchiveError::TooManyEntries { count : names.len() });
+    }
+
+    let mut record_locations = Vec::new();
+    let mut stored = Vec::with_capacity(names.len());
+
+    match archive_type {
+        ArchiveType::SAR | ArchiveType::NSA => {
+            file.write_u16_be(names.len() as u16)?;
+            file.write_u32_be(0)?;
+        }
+        ArchiveType::NS2 => file.write_u32_le(0)?
+    }
+
+    for name in names {
+        match archive_type {
+            ArchiveType::SAR => {
+                file.write_shiftjis(name)?;
+                record_locations.push(file.position);
+                file.write_buffer_through_keytable(&[0; 8])?; // Offset, size.
+            }
+            ArchiveType::NSA => {
+                file.write_shiftjis(name)?;
+                record_locations.push(file.position);
+                file.write_buffer_through_keytable(&[0; 13])?; // Compression, offset, size, decompressed size.
+            }
+            ArchiveType::NS2 => {
+                file.write_quoted_shiftjis(name)?;
+                record_locations.push(file.position);
+                file.write_u32_le(0)?; // Size.
+            }
+        }
+    }
+
+    // NS2's header finishes with an 'e' after the last entry.
+    if matches!(archive_type, ArchiveType::NS2) {
+        file.write_u8_be(b'e')?;
+    }
+
+    let end_of_header = file.position;
+    match archive_type {
+        ArchiveType::SAR | ArchiveType::NSA => {
+            file.seek(SeekFrom::Start(2))?;
+            file.write_u32_be(end_of_header as u32)?;
+        }
+        ArchiveType::NS2 => {
+            file.seek(SeekFrom::Start(0))?;
+            file.write_u32_le(end_of_header as u32)?;
+        }
+    }
+    file.seek(SeekFrom::Start(end_of_header as u64))?;
+
+    for (i, record_location) in record_locations.iter().enumerate() {
+        let (compression, data, decompressed_size) = load(i)?;
+        if !matches!(archive_type, ArchiveType::NS2) {
+            pad_to_alignment(file, alignment)?;
+        }
+        let entry_offset = file.position;
+
+        file.write_entry_data(&data, compression, &names[i])?;
+        let end_of_entry = file.position;
+        archive_u32(end_of_entry)?;
+        let decompressed_size = archive_u32(decompressed_size)?;
+
+        file.seek(SeekFrom::Start(*record_location as u64))?;
+        match archive_type {
+            ArchiveType::SAR => {
+                file.write_u32_be((entry_offset - end_of_header) as u32)?;
+                file.write_u32_be(data.len() as u32)?;
+            }
+            ArchiveType::NSA => {
+                file.write_u8_be(u8::from(compression))?;
+                file.write_u32_be((entry_offset - end_of_header) as u32)?;
+                file.write_u32_be(data.len() as u32)?;
+                file.write_u32_be(decompressed_size)?;
+            }
+            ArchiveType::NS2 => file.write_u32_le(data.len() as u32)?
         }
+        file.seek(SeekFrom::Start(end_of_entry as u64))?;
+        stored.push((compression, data.len()));
+    }
+
+    Ok(stored)
+}
+
+fn padding_for(position : usize, alignment : usize) -> usize {
+    if alignment <= 1 {
+        return 0;
+    }
+
+    (alignment - position % alignment) % alignment
+}
+
+fn pad_to_alignment(file : &mut FileHelper, alignment : usize) -> std::io::Result<()> {
+    let padding = padding_for(file.position, alignment);
+    file.write_buffer(&vec![0; padding])
+}
+
+// The LZSS ONScripter's NsaReader decodes, Okumura's EI/EJ variant with:
+//   - EI = 8: an 8 bit offset into a 256 byte ring buffer (N = 1 << EI).
+//   - EJ = 4: a 4 bit match length, with matches being length + 2 bytes, as the crate derives P = 1 from EI and EJ.
+//   - C = 0: the ring buffer starts zeroed rather than filled with spaces as classic LZSS does, and ONScripter
+//     starts writing at N - F, where F = (1 << EJ) + P, which is also where the crate starts.
+// ONScripter stops decoding at the entry's decompressed size rather than the end of the data, so we trim to it too.
+type NsaLzss = lzss::Lzss<8, 4, 0, { 1 << 8 }, { 2 << 8 }>;
+
+// Lets LZSS decompress onto the end of a buffer the caller owns, see Archive::extract_into.
+struct AppendWriter<'a>(&'a mut Vec<u8>);
+
+impl lzss::Write for AppendWriter<'_> {
+    type Output = ();
+    type Error = std::convert::Infallible;
 
-        buffer
+    fn write(&mut self, data : u8) -> Result<(), Self::Error> {
+        self.0.push(data);
+        Ok(())
     }
-}
\ No newline at end of file
+
+    fn finish(self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+impl Archive {
+    /*
+    fn write_sar_header(&self, archive : &mut Archive) {
+        let mut offsets : Vec<(usize, usize)> = Vec::new();
+        archive.file.seek(SeekFrom::Start(archive.index.offset as u64));
+
+        // TODO: Should check this cast
+        archive.file.write_u16_be(archive.index.entries.len() as u16);
+
+        // Skip writing out the internal offset for now.
+        archive.file.seek(SeekFrom::Current(4));
+
+        for entry in &archive.index.entries {
+            archive.file.write_shiftjis(&entry.name);
+            
+            // Skip writing out the internal offset for now.
+            offsets.push((archive.file.position, entry.size));
+            archive.file.seek(SeekFrom::Current(4));
+
+            // TODO: Should check this cast
+            archive.file.write_u32_be(entry.size as u32);
+        }
+
+        let file_offset = archive.file.position;
+        archive.file.seek(SeekFrom::Start(archive.index.offset as u64 + 2));
+        archive.file.write_u32_be(file_offset);
+
+        for (offset_position, entry_size) in offsets {
+            archive.file.seek(SeekFrom::Start(archive.index.offset as u64 + 2));
+            archive.file.write_u32_be(file_offset);
+        }
+
+        //archive.file.write_u32_be(archive.index.offset )
+    } 
+    */
+
+
+    // SAR has no compression field, entries named .nbz or .spb are read as Bzip2 and SPB, as ONScripter does and as
+    // NSA and NS2 do for entries they store as-is. Only the sizes of the rest are known without reading them.
+    fn parse_sar_header(file : &mut FileHelper, offset : u32, options : &ReadOptions) -> Result<ArchiveIndex, ArchiveError> {
+        let mut entries : Vec<ArchiveEntry> = Vec::new();
+        let num_of_entries = file.read_u16_be()?;
+        let data_offset = file.read_u32_be()?;
+        let file_offset = file_position(offset, data_offset)?; // Entries start at this address in the file
+        check_entry_count(file, offset, num_of_entries, 1 + 8)?;
+
+        for i in 0..num_of_entries {
+            let (name, malformed) = file.read_shiftjis(options)?;
+            if malformed {
+                report_malformed_name(i as usize, &name);
+            }
+            let compression = compression_from_name(&name);
+            let entry_offset = file.read_u32_be()?;
+            let size = file.read_u32_be()?;
+            archive_position(&[data_offset, entry_offset, size])?;
+
+            let offset = file_position(offset, data_offset + entry_offset)?;
+            let size = size as usize;
+            let decompressed_size = matches!(compression, Compression::None).then_some(size);
+
+            entries.push(ArchiveEntry {
+                name, offset, size, decompressed_size, compression
+            });
+        }
+
+        Ok(ArchiveIndex::new(entries, file_offset))
+    }
+    
+    // Only options.copy_buffer_size and options.names apply, SAR can't store anything compressed.
+    pub fn create_sar_archive(file: File, root_dir: &Path, entries : Vec<PathBuf>, key_table : &KeyTable, options : &CreateOptions) -> Result<Vec<EntryReport>, ArchiveError> {
+        let mut file_helper = FileHelper {file, key_table : *key_table, position : 0};
+
+        if (u16::MAX as usize) < entries.len() {
+            return Err(ArchiveError::TooManyEntries { count : entries.len() });
+        }
+
+        let mut entry_offset_locations = Vec::new();
+        let mut reports = Vec::with_capacity(entries.len());
+
+        log::debug!("Entries: {}", entries.len());
+
+        file_helper.write_u16_be(entries.len() as u16)?;
+        file_helper.write_u32_be(0)?;
+
+        for entry in &entries {
+            let fullpath = root_dir.join(entry);
+            let mut entry_file = std::fs::File::open(&fullpath)?;
+            let entry_size = entry_file.seek(SeekFrom::End(0))?;
+            let entry_inner_path = stored_name(entry, options)?;
+
+            file_helper.write_shiftjis(&entry_inner_path)?;
+
+            // Note down where this offset value is for later.
+            entry_offset_locations.push(file_helper.position);            
+            file_helper.write_u32_be(0)?;
+            file_helper.write_u32_be(entry_size as u32)?;
+
+            log::debug!("Entry {}, {}", &entry_inner_path, entry_size);
+            reports.push(EntryReport { name : entry_inner_path, original_size : entry_size as usize, stored_size : entry_size as usize, compression : Compression::None });
+        }
+
+        let end_of_header = file_helper.position;
+        log::debug!("End of Header: {end_of_header}");
+
+        file_helper.seek(SeekFrom::Start(2))?;
+        file_helper.write_u32_be(end_of_header as u32)?;
+        file_helper.seek(SeekFrom::Start(end_of_header as u64))?;
+        
+        // We only want to init this once for all files, so the buffer lives outside of the read_file_into_file call.
+        let buffer_size = if options.copy_buffer_size == 0 { DEFAULT_COPY_BUFFER_SIZE } else { options.copy_buffer_size };
+        let mut buffer = vec![0; buffer_size];
+        
+        for (entry_file_name, entry_offset_location) in entries.iter().zip(&entry_offset_locations) {
+            let fullpath = root_dir.join(entry_file_name);
+            let mut entry_file = std::fs::File::open(&fullpath)?;
+            let entry_offset = file_helper.position;
+
+            file_helper.seek(SeekFrom::Start(*entry_offset_location as u64))?;
+            file_helper.write_u32_be((entry_offset - end_of_header) as u32)?;
+
+            file_helper.seek(SeekFrom::Start(entry_offset as u64))?;
+            file_helper.write_file(&mut entry_file, &mut buffer)?;
+            archive_u32(file_helper.position)?;
+        }
+        
+        Ok(reports)
+    }
+
+
+    fn parse_nsa_header(file : &mut FileHelper, offset : u32, options : &ReadOptions) -> Result<ArchiveIndex, ArchiveError> {
+        let mut entries : Vec<ArchiveEntry> = Vec::new();
+        let num_of_entries = file.read_u16_be()?;
+        let data_offset = file.read_u32_be()?;
+        let file_offset = file_position(offset, data_offset)?; // Entries start at this address in the file
+
+        log::debug!("Number of entries: {num_of_entries}; File Offset {file_offset}");
+        check_entry_count(file, offset, num_of_entries, 1 + 1 + 12)?;
+
+        for i in 0..num_of_entries {
+            let (name, malformed) = file.read_shiftjis(options)?;
+            if malformed {
+                report_malformed_name(i as usize, &name);
+            }
+
+            let compression = nsa_compression(file.read_u8()?, &name);
+
+            let entry_offset = file.read_u32_be()?;
+            let size = file.read_u32_be()?;
+            archive_position(&[data_offset, entry_offset, size])?;
+
+            let offset = file_position(offset, data_offset + entry_offset)?;
+            let size = size as usize;
+            let mut decompressed_size : Option<usize> = Some(file.read_u32_be()? as usize);
+
+            // ONScripter notes decompression of these just for the sake of filling this value as a
+            // large potential slowdown depending on the archive. We'll follow their lead in ignoring
+            // it until the entry is actually opened.
+            if matches!(compression, Compression::Bzip2) || matches!(compression, Compression::Spb) {
+                decompressed_size = None;
+            }
+
+            // Some tools write 0 here rather than the real size, so it can't be trusted to size the output.
+            if decompressed_size == Some(0) {
+                decompressed_size = None;
+            }
+
+            entries.push(ArchiveEntry {
+                name, offset, size, decompressed_size, compression
+            });
+        }
+
+        // Most tools store entry offsets from the start of the data, but some store them from the start of the
+        // archive, which shifts every extracted entry by the size of the header. We only switch to the latter if
+        // it's more plausible than the usual convention.
+        let header_size = file_offset - offset as usize;
+        let usual = Self::entry_offsets_plausibility(file, &entries, 0);
+        let from_start = Self::entry_offsets_plausibility(file, &entries, header_size);
+        if matches!((usual, from_start), (Some(usual), Some(from_start)) if from_start > usual) {
+            log::info!("Entry offsets are from the start of the archive rather than the start of the data");
+
+            for entry in &mut entries {
+                entry.offset -= header_size;
+            }
+        }
+
+        Ok(ArchiveIndex::new(entries, file_offset))
+    }
+
+    // With every entry moved back by shift bytes, how many of the first few fit in the file and start with the data
+    // we'd expect of them, or None if the file's size can't be read. An entry running past the end doesn't rule the
+    // shift out, since it's what a truncated archive looks like either way.
+    fn entry_offsets_plausibility(file : &mut FileHelper, entries : &[ArchiveEntry], shift : usize) -> Option<usize> {
+        let file_size = file.file.metadata().ok()?.len() as usize;
+
+        let plausible = entries.iter().take(16)
+            .filter(|entry| entry.offset + entry.size <= file_size + shift)
+            .filter(|entry| Self::entry_start_is_plausible(file, entry.offset - shift, entry.size, entry.compression))
+            .count();
+
+        Some(plausible)
+    }
+
+    // Whether the first few bytes at offset look like the start of an entry with this compression. Uncompressed
+    // entries have to start with the magic of a format games commonly use, so text won't pass, and LZSS and unknown
+    // compressions have no header to check so they always pass.
+    fn entry_start_is_plausible(file : &mut FileHelper, offset : usize, size : usize, compression : Compression) -> bool {
+        let peek = size.min(8);
+        let data = match compression {
+            Compression::None | Compression::Lzss => file.try_read_slice_through_keytable(offset, peek),
+            Compression::Spb | Compression::Bzip2 | Compression::Zstd | Compression::Unknown(_) => file.try_read_slice(offset, peek)
+        };
+
+        let Ok(data) = data else {
+            return false;
+        };
+
+        match compression {
+            Compression::None => {
+                const MAGICS : [&[u8]; 8] = [b"BM", b"RIFF", b"OggS", b"ID3", b"MThd", b"\x89PNG", b"\xFF\xD8\xFF", b"GIF8"];
+                MAGICS.iter().any(|magic| data.starts_with(magic))
+            },
+            Compression::Spb => data.len() >= 4 && data[0..2] != [0; 2] && data[2..4] != [0; 2], // Width and height.
+            Compression::Bzip2 => data.get(4..7) == Some(b"BZh"),
+            Compression::Zstd => data.starts_with(&[0x28, 0xB5, 0x2F, 0xFD]), // A zstd frame's magic.
+            Compression::Lzss | Compression::Unknown(_) => true
+        }
+    }
+    
+    pub fn plan_compression(path : &Path, bzip2 : bool, spb : bool, lzss : bool) -> CompressionPlan {
+        file_encoding_to_use(path, path, &CreateOptions { bzip2, spb, lzss, ..Default::default() })
+    }
+
+    // As plan_compression, but taking overrides into account as well, entry being the file's path within root_dir.
+    pub fn plan_entry_compression(root_dir : &Path, entry : &Path, options : &CreateOptions) -> CompressionPlan {
+        file_encoding_to_use(&root_dir.join(entry), entry, options)
+    }
+
+    pub fn create_nsa_archive(file: File, root_dir: &Path, entries : Vec<PathBuf>, key_table : &KeyTable, options : &CreateOptions) -> Result<Vec<EntryReport>, ArchiveError> {
+        Self::create_nsa_archive_cached(file, root_dir, entries, key_table, options, &mut CompressionCache::default())
+    }
+
+    // Entries the cache already has compressed bodies for are reused rather than compressed again, and the cache
+    // is updated with everything written, ready to be saved for the next build.
+    pub fn create_nsa_archive_cached(file: File, root_dir: &Path, entries : Vec<PathBuf>, key_table : &KeyTable, options : &CreateOptions, cache : &mut CompressionCache) -> Result<Vec<EntryReport>, ArchiveError> {
+        let names = entries.iter().map(|entry| stored_name(entry, options)).collect::<Result<Vec<String>, ArchiveError>>()?;
+        let mut file_helper = FileHelper {file, key_table : *key_table, position : 0};
+        let mut original_sizes = Vec::with_capacity(entries.len());
+
+        log::debug!("Entries: {}", entries.len());
+
+        let stored = write_archive(&mut file_helper, ArchiveType::NSA, &names, options.alignment, |i| {
+            let fullpath = root_dir.join(&entries[i]);
+            let plan = file_encoding_to_use(&fullpath, &entries[i], options);
+            let (compression, compressed, original_size) = cache.compress(&fullpath, &names[i], plan.compression, options)?;
+            let decompressed_size = nsa_decompressed_size(compression, &compressed, original_size, options)?;
+
+            log::debug!("Entry {}, {} -> {} ({:?})", names[i], decompressed_size, compressed.len(), compression);
+            original_sizes.push(original_size);
+            Ok((compression, compressed, decompressed_size))
+        })?;
+
+        let reports = names.into_iter().zip(original_sizes).zip(stored);
+        Ok(reports.map(|((name, original_size), (compression, stored_size))| EntryReport { name, original_size, stored_size, compression }).collect())
+    }
+    
+    // Produces the same archive as create_nsa_archive, but compresses entries on options.threads threads. They're
+    // compressed a batch of a few per thread at a time, and each batch is written out in order before the next is
+    // started, so the layout is the same as the serial path and only a batch's worth of entries is held in memory.
+    // progress is called with the number of entries written so far and the total, as each one is written.
+    pub fn create_nsa_archive_parallel<F>(file: File, root_dir: &Path, entries : Vec<PathBuf>, key_table : &KeyTable, options : &CreateOptions, mut progress : F) -> Result<Vec<EntryReport>, ArchiveError>
+    where F : FnMut(usize, usize) {
+        let threads = match options.threads {
+            0 => std::thread::available_parallelism().map_or(1, |threads| threads.get()),
+            threads => threads
+        };
+        let batch_size = threads * 4;
+
+        let names = entries.iter().map(|entry| stored_name(entry, options)).collect::<Result<Vec<_>, _>>()?;
+        let mut file_helper = FileHelper {file, key_table : *key_table, position : 0};
+        let mut batch : std::collections::VecDeque<Result<StoredEntry, ArchiveError>> = Default::default();
+
+        let stored = write_archive(&mut file_helper, ArchiveType::NSA, &names, options.alignment, |i| {
+            if batch.is_empty() {
+                let batch_entries = &entries[i..entries.len().min(i + batch_size)];
+                batch = compress_files_in_parallel(root_dir, batch_entries, options, threads).into();
+            }
+
+            progress(i, entries.len());
+            batch.pop_front().unwrap()
+        })?;
+
+        progress(entries.len(), entries.len());
+        entries.iter().zip(&names).zip(stored).map(|((entry, name), (compression, size))| entry_report(root_dir, entry, name, compression, size)).collect()
+    }
+
+    // Produces the same archive as create_nsa_archive, but for outputs that can't seek back to fill in the header,
+    // such as a pipe. Every entry is compressed once to find out how big it'll be, so the whole header can be written
+    // first, and then again as it's written out. That takes about twice as long, but still only holds one entry in
+    // memory at a time. A file that changes between the two passes fails the write rather than corrupting it.
+    pub fn create_nsa_archive_streaming<W : Write>(out : W, root_dir : &Path, entries : Vec<PathBuf>, key_table : &KeyTable, options : &CreateOptions) -> Result<Vec<EntryReport>, ArchiveError> {
+        if (u16::MAX as usize) < entries.len() {
+            return Err(ArchiveError::TooManyEntries { count : entries.len() });
+        }
+
+        let names = entries.iter().map(|entry| stored_name(entry, options)).collect::<Result<Vec<_>, _>>()?;
+        let encoded_names = names.iter().map(|name| encode_shiftjis(name)).collect::<Result<Vec<_>, _>>()?;
+        let header_size = names.iter().try_fold(header_base_size(ArchiveType::NSA), |size, name| Ok::<_, ArchiveError>(size + header_entry_size(name, ArchiveType::NSA)?))?;
+
+        let mut header = Vec::with_capacity(header_size);
+        header.extend_from_slice(&(names.len() as u16).to_be_bytes());
+        header.extend_from_slice(&archive_u32(header_size)?.to_be_bytes());
+
+        let mut reports = Vec::with_capacity(entries.len());
+        let mut offset = header_size;
+        for ((entry, name), encoded_name) in entries.iter().zip(&names).zip(&encoded_names) {
+            let (compression, data, decompressed_size) = compress_file_for_nsa(root_dir, entry, options)?;
+            offset += padding_for(offset, options.alignment);
+
+            header.extend_from_slice(encoded_name);
+            header.push(0);
+            header.push(u8::from(compression));
+            header.extend_from_slice(&archive_u32(offset - header_size)?.to_be_bytes());
+            header.extend_from_slice(&archive_u32(data.len())?.to_be_bytes());
+            header.extend_from_slice(&archive_u32(decompressed_size)?.to_be_bytes());
+
+            offset += data.len();
+            archive_u32(offset)?;
+            reports.push(entry_report(root_dir, entry, name, compression, data.len())?);
+        }
+
+        let inverse = key_table.invert();
+        let through_keytable = |data : &mut [u8]| data.iter_mut().for_each(|byte| *byte = inverse.apply(*byte));
+        through_keytable(&mut header);
+
+        let mut out = std::io::BufWriter::new(out);
+        out.write_all(&header)?;
+
+        let mut position = header_size;
+        for ((entry, name), size) in entries.iter().zip(&names).zip(reports.iter().map(|report| report.stored_size)) {
+            let padding = padding_for(position, options.alignment);
+            out.write_all(&vec![0; padding])?;
+
+            let (compression, mut data, _) = compress_file_for_nsa(root_dir, entry, options)?;
+            if data.len() != size {
+                return Err(ArchiveError::ChangedDuringWrite { name : name.clone() });
+            }
+            if is_keyed(compression, name) {
+                through_keytable(&mut data);
+            }
+            out.write_all(&data)?;
+
+            position += padding + size;
+        }
+
+        out.flush()?;
+        Ok(reports)
+    }
+
+    fn parse_ns2_header(file : &mut FileHelper, offset : u32, options : &ReadOptions) -> Result<ArchiveIndex, ArchiveError> {
+        let mut entries : Vec<ArchiveEntry> = Vec::new();
+        let data_offset = file.read_u32_le()?;
+
+        // The whole header is read before it's walked, so a data offset past the end of the file is refused before
+        // anything that size is allocated.
+        let archive_size = (file.file.metadata()?.len() as usize).saturating_sub(offset as usize);
+        if archive_size < data_offset as usize {
+            return Err(ArchiveError::MalformedHeader { position : offset as usize });
+        }
+        let header = file.try_read_slice_through_keytable(offset as usize, data_offset as usize)?;
+        let mut entry_offset = data_offset; // Entries follow one another, in the order they're listed.
+
+        let (header_entries, walked) = walk_ns2_header(&header, data_offset as usize);
+        walked.map_err(|position| ArchiveError::MalformedHeader { position : offset as usize + position })?;
+
+        for (name, size) in header_entries {
+            let (name, malformed) = decode_shiftjis_name(name, options)?;
+            if malformed {
+                report_malformed_name(entries.len(), &name);
+            }
+
+            let compression = compression_from_name(&name);
+            let file_offset = file_position(offset, entry_offset)?;
+            
+            log::debug!("{name}: {size}: {file_offset}");
+            
+            entries.push(ArchiveEntry {
+                name, offset: file_offset, size: size as usize, decompressed_size: None, compression
+            });
+
+            entry_offset = archive_position(&[entry_offset, size])?;
+        }
+
+        Ok(ArchiveIndex::new(entries, file_position(offset, data_offset)?))
+    }
+
+    // NS2 has no compression field and can't be padded, so entries are stored as they are on disk, in the order given,
+    // and no CreateOptions apply. As with SAR, entries named .nbz or .spb are read as Bzip2 and SPB.
+    pub fn create_ns2_archive(file: File, root_dir: &Path, entries : Vec<PathBuf>, key_table : &KeyTable) -> Result<Vec<EntryReport>, ArchiveError> {
+        let names = entries.iter().map(|entry| entry_name(entry)).collect::<Result<Vec<String>, ArchiveError>>()?;
+        let mut file_helper = FileHelper {file, key_table : *key_table, position : 0};
+
+        let stored = write_archive(&mut file_helper, ArchiveType::NS2, &names, 0, |i| {
+            let data = std::fs::read(root_dir.join(&entries[i]))?;
+            let size = data.len();
+            Ok((Compression::None, data, size))
+        })?;
+
+        entries.iter().zip(&names).zip(stored).map(|((entry, name), (compression, size))| entry_report(root_dir, entry, name, compression, size)).collect()
+    }
+
+    fn parse_header(file : &mut FileHelper, archive_type : &ArchiveType, offset : u32, options : &ReadOptions) -> Result<ArchiveIndex, ArchiveError> {
+        match archive_type {
+            ArchiveType::SAR => Self::parse_sar_header(file, offset, options),
+            ArchiveType::NSA => Self::parse_nsa_header(file, offset, options),
+            ArchiveType::NS2 => Self::parse_ns2_header(file, offset, options)
+        }
+    }
+
+    pub fn open_file(file : std::fs::File, archive_type : ArchiveType, offset : u32, key_table : &KeyTable) -> Archive {
+        Self::open_file_with_options(file, archive_type, offset, key_table, &ReadOptions::default())
+    }
+
+    pub fn open_file_with_options(file : std::fs::File, archive_type : ArchiveType, offset : u32, key_table : &KeyTable, options : &ReadOptions) -> Archive {
+        Self::try_open_file_with_options(file, archive_type, offset, key_table, options).unwrap()
+    }
+
+    pub fn try_open_file_with_options(file : std::fs::File, archive_type : ArchiveType, offset : u32, key_table : &KeyTable, options : &ReadOptions) -> Result<Archive, ArchiveError> {
+        let mut file_helper = FileHelper {file, key_table : *key_table, position : 0};
+
+        // A handful of archives named .nsa are really SAR archives, which an NSA reading takes apart at the wrong places.
+        let archive_type = match archive_type {
+            ArchiveType::NSA if Self::is_sar_named_nsa(&mut file_helper, offset)? => {
+                log::info!("Archive has no compression bytes in its header, reading it as a SAR archive");
+                ArchiveType::SAR
+            }
+            archive_type => archive_type
+        };
+
+        // The header sits at the offset too, not just the data, as is the case for archives embedded in another file.
+        file_helper.seek(SeekFrom::Start(offset as u64))?;
+        let index = Self::parse_header(&mut file_helper, &archive_type, offset, options)?;
+
+        Ok(Archive {
+            file : file_helper,
+            index,
+            archive_type,
+        })
+    }
+
+    // The archive in file with an index that's already been read, such as one ArchiveIndex::deserialize gave back,
+    // rather than reading its header. The index has to be the one read from this file with the same key table, as
+    // nothing is checked against the header.
+    pub fn from_index(file : File, index : ArchiveIndex, archive_type : ArchiveType, key_table : &KeyTable) -> Archive {
+        Archive { file : FileHelper { file, key_table : *key_table, position : 0 }, index, archive_type }
+    }
+
+    // The SAR or NSA header at offset as far as its data offset says it goes, read through the key table, and the size of
+    // everything from offset on. None if there isn't room for even the start of a header.
+    fn read_header_at(file : &mut FileHelper, offset : usize) -> Result<Option<(Vec<u8>, usize)>, ArchiveError> {
+        let archive_size = (file.file.metadata()?.len() as usize).saturating_sub(offset);
+        if archive_size < 6 {
+            return Ok(None);
+        }
+
+        let start = file.try_read_slice_through_keytable(offset, 6)?;
+        let data_offset = u32::from_be_bytes(start[2..6].try_into().unwrap()) as usize;
+        let header = file.try_read_slice_through_keytable(offset, data_offset.clamp(6, archive_size))?;
+
+        Ok(Some((header, archive_size)))
+    }
+
+    // Whether the header at offset only makes sense read as SAR, archives that read consistently as NSA are left alone.
+    fn is_sar_named_nsa(file : &mut FileHelper, offset : u32) -> Result<bool, ArchiveError> {
+        let Some((header, archive_size)) = Self::read_header_at(file, offset as usize)? else {
+            return Ok(false);
+        };
+
+        Ok(!header_is_consistent(&header, archive_size, &ArchiveType::NSA) && header_is_consistent(&header, archive_size, &ArchiveType::SAR))
+    }
+
+    // How many bytes were put in front of the archive's header when it was damaged in transfer, a UTF-8 BOM from being
+    // handled as text, or some stray whitespace, or both. They're only taken as such when the header doesn't make sense
+    // where it is but does after them. Archives put in front of on purpose are what find_embedded_archive is for.
+    fn damaged_preamble_size(file : &mut FileHelper, archive_type : &ArchiveType) -> Result<usize, ArchiveError> {
+        const BOM : &[u8] = b"\xEF\xBB\xBF";
+        const MAX_WHITESPACE : usize = 16;
+
+        // An NSA might really be a SAR, as try_open_file_with_options allows for.
+        let types : &[ArchiveType] = match archive_type {
+            ArchiveType::SAR => &[ArchiveType::SAR],
+            ArchiveType::NSA => &[ArchiveType::NSA, ArchiveType::SAR],
+            ArchiveType::NS2 => return Ok(0)
+        };
+        let fits_at = |file : &mut FileHelper, offset : usize| -> Result<bool, ArchiveError> {
+            let Some((header, archive_size)) = Self::read_header_at(file, offset)? else {
+                return Ok(false);
+            };
+            Ok(types.iter().any(|archive_type| header_is_consistent(&header, archive_size, archive_type)))
+        };
+
+        // These bytes were added after the archive was written, so they aren't behind the key table.
+        let file_size = file.file.metadata()?.len() as usize;
+        let start = file.try_read_slice(0, file_size.min(BOM.len() + MAX_WHITESPACE))?;
+
+        let bom = if start.starts_with(BOM) { BOM.len() } else { 0 };
+        let whitespace = start[bom..].iter().take_while(|byte| byte.is_ascii_whitespace()).count();
+        let size = bom + whitespace;
+
+        if size == 0 || fits_at(file, 0)? || !fits_at(file, size)? {
+            return Ok(0);
+        }
+
+        Ok(size)
+    }
+
+    // Opens the archive at the start of the file as try_open_file_with_options does, unless some bytes were put in front
+    // of it in transfer, see damaged_preamble_size, in which case they're reported and it's opened after them. NS2
+    // headers have nothing to check them against, so those are always opened at the start.
+    pub fn try_open_auto(file : File, archive_type : ArchiveType, key_table : &KeyTable, options : &ReadOptions) -> Result<Archive, ArchiveError> {
+        let mut file_helper = FileHelper { file, key_table : *key_table, position : 0 };
+        let offset = Self::damaged_preamble_size(&mut file_helper, &archive_type)?;
+
+        if offset != 0 {
+            let skipped = file_helper.try_read_slice(0, offset)?;
+            log::info!("Skipped {offset} bytes in front of the archive's header: {skipped:02X?}");
+        }
+
+        Self::try_open_file_with_options(file_helper.file, archive_type, offset as u32, key_table, options)
+    }
+
+    pub fn open_auto(file : File, archive_type : ArchiveType, key_table : &KeyTable, options : &ReadOptions) -> Archive {
+        Self::try_open_auto(file, archive_type, key_table, options).unwrap()
+    }
+
+    // Which type of archive the file holds going by its header rather than its name, for archives that have been renamed
+    // or lost their extension. NS2 starts with a little endian data offset where SAR and NSA have a big endian count
+    // and offset, so the same bytes read as the wrong one give nonsense, and every reading is walked all the way
+    // through rather than trusting those first bytes. NSA is tried before SAR, a SAR's header read as NSA falls apart
+    // at the compression bytes. None if none of them make sense of it.
+    pub fn detect_archive_type(file : &File, key_table : &KeyTable) -> Result<Option<ArchiveType>, ArchiveError> {
+        // Even 65535 entries with long names don't come near this, so a nonsense offset in something that isn't an
+        // archive can't have the whole file read.
+        const MAX_HEADER_SIZE : usize = 1 << 23;
+
+        let file_size = file.metadata()?.len() as usize;
+        let mut file_helper = FileHelper { file : file.try_clone()?, key_table : *key_table, position : 0 };
+        if file_size < 6 {
+            return Ok(None);
+        }
+
+        let start = file_helper.try_read_slice_through_keytable(0, 6)?;
+        let be_offset = u32::from_be_bytes(start[2..6].try_into().unwrap()) as usize;
+        // NS2's first name starts right after its offset, anything else can't be one.
+        let le_offset = match start[4] {
+            b'"' => u32::from_le_bytes(start[0..4].try_into().unwrap()) as usize,
+            _ => 0
+        };
+        let header_size = be_offset.max(le_offset).clamp(6, file_size.min(MAX_HEADER_SIZE));
+        let header = file_helper.try_read_slice_through_keytable(0, header_size)?;
+
+        let detected = if header_is_consistent(&header, file_size, &ArchiveType::NSA) {
+            Some(ArchiveType::NSA)
+        } else if header_is_consistent(&header, file_size, &ArchiveType::SAR) {
+            Some(ArchiveType::SAR)
+        } else if ns2_header_is_consistent(&header, file_size) {
+            Some(ArchiveType::NS2)
+        } else {
+            None
+        };
+
+        Ok(detected)
+    }
+
+    // Scans backwards from the end of the file for a SAR or NSA header, for archives that have been appended
+    // to something else, most commonly the game's executable. Returns the archive's type and offset. Only headers in
+    // the first 4 GiB are looked for, as an offset past that can't be given.
+    pub fn find_embedded_archive(file : &mut File, key_table : &KeyTable) -> Option<(ArchiveType, u32)> {
+        Self::find_embedded_archive_in_windows(file, key_table, 1 << 20)
+    }
+
+    // The file is read a window at a time from the end, and each is checked along with the window after it, so a
+    // header is only read again on its own if it's longer than a window.
+    fn find_embedded_archive_in_windows(file : &mut File, key_table : &KeyTable, window_size : usize) -> Option<(ArchiveType, u32)> {
+        let file_size = file.metadata().ok()?.len() as usize;
+        let mut read = |offset : usize, size : usize| -> Option<Vec<u8>> {
+            let mut data = vec![0; size];
+            file.seek(SeekFrom::Start(offset as u64)).ok()?;
+            file.read_exact(&mut data).ok()?;
+
+            for byte in &mut data {
+                *byte = key_table[*byte as usize];
+            }
+            Some(data)
+        };
+
+        let mut end = file_size.min(u32::MAX as usize + 1);
+        while end > 0 {
+            let window_start = end.saturating_sub(window_size);
+            let window = read(window_start, file_size.min(end + window_size) - window_start)?;
+
+            for start in (window_start..end).rev() {
+                for archive_type in [ArchiveType::NSA, ArchiveType::SAR] {
+                    let consistent = match check_header(&window[start - window_start..], file_size - start, &archive_type) {
+                        HeaderCheck::Consistent => true,
+                        HeaderCheck::Inconsistent => false,
+                        HeaderCheck::Truncated => read(start, 6)
+                            .map(|start_of_header| u32::from_be_bytes(start_of_header[2..6].try_into().unwrap()) as usize)
+                            .filter(|&data_offset| data_offset <= file_size - start)
+                            .and_then(|data_offset| read(start, data_offset))
+                            .is_some_and(|header| header_is_consistent(&header, file_size - start, &archive_type))
+                    };
+
+                    if consistent {
+                        return Some((archive_type, u32::try_from(start).ok()?));
+                    }
+                }
+            }
+
+            end = window_start;
+        }
+
+        None
+    }
+
+    // The key table the archive was opened with, which is also the one KeyTable obfuscated scripts within it use.
+    pub fn key_table(&self) -> &KeyTable {
+        &self.file.key_table
+    }
+
+    // The entries whose data starts at or after offset bytes into the file, in the order they're listed, such as
+    // whatever was appended to an archive after a known earlier end of it.
+    pub fn entries_after(&self, offset : usize) -> Vec<&ArchiveEntry> {
+        self.index.entries.iter().filter(|entry| entry.offset >= offset).collect()
+    }
+
+    pub fn extract(&mut self, info : ArchiveEntryInfo) -> Vec<u8> {
+        self.try_extract(info).unwrap()
+    }
+
+    pub fn extract_by_name(&mut self, name : &str) -> Option<Vec<u8>> {
+        let index = *self.index.entries_map.get(name)?;
+        let info = self.index.entries[index].info();
+        Some(self.extract(info))
+    }
+
+    // The entry's data as it's stored, still compressed, with the compression it's stored with, so it can be copied
+    // into another archive without decompressing and compressing it again. Only the key table is undone, for the
+    // entries it applies to, decompress_entry does the rest.
+    pub fn extract_raw(&mut self, info : ArchiveEntryInfo) -> (Compression, Vec<u8>) {
+        self.try_extract_raw(info).unwrap()
+    }
+
+    pub fn try_extract_raw(&mut self, info : ArchiveEntryInfo) -> Result<(Compression, Vec<u8>), ArchiveError> {
+        let data = self.read_stored(&info)?;
+        Ok((info.compression, data))
+    }
+
+    fn spb_entry(&self, name : &str) -> Result<ArchiveEntryInfo, ArchiveError> {
+        let index = *self.index.entries_map.get(name).ok_or_else(|| ArchiveError::EntryNotFound { name : name.to_string() })?;
+        let info = self.index.entries[index].info();
+
+        match info.compression {
+            Compression::Spb => Ok(info),
+            _ => Err(ArchiveError::NotSpb { name : name.to_string() })
+        }
+    }
+
+    // An SPB entry decoded to the BMP it holds, for editing and putting back with replace_with_spb.
+    pub fn extract_spb_as_bmp(&mut self, name : &str) -> Result<Vec<u8>, ArchiveError> {
+        let info = self.spb_entry(name)?;
+        self.try_extract(info)
+    }
+
+    // Encodes bmp as SPB and writes it over the SPB entry's data, so the archive's file has to have been opened for
+    // writing. This only works in place: the image has to keep its size, as NSA headers record the size it decodes
+    // to, and has to encode to no more than the entry's stored size. The rest of the entry is zeroed, SPB decoding
+    // stops at the end of the image. Anything else needs the archive rebuilding, which CantReplaceInPlace says.
+    pub fn replace_with_spb(&mut self, name : &str, bmp : &[u8]) -> Result<(), ArchiveError> {
+        let info = self.spb_entry(name)?;
+        let old = self.read_stored(&info)?;
+        let new = encode_spb(Image::from_bmp_bytes(bmp).map_err(ArchiveError::Spb)?).map_err(ArchiveError::Spb)?;
+
+        if info.size < new.len() || old.get(0..4) != new.get(0..4) {
+            return Err(ArchiveError::CantReplaceInPlace { name : name.to_string() });
+        }
+
+        let mut data = new;
+        data.resize(info.size, 0);
+
+        self.file.file.seek(SeekFrom::Start(info.offset as u64))?;
+        self.file.file.write_all(&data)?;
+        self.file.file.flush()?;
+        Ok(())
+    }
+
+    // Every entry decompressed into memory at once, so this needs as much memory as the whole archive extracted,
+    // which is fine for small archives and tests but not something to do with a game's main archive.
+    pub fn extract_all_to_map(&mut self) -> HashMap<String, Vec<u8>> {
+        self.try_extract_all_to_map().unwrap()
+    }
+
+    pub fn try_extract_all_to_map(&mut self) -> Result<HashMap<String, Vec<u8>>, ArchiveError> {
+        let mut entries = HashMap::with_capacity(self.index.entries_map.len());
+
+        for (name, index) in self.index.entries_map.clone() {
+            let info = self.index.entries[index].info();
+            let data = self.try_extract(info)?;
+            entries.insert(name, data);
+        }
+
+        Ok(entries)
+    }
+
+    pub fn try_extract(&mut self, info : ArchiveEntryInfo) -> Result<Vec<u8>, ArchiveError> {
+        let buffer = self.read_stored(&info)?;
+        decompress_entry(buffer, info.compression, info.decompressed_size)
+    }
+
+    // As extract, but into buffer, which is cleared first, so extracting one entry after another can reuse the one
+    // allocation instead of making a new one for each. Compressed entries are still read into a temporary buffer to
+    // be decompressed from, and SPB images are decoded into one of their own that then replaces buffer.
+    pub fn extract_into(&mut self, info : ArchiveEntryInfo, buffer : &mut Vec<u8>) {
+        self.try_extract_into(info, buffer).unwrap()
+    }
+
+    pub fn try_extract_into(&mut self, info : ArchiveEntryInfo, buffer : &mut Vec<u8>) -> Result<(), ArchiveError> {
+        buffer.clear();
+
+        match info.compression {
+            Compression::None => self.file.try_read_onto(info.offset, info.size, buffer, true)?,
+            Compression::Unknown(compression) => return Err(ArchiveError::UnknownCompression { compression }),
+            Compression::Spb => *buffer = self.try_extract(info)?,
+            Compression::Lzss => {
+                let stored = self.read_stored(&info)?;
+                buffer.reserve(info.decompressed_size.unwrap_or(stored.len()));
+                NsaLzss::decompress_stack(lzss::SliceReader::new(&stored), AppendWriter(buffer)).unwrap();
+
+                if let Some(decompressed_size) = info.decompressed_size {
+                    buffer.truncate(decompressed_size);
+                }
+            },
+            Compression::Bzip2 => {
+                let stored = self.read_stored(&info)?;
+                let (Some(size), Some(stream)) = (stored.get(0..4), stored.get(4..)) else {
+                    return Err(ArchiveError::Io(ErrorKind::UnexpectedEof.into()));
+                };
+
+                buffer.reserve(u32::from_be_bytes(size.try_into().unwrap()) as usize);
+                bzip2::read::BzDecoder::new(stream).read_to_end(buffer)?;
+            },
+            Compression::Zstd => *buffer = self.try_extract(info)?
+        }
+
+        Ok(())
+    }
+
+    // How many bytes extract will return for the entry, found without decompressing it where that can be done: SPB
+    // data starts with the image's dimensions, Bzip2 data with its decompressed size, and NSA headers give the size
+    // of LZSS entries. LZSS entries whose header leaves it out are the exception and get decompressed.
+    pub fn decompressed_size(&mut self, info : ArchiveEntryInfo) -> usize {
+        self.try_decompressed_size(info).unwrap()
+    }
+
+    pub fn try_decompressed_size(&mut self, info : ArchiveEntryInfo) -> Result<usize, ArchiveError> {
+        match info.compression {
+            Compression::None => Ok(info.size),
+            Compression::Unknown(compression) => Err(ArchiveError::UnknownCompression { compression }),
+            Compression::Lzss => match info.decompressed_size {
+                Some(size) => Ok(size),
+                None => Ok(self.try_extract(info)?.len())
+            },
+            Compression::Spb => {
+                let start = self.file.try_read_slice(info.offset, info.size.min(4))?;
+                decoded_spb_size(&start).map_err(ArchiveError::Spb)
+            }
+            Compression::Bzip2 => {
+                let start = self.file.try_read_slice(info.offset, info.size.min(4))?;
+                let size : [u8; 4] = start.try_into().map_err(|_| std::io::Error::from(std::io::ErrorKind::UnexpectedEof))?;
+                Ok(u32::from_be_bytes(size) as usize)
+            },
+            Compression::Zstd => match info.decompressed_size {
+                Some(size) => Ok(size),
+                None => Ok(self.try_extract(info)?.len())
+            }
+        }
+    }
+
+    // The entry's data as it's stored in the archive, still compressed.
+    fn read_stored(&mut self, info : &ArchiveEntryInfo) -> Result<Vec<u8>, ArchiveError> {
+        let buffer = match info.compression {
+            Compression::None | Compression::Lzss => self.file.try_read_slice_through_keytable(info.offset, info.size)?,
+            Compression::Spb | Compression::Bzip2 | Compression::Zstd | Compression::Unknown(_) => self.file.try_read_slice(info.offset, info.size)?
+        };
+
+        Ok(buffer)
+    }
+
+    // Writes every entry of src into a new archive of the target type. Entries are decompressed and, for an NSA
+    // target, compressed again as options asks. Entries named .nbz or .spb are the exception, readers decide how
+    // to decompress those by their name, so they're copied as they're stored.
+    pub fn convert(src : &mut Archive, out : File, target : ArchiveType, options : &CreateOptions) -> Result<(), ArchiveError> {
+        let names : Vec<String> = src.index.entries.iter().map(|entry| entry.name.clone()).collect();
+        let mut file_helper = FileHelper { file : out, key_table : src.file.key_table, position : 0 };
+
+        write_archive(&mut file_helper, target, &names, options.alignment, |i| {
+            let info = src.index.entries[i].info();
+            let lowercase_name = names[i].to_lowercase();
+
+            if lowercase_name.ends_with(".nbz") || lowercase_name.ends_with(".spb") {
+                let data = src.read_stored(&info)?;
+                let size = nsa_decompressed_size(Compression::None, &data, data.len(), options)?;
+                return Ok((Compression::None, data, size));
+            }
+
+            let data = src.try_extract(info)?;
+            if !matches!(target, ArchiveType::NSA) {
+                let size = data.len();
+                return Ok((Compression::None, data, size));
+            }
+
+            let plan = encoding_to_use(Path::new(&names[i]), data.get(..MAGIC_SIZE).unwrap_or(&data), options);
+            let (compression, compressed) = compress_entry(&data, plan.compression, options)?;
+            let decompressed_size = nsa_decompressed_size(compression, &compressed, data.len(), options)?;
+
+            Ok((compression, compressed, decompressed_size))
+        })?;
+
+        Ok(())
+    }
+
+    // Writes src again with its entries packed one after another, in the same order and compressed as they are, leaving
+    // out any gaps between them and anything after the last. SPB entries that replace_with_spb wrote a smaller image
+    // into have the zeroes after it dropped too. NSA decompressed sizes are written as the actual size, even where the
+    // archive had 0.
+    pub fn compact(src : &mut Archive, out : File) -> Result<(), ArchiveError> {
+        let names : Vec<String> = src.index.entries.iter().map(|entry| entry.name.clone()).collect();
+        let mut file_helper = FileHelper { file : out, key_table : src.file.key_table, position : 0 };
+
+        write_archive(&mut file_helper, src.archive_type, &names, 0, |i| {
+            let info = src.index.entries[i].info();
+            let compression = info.compression;
+            let size = info.size;
+
+            let (data, decompressed_size) = match compression {
+                Compression::Spb => {
+                    let data = trim_spb_slack(src.read_stored(&info)?);
+                    let decompressed_size = decoded_spb_size(&data).map_err(ArchiveError::Spb)?;
+                    (data, decompressed_size)
+                }
+                Compression::Unknown(_) => (src.read_stored(&info)?, size),
+                _ => {
+                    let data = src.read_stored(&info)?;
+                    (data, src.try_decompressed_size(info)?)
+                }
+            };
+
+            Ok((compression, data, decompressed_size))
+        })?;
+
+        Ok(())
+    }
+
+    // Compacts the archive at path over itself, through write_atomically.
+    pub fn compact_file(path : &Path, archive_type : ArchiveType, offset : u32, key_table : &KeyTable) -> Result<(), ArchiveError> {
+        let mut src = Archive::try_open_file_with_options(File::open(path)?, archive_type, offset, key_table, &ReadOptions::default())?;
+        write_atomically(path, |out| Archive::compact(&mut src, out))
+    }
+
+    // As replace_with_spb, for the archive at path. The archive is copied and the image replaced in the copy, which
+    // then takes the original's place through write_atomically, rather than writing over the original's data.
+    pub fn replace_with_spb_in_file(path : &Path, archive_type : ArchiveType, offset : u32, key_table : &KeyTable, name : &str, bmp : &[u8]) -> Result<(), ArchiveError> {
+        write_atomically(path, |mut out| {
+            std::io::copy(&mut File::open(path)?, &mut out)?;
+            out.seek(SeekFrom::Start(0))?;
+
+            let mut archive = Archive::try_open_file_with_options(out, archive_type, offset, key_table, &ReadOptions::default())?;
+            archive.replace_with_spb(name, bmp)
+        })
+    }
+
+    // The first n bytes of the entry as extract would return them, or all of it if it's shorter. Uncompressed entries
+    // only have those bytes read, LZSS and Bzip2 entries stop decompressing once they have them, but SPB can't
+    // stop early as the BMP it decodes to starts with the bottom row of the image, and Zstd entries are decompressed
+    // whole too.
+    pub fn peek(&mut self, info : ArchiveEntryInfo, n : usize) -> Vec<u8> {
+        self.try_peek(info, n).unwrap()
+    }
+
+    pub fn try_peek(&mut self, info : ArchiveEntryInfo, n : usize) -> Result<Vec<u8>, ArchiveError> {
+        match info.compression {
+            Compression::None => Ok(self.file.try_read_slice_through_keytable(info.offset, n.min(info.size))?),
+            Compression::Unknown(compression) => Err(ArchiveError::UnknownCompression { compression }),
+            Compression::Lzss => {
+                let input = self.file.try_read_slice_through_keytable(info.offset, info.size)?;
+                let mut output = vec![0; n];
+
+                // The writer erroring just means it's full, which is what we're after.
+                let length = match NsaLzss::decompress_stack(lzss::SliceReader::new(&input), lzss::SliceWriter::new(&mut output)) {
+                    Ok(length) => length,
+                    Err(_) => n
+                };
+
+                output.truncate(length);
+                Ok(output)
+            },
+            Compression::Bzip2 => {
+                let input = self.file.try_read_slice(info.offset, info.size)?;
+                let Some(stream) = input.get(4..) else {
+                    return Err(ArchiveError::Io(ErrorKind::UnexpectedEof.into()));
+                };
+
+                let mut output = Vec::with_capacity(n);
+                bzip2::read::BzDecoder::new(stream).take(n as u64).read_to_end(&mut output)?;
+                Ok(output)
+            },
+            Compression::Spb | Compression::Zstd => {
+                let mut output = self.try_extract(info)?;
+                output.truncate(n);
+                Ok(output)
+            }
+        }
+    }
+
+    // Compresses the file with every compression that applies to it and checks each decompresses back to the
+    // original. Bzip2's output bytes depend on the version of libbzip2 doing the compressing, so it's the decoded
+    // data that's compared rather than the compressed bytes, and for SPB, which only keeps the pixels, it's the
+    // pixels that have to match.
+    pub fn verify_roundtrip(path : &Path) -> Result<(), crate::Error> {
+        let data = std::fs::read(path)?;
+
+        for compression in [Compression::None, Compression::Spb, Compression::Lzss, Compression::Bzip2] {
+            let (compression, compressed) = compress_entry(&data, compression, &CreateOptions::default())?;
+            let decompressed = decompress_entry(compressed, compression, Some(data.len()))?;
+
+            let matches = match compression {
+                Compression::Spb => {
+                    let original = Image::from_bmp_bytes(&data)?;
+                    let roundtripped = Image::from_bmp_bytes(&decompressed)?;
+                    (original.width, original.height, original.pixel_buffer) == (roundtripped.width, roundtripped.height, roundtripped.pixel_buffer)
+                },
+                _ => decompressed == data
+            };
+
+            if !matches {
+                return Err(ArchiveError::RoundtripMismatch { compression }.into());
+            }
+        }
+
+        Ok(())
+    }
+
+    fn file_size(&mut self) -> usize {
+        self.file.file.metadata().unwrap().len() as usize
+    }
+
+    pub fn trailing_region(&mut self) -> Option<TrailingRegion> {
+        let end_of_data = self.index.entries.iter()
+            .map(|entry| entry.offset + entry.size)
+            .max()
+            .unwrap_or(self.index.offset);
+        let file_size = self.file_size();
+
+        if end_of_data < file_size {
+            Some(TrailingRegion { offset : end_of_data, size : file_size - end_of_data })
+        } else {
+            None
+        }
+    }
+
+    // The file from its start up to where the entries' data starts, which is the header and anything in front of it,
+    // read through the key table as the header is parsed, for seeing what a header that won't parse holds.
+    pub fn header_bytes(&mut self) -> Vec<u8> {
+        self.try_header_bytes().unwrap()
+    }
+
+    pub fn try_header_bytes(&mut self) -> Result<Vec<u8>, ArchiveError> {
+        Ok(self.file.try_read_slice_through_keytable(0, self.index.offset)?)
+    }
+
+    // The trailing bytes are returned verbatim, no key table is applied, so they can be appended as-is.
+    pub fn trailing_bytes(&mut self) -> Vec<u8> {
+        match self.trailing_region() {
+            Some(region) => self.file.read_slice(region.offset, region.size),
+            None => Vec::new()
+        }
+    }
+
+    pub fn validate(&mut self) -> Vec<ValidationIssue> {
+        let mut issues = Vec::new();
+        let file_size = self.file_size();
+
+        for entry in &self.index.entries {
+            if file_size < entry.offset + entry.size {
+                issues.push(ValidationIssue::EntryOutOfBounds { name : entry.name.clone(), offset : entry.offset, size : entry.size });
+            }
+        }
+
+        let alignment = self.index.alignment().unwrap_or(1);
+        // Gaps are from the furthest any entry so far reaches, entries out of order can fill in for each other.
+        let mut covered = self.index.entries.first().map_or(0, |entry| entry.offset + entry.size);
+
+        for pair in self.index.entries.windows(2) {
+            let (previous, entry) = (&pair[0], &pair[1]);
+            let end = previous.offset + previous.size;
+
+            if entry.offset < previous.offset {
+                issues.push(ValidationIssue::OutOfOrder {
+                    name : entry.name.clone(), offset : entry.offset, previous : previous.name.clone(), previous_offset : previous.offset
+                });
+            } else if entry.offset < end {
+                let size = end.min(entry.offset + entry.size) - entry.offset;
+                issues.push(ValidationIssue::Overlap { name : entry.name.clone(), previous : previous.name.clone(), size });
+            } else if covered + alignment <= entry.offset || (covered < entry.offset && entry.offset % alignment != 0) {
+                issues.push(ValidationIssue::Gap { offset : covered, size : entry.offset - covered });
+            }
+
+            covered = covered.max(entry.offset + entry.size);
+        }
+
+        if let Some(region) = self.trailing_region() {
+            issues.push(ValidationIssue::TrailingData(region));
+        }
+
+        issues
+    }
+
+    // Compares the archive against the directory under root it's meant to hold, entry by entry, by extracting each
+    // one and comparing it with the file at its name. Names are matched as the engine looks them up, ignoring case
+    // and whichever separator they use. Entries listed more than once are each compared against the same file.
+    pub fn verify_against_dir(&mut self, root : &Path) -> Vec<Discrepancy> {
+        let lookup_name = |name : &str| name.replace('\\', "/").to_lowercase();
+
+        let mut files = Vec::new();
+        crate::vfs::collect_loose_files(root, root, &mut files);
+        let mut unmatched : BTreeMap<String, String> = files.into_iter().map(|file| (lookup_name(&file), file)).collect();
+        let mut matched = HashMap::new();
+
+        let mut discrepancies = Vec::new();
+        for i in 0..self.index.entries.len() {
+            let name = self.index.entries[i].name.clone();
+            let key = lookup_name(&name);
+
+            let Some(file) = unmatched.remove(&key).or_else(|| matched.get(&key).cloned()) else {
+                discrepancies.push(Discrepancy::Missing { name });
+                continue;
+            };
+            matched.insert(key, file.clone());
+
+            let data = match self.try_extract(self.index.entries[i].info()) {
+                Ok(data) => data,
+                Err(error) => {
+                    discrepancies.push(Discrepancy::Unreadable { name, error });
+                    continue;
+                }
+            };
+
+            if !matches_file(&data, &root.join(&file)).unwrap_or(false) {
+                discrepancies.push(Discrepancy::Differs { name });
+            }
+        }
+
+        discrepancies.extend(unmatched.into_values().map(|path| Discrepancy::Extra { path }));
+        discrepancies
+    }
+}
+
+// Reads an archive front to back, for sources that can't seek such as pipes, see Archive::stream_entries, and
+// for salvaging what can be read of a damaged header, see Archive::try_salvage.
+struct StreamReader<R : Read> {
+    reader : R,
+    key_table : KeyTable,
+    position : usize,
+    options : ReadOptions
+}
+
+impl<R : Read> StreamReader<R> {
+    fn read_raw(&mut self, size : usize) -> std::io::Result<Vec<u8>> {
+        let mut buffer = vec![0; size];
+        self.reader.read_exact(&mut buffer)?;
+        self.position += size;
+        Ok(buffer)
+    }
+
+    fn read_keyed(&mut self, size : usize) -> std::io::Result<Vec<u8>> {
+        let mut buffer = self.read_raw(size)?;
+        for byte in &mut buffer {
+            *byte = self.key_table[*byte as usize];
+        }
+        Ok(buffer)
+    }
+
+    // As read_keyed, but stopping early if the source runs out first.
+    fn read_keyed_up_to(&mut self, size : usize) -> std::io::Result<Vec<u8>> {
+        let mut buffer = Vec::new();
+        (&mut self.reader).take(size as u64).read_to_end(&mut buffer)?;
+        self.position += buffer.len();
+
+        for byte in &mut buffer {
+            *byte = self.key_table[*byte as usize];
+        }
+        Ok(buffer)
+    }
+
+    fn read_u8(&mut self) -> std::io::Result<u8> {
+        Ok(self.read_keyed(1)?[0])
+    }
+
+    fn read_u16_be(&mut self) -> std::io::Result<u16> {
+        Ok(u16::from_be_bytes(self.read_keyed(2)?.try_into().unwrap()))
+    }
+
+    fn read_u32_be(&mut self) -> std::io::Result<u32> {
+        Ok(u32::from_be_bytes(self.read_keyed(4)?.try_into().unwrap()))
+    }
+
+    fn read_u32_le(&mut self) -> std::io::Result<u32> {
+        Ok(u32::from_le_bytes(self.read_keyed(4)?.try_into().unwrap()))
+    }
+
+    fn read_until(&mut self, terminator : u8) -> Result<String, ArchiveError> {
+        let mut buffer = Vec::new();
+        loop {
+            let byte = self.read_u8()?;
+            if byte == terminator {
+                break;
+            }
+            buffer.push(byte);
+        }
+        Ok(decode_shiftjis_name(&buffer, &self.options)?.0)
+    }
+
+    // Reads and throws away everything up to offset, which can't be behind us.
+    fn skip_to(&mut self, offset : usize) -> Result<(), ArchiveError> {
+        if offset < self.position {
+            return Err(ArchiveError::NotStreamable { offset });
+        }
+        std::io::copy(&mut (&mut self.reader).take((offset - self.position) as u64), &mut std::io::sink())?;
+        self.position = offset;
+        Ok(())
+    }
+
+    // Entries are added as each is read, so those before whatever stops the header being read are kept.
+    fn read_header(&mut self, archive_type : ArchiveType, offset : u32, entries : &mut Vec<ArchiveEntry>) -> Result<(), ArchiveError> {
+        match archive_type {
+            ArchiveType::SAR | ArchiveType::NSA => {
+                let num_of_entries = self.read_u16_be()?;
+                let data_offset = self.read_u32_be()?;
+
+                for _ in 0..num_of_entries {
+                    let name = self.read_until(0)?;
+                    let compression = match archive_type {
+                        ArchiveType::NSA => {
+                            let byte = self.read_u8()?;
+                            try_nsa_compression(byte, &name).ok_or(ArchiveError::MalformedHeader { position : self.position - 1 })?
+                        }
+                        _ => compression_from_name(&name)
+                    };
+                    let entry_offset = self.read_u32_be()?;
+                    let size = self.read_u32_be()?;
+                    archive_position(&[data_offset, entry_offset, size])?;
+
+                    let entry_offset = file_position(offset, data_offset + entry_offset)?;
+                    let size = size as usize;
+                    let decompressed_size = match archive_type {
+                        ArchiveType::NSA => Some(self.read_u32_be()? as usize).filter(|&size| size != 0 && matches!(compression, Compression::None | Compression::Lzss)),
+                        _ => matches!(compression, Compression::None).then_some(size)
+                    };
+
+                    entries.push(ArchiveEntry { name, offset : entry_offset, size, decompressed_size, compression });
+                }
+            }
+            ArchiveType::NS2 => {
+                let data_offset = self.read_u32_le()?;
+                let offset_of_file_data = file_position(offset, data_offset)?;
+                let mut entry_offset = data_offset;
+
+                // The header is walked from as much of it as there is, so the entries before where it's cut off are
+                // kept too.
+                let mut header = data_offset.to_le_bytes().to_vec();
+                header.extend(self.read_keyed_up_to(offset_of_file_data.saturating_sub(self.position))?);
+                let (header_entries, walked) = walk_ns2_header(&header, data_offset as usize);
+
+                for (name, size) in header_entries {
+                    let name = decode_shiftjis_name(name, &self.options)?.0;
+                    let compression = compression_from_name(&name);
+                    let file_offset = file_position(offset, entry_offset)?;
+
+                    entries.push(ArchiveEntry { name, offset : file_offset, size : size as usize, decompressed_size : None, compression });
+                    entry_offset = archive_position(&[entry_offset, size])?;
+                }
+
+                walked.map_err(|position| ArchiveError::MalformedHeader { position : offset as usize + position })?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+struct StreamEntries<R : Read> {
+    reader : StreamReader<R>,
+    entries : std::vec::IntoIter<ArchiveEntry>
+}
+
+impl<R : Read> StreamEntries<R> {
+    fn read_stored(&mut self, entry : &ArchiveEntry) -> Result<Vec<u8>, ArchiveError> {
+        self.reader.skip_to(entry.offset)?;
+
+        Ok(match entry.compression {
+            Compression::None | Compression::Lzss => self.reader.read_keyed(entry.size)?,
+            Compression::Spb | Compression::Bzip2 | Compression::Zstd | Compression::Unknown(_) => self.reader.read_raw(entry.size)?
+        })
+    }
+}
+
+impl<R : Read> Iterator for StreamEntries<R> {
+    type Item = Result<(ArchiveEntry, Vec<u8>), ArchiveError>;
+
+    // An entry that doesn't decompress is an error on its own, but once reading fails where the stream is is unknown,
+    // so nothing more is read from it.
+    fn next(&mut self) -> Option<Self::Item> {
+        let entry = self.entries.next()?;
+
+        let data = match self.read_stored(&entry) {
+            Ok(data) => data,
+            Err(error) => {
+                self.entries = Vec::new().into_iter();
+                return Some(Err(error));
+            }
+        };
+
+        Some(decompress_entry(data, entry.compression, entry.decompressed_size).map(|data| (entry, data)))
+    }
+}
+
+impl Archive {
+    // Reads the header from reader, then each entry in the order they're laid out, yielding them as they're read
+    // and never seeking backward, so it works over pipes and other streams. Offset is how far into the stream the
+    // archive starts. This relies on the data following the header in order, as the tools that make archives lay
+    // it out, and on NSA entry offsets being from the start of the data, as there's no going back to check like
+    // open_file does. An entry overlapping the one before it is ArchiveError::NotStreamable.
+    pub fn stream_entries<R : Read>(reader : R, archive_type : ArchiveType, offset : u32, key_table : &KeyTable) -> Result<impl Iterator<Item = Result<(ArchiveEntry, Vec<u8>), ArchiveError>>, ArchiveError> {
+        let mut reader = StreamReader { reader, key_table : *key_table, position : 0, options : ReadOptions::default() };
+        reader.skip_to(offset as usize)?;
+
+        let mut entries = Vec::new();
+        reader.read_header(archive_type, offset, &mut entries)?;
+        entries.sort_by_key(|entry| entry.offset);
+
+        Ok(StreamEntries { reader, entries : entries.into_iter() })
+    }
+
+    // Opens as much of a damaged archive as can be read. The header is read entry by entry until something in it
+    // can't be, and the archive has every entry read before then, with the error that stopped it. Entries that run
+    // past the end of the file, or don't decompress, are left in, so extracting them fails on its own without
+    // stopping the rest. Names that aren't valid Shift-JIS are read with replacement characters.
+    pub fn try_salvage(file : File, archive_type : ArchiveType, offset : u32, key_table : &KeyTable) -> (Archive, Option<ArchiveError>) {
+        let mut entries = Vec::new();
+        let mut file_helper = FileHelper { file, key_table : *key_table, position : 0 };
+
+        let error = file_helper.file.seek(SeekFrom::Start(offset as u64)).map_err(ArchiveError::from).and_then(|_| {
+            let reader = std::io::BufReader::new(&file_helper.file);
+            let mut reader = StreamReader { reader, key_table : *key_table, position : offset as usize, options : ReadOptions { lossy_names : true, ..Default::default() } };
+            reader.read_header(archive_type, offset, &mut entries)
+        }).err();
+
+        let archive = Archive { file : file_helper, index : ArchiveIndex::new(entries, offset as usize), archive_type };
+        (archive, error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An archive made from files written to a temporary directory, which is removed when this is dropped.
+    struct Built {
+        _dir : tempfile::TempDir,
+        source : PathBuf,
+        path : PathBuf
+    }
+
+    impl Built {
+        fn bytes(&self) -> Vec<u8> {
+            std::fs::read(&self.path).unwrap()
+        }
+    }
+
+    fn build(archive_type : ArchiveType, files : &[(&str, &[u8])], options : &CreateOptions) -> Built {
+        build_with_key_table(archive_type, files, options, &KeyTable::identity())
+    }
+
+    fn xor_key_table(key : u8) -> KeyTable {
+        let mut bytes = [0; 256];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = i as u8 ^ key;
+        }
+        KeyTable::from_bytes(bytes).unwrap()
+    }
+
+    fn build_with_key_table(archive_type : ArchiveType, files : &[(&str, &[u8])], options : &CreateOptions, key_table : &KeyTable) -> Built {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("src");
+        for (name, data) in files {
+            let path = source.join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, data).unwrap();
+        }
+
+        let entries : Vec<PathBuf> = files.iter().map(|(name, _)| PathBuf::from(name)).collect();
+        let path = match archive_type {
+            ArchiveType::SAR => dir.path().join("arc.sar"),
+            ArchiveType::NSA => dir.path().join("arc.nsa"),
+            ArchiveType::NS2 => dir.path().join("00.ns2")
+        };
+        let file = File::create(&path).unwrap();
+
+        match archive_type {
+            ArchiveType::SAR => Archive::create_sar_archive(file, &source, entries, key_table, options),
+            ArchiveType::NSA => Archive::create_nsa_archive(file, &source, entries, key_table, options),
+            ArchiveType::NS2 => Archive::create_ns2_archive(file, &source, entries, key_table)
+        }.unwrap();
+
+        Built { _dir : dir, source, path }
+    }
+
+    #[test]
+    fn nsa_headers_with_any_compression_byte_are_detected() {
+        let built = build(ArchiveType::NSA, &[("a.txt", b"hello")], &CreateOptions::default());
+
+        for compression in [ZSTD_BYTE, 0x7F] {
+            let mut bytes = built.bytes();
+            bytes[6 + "a.txt".len() + 1] = compression;
+            std::fs::write(&built.path, &bytes).unwrap();
+
+            let file = File::open(&built.path).unwrap();
+            assert!(matches!(Archive::detect_archive_type(&file, &KeyTable::identity()), Ok(Some(ArchiveType::NSA))));
+        }
+    }
+
+    #[test]
+    fn streaming_a_truncated_archive_is_an_error() {
+        let files : &[(&str, &[u8])] = &[("a.txt", b"first"), ("b.txt", b"second")];
+        let bytes = build(ArchiveType::NSA, files, &CreateOptions::default()).bytes();
+
+        let streamed : Vec<(String, Vec<u8>)> = Archive::stream_entries(&bytes[..], ArchiveType::NSA, 0, &KeyTable::identity()).unwrap()
+            .map(|entry| entry.map(|(entry, data)| (entry.name, data)).unwrap())
+            .collect();
+        assert_eq!(streamed, vec![("a.txt".to_string(), b"first".to_vec()), ("b.txt".to_string(), b"second".to_vec())]);
+
+        // Cut off partway through the second entry's data, the first still comes through.
+        let cut = &bytes[..bytes.len() - 3];
+        let mut entries = Archive::stream_entries(cut, ArchiveType::NSA, 0, &KeyTable::identity()).unwrap();
+        assert_eq!(entries.next().unwrap().unwrap().1, b"first");
+        assert!(matches!(entries.next(), Some(Err(ArchiveError::Io(_)))));
+        assert!(entries.next().is_none());
+
+        // Cut off in the header.
+        assert!(Archive::stream_entries(&bytes[..10], ArchiveType::NSA, 0, &KeyTable::identity()).is_err());
+    }
+
+    #[test]
+    fn ns2_archives_read_back_as_written() {
+        let files : &[(&str, &[u8])] = &[("readme.txt", b"hello"), ("sub/\u{80cc}\u{666f}.bmp", b"BM not really"), ("sub/empty.dat", b""), ("voice.wav", &[7; 300])];
+        let key_table = xor_key_table(0x84);
+        let built = build_with_key_table(ArchiveType::NS2, files, &CreateOptions::default(), &key_table);
+
+        let mut file = FileHelper { file : File::open(&built.path).unwrap(), key_table, position : 0 };
+        let index = Archive::parse_ns2_header(&mut file, 0, &ReadOptions::default()).unwrap();
+
+        // A u32 data offset, each name quoted with its u32 size, and an 'e'.
+        let header_size = 4 + files.iter().map(|(name, _)| shiftjis_length(name).unwrap() + 2 + 4).sum::<usize>() + 1;
+        assert_eq!(index.offset, header_size);
+
+        let mut offset = header_size;
+        for (entry, (name, data)) in index.entries.iter().zip(files) {
+            assert_eq!((entry.name.as_str(), entry.size, entry.offset), (*name, data.len(), offset));
+            offset += data.len();
+        }
+        assert_eq!(index.entries.len(), files.len());
+        assert_eq!(offset, built.bytes().len());
+
+        let mut archive = Archive::try_open_file_with_options(File::open(&built.path).unwrap(), ArchiveType::NS2, 0, &key_table, &ReadOptions::default()).unwrap();
+        for (i, (_, data)) in files.iter().enumerate() {
+            let info = archive.index.entries[i].info();
+            assert_eq!(archive.read_stored(&info).unwrap(), *data);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn entries_whose_paths_arent_utf8_are_an_error() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let name = std::ffi::OsStr::from_bytes(b"\x82\xa0.txt");
+        std::fs::write(dir.path().join(name), b"").unwrap();
+
+        let file = File::create(dir.path().join("00.ns2")).unwrap();
+        let result = Archive::create_ns2_archive(file, dir.path(), vec![PathBuf::from(name)], &KeyTable::identity());
+        assert!(matches!(result, Err(ArchiveError::InvalidEntryName { .. })));
+    }
+
+    fn open_bytes(bytes : &[u8], archive_type : ArchiveType, options : &ReadOptions) -> Result<Archive, ArchiveError> {
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(bytes).unwrap();
+        Archive::try_open_file_with_options(file, archive_type, 0, &KeyTable::identity(), options)
+    }
+
+    #[test]
+    fn inflated_entry_counts_are_an_error() {
+        let files : &[(&str, &[u8])] = &[("a.txt", b"hello")];
+
+        for archive_type in [ArchiveType::SAR, ArchiveType::NSA] {
+            let mut bytes = build(archive_type, files, &CreateOptions::default()).bytes();
+            bytes[0..2].copy_from_slice(&u16::MAX.to_be_bytes());
+
+            let result = open_bytes(&bytes, archive_type, &ReadOptions::default());
+            assert!(matches!(result, Err(ArchiveError::ImpossibleEntryCount { count : 65535, .. })), "{archive_type:?}");
+        }
+    }
+
+    #[test]
+    fn truncated_headers_are_an_error() {
+        // Two entries, but the file ends partway through the first one's name.
+        let mut sar = vec![0, 2, 0, 0, 0, 100];
+        sar.extend_from_slice(b"a_name_22_bytes_long_x");
+        assert!(matches!(open_bytes(&sar, ArchiveType::SAR, &ReadOptions::default()), Err(ArchiveError::Io(_))));
+
+        // The name ends, but the offset and size after it don't.
+        sar.extend_from_slice(&[0, 0, 0]);
+        assert!(matches!(open_bytes(&sar, ArchiveType::SAR, &ReadOptions::default()), Err(ArchiveError::Io(_))));
+    }
+
+    #[test]
+    fn ns2_data_offsets_past_the_end_of_the_file_are_an_error() {
+        let mut ns2 = 0x8000_0000u32.to_le_bytes().to_vec();
+        ns2.extend_from_slice(b"\"a.txt\"\0\0\0");
+        assert_eq!(ns2.len(), 14);
+        assert!(matches!(open_bytes(&ns2, ArchiveType::NS2, &ReadOptions::default()), Err(ArchiveError::MalformedHeader { position : 0 })));
+    }
+
+    #[test]
+    fn names_that_arent_shift_jis_are_an_error_unless_lossy() {
+        let mut bytes = build(ArchiveType::SAR, &[("ab.txt", b"hello")], &CreateOptions::default()).bytes();
+        bytes[6] = 0x81; // A lead byte followed by a space, which can't trail it.
+        bytes[7] = b' ';
+
+        assert!(matches!(open_bytes(&bytes, ArchiveType::SAR, &ReadOptions::default()), Err(ArchiveError::MalformedName { .. })));
+
+        let archive = open_bytes(&bytes, ArchiveType::SAR, &ReadOptions { lossy_names : true, ..Default::default() }).unwrap();
+        assert_eq!(archive.index.entries.len(), 1);
+    }
+
+    #[test]
+    fn cached_nsa_archives_match_uncached_ones() {
+        let files : &[(&str, &[u8])] = &[("a.txt", &[b'a'; 1000]), ("sub/b.dat", b"no compression"), ("c.txt", &[b'c'; 500])];
+        let options = CreateOptions { lzss : true, alignment : 16, ..Default::default() };
+        let built = build(ArchiveType::NSA, files, &options);
+        let entries : Vec<PathBuf> = files.iter().map(|(name, _)| PathBuf::from(name)).collect();
+
+        let mut cache = CompressionCache::default();
+        for misses in [files.len(), 0] {
+            let path = built.source.with_file_name("cached.nsa");
+            let reports = Archive::create_nsa_archive_cached(File::create(&path).unwrap(), &built.source, entries.clone(), &KeyTable::identity(), &options, &mut cache).unwrap();
+
+            assert_eq!(std::fs::read(&path).unwrap(), built.bytes());
+            assert_eq!(cache.misses, misses);
+            assert_eq!(reports.iter().map(|report| (report.name.as_str(), report.original_size)).collect::<Vec<_>>(), vec![("a.txt", 1000), ("sub/b.dat", 14), ("c.txt", 500)]);
+
+            let saved = built.source.with_file_name("cache");
+            cache.save(&saved).unwrap();
+            cache = CompressionCache::load(&saved).unwrap();
+        }
+    }
+
+    // Bytes that don't look like anything, standing in for an executable.
+    fn junk(size : usize) -> Vec<u8> {
+        let mut state = 0x2545_f491u32;
+        (0..size).map(|_| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            (state >> 16) as u8
+        }).collect()
+    }
+
+    #[test]
+    fn embedded_archives_are_found_whatever_the_window_size() {
+        let files : &[(&str, &[u8])] = &[("readme.txt", b"hello"), ("sub/long_enough_name_to_cross_windows.txt", b"world")];
+        let key_table = xor_key_table(0x84);
+
+        for archive_type in [ArchiveType::NSA, ArchiveType::SAR] {
+            let mut bytes = junk(5000);
+            bytes.extend(build_with_key_table(archive_type, files, &CreateOptions::default(), &key_table).bytes());
+
+            let mut file = tempfile::tempfile().unwrap();
+            file.write_all(&bytes).unwrap();
+
+            // Windows smaller than the header have it read again on its own.
+            for window_size in [16, 64, 1 << 20] {
+                let found = Archive::find_embedded_archive_in_windows(&mut file, &key_table, window_size);
+                assert_eq!(found, Some((archive_type, 5000)), "window of {window_size}");
+            }
+
+            let archive = Archive::try_open_file_with_options(file, archive_type, 5000, &key_table, &ReadOptions::default()).unwrap();
+            assert_eq!(archive.index.entries.len(), 2);
+        }
+
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(&junk(5000)).unwrap();
+        assert!(Archive::find_embedded_archive_in_windows(&mut file, &key_table, 64).is_none());
+    }
+
+    #[test]
+    fn bzip2_entries_of_several_small_blocks_extract() {
+        let payload = junk(300_000);
+        let options = CreateOptions {
+            overrides : HashMap::from([(PathBuf::from("a.dat"), Compression::Bzip2)]),
+            bzip2_block_size : 1,
+            ..Default::default()
+        };
+        let built = build(ArchiveType::NSA, &[("a.dat", &payload)], &options);
+
+        let mut archive = open_bytes(&built.bytes(), ArchiveType::NSA, &ReadOptions::default()).unwrap();
+        assert_eq!(archive.try_extract(archive.index.entries[0].info()).unwrap(), payload);
+    }
+
+    #[test]
+    fn bzip2_archives_built_twice_with_a_block_size_are_identical() {
+        let payload = junk(250_000);
+        let files : &[(&str, &[u8])] = &[("a.dat", &payload), ("b.wav", b"not really a wav")];
+        let build_with_block_size = |bzip2_block_size| {
+            let overrides = HashMap::from([(PathBuf::from("a.dat"), Compression::Bzip2), (PathBuf::from("b.wav"), Compression::Bzip2)]);
+            build(ArchiveType::NSA, files, &CreateOptions { overrides, bzip2_block_size, ..Default::default() }).bytes()
+        };
+
+        for block_size in [1, 9] {
+            assert_eq!(build_with_block_size(block_size), build_with_block_size(block_size), "block size {block_size}");
+        }
+        // The payload spans several 100k blocks at size 1 but only one at size 9, so the block size does show.
+        assert_ne!(build_with_block_size(1), build_with_block_size(9));
+    }
+
+    #[test]
+    fn bzip2_decodes_to_what_was_encoded() {
+        // Bzip2's bytes depend on libbzip2's version, so only the round trip is checked.
+        for data in [Vec::new(), b"hello".to_vec(), b"repeated ".repeat(5000), junk(300_000)] {
+            for block_size in [1, 9] {
+                let compressed = compress_bz2_with_block_size(&data, block_size);
+                assert_eq!(decompress_entry(compressed, Compression::Bzip2, None).unwrap(), data);
+            }
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.txt");
+        std::fs::write(&path, b"verify me ".repeat(100)).unwrap();
+        Archive::verify_roundtrip(&path).unwrap();
+    }
+
+    #[test]
+    fn uncompressed_archives_are_byte_exact() {
+        let files : &[(&str, &[u8])] = &[("a.txt", b"hello"), ("sub/b", b"")];
+
+        // Count, data offset, then each name with its offset and size, and for NSA its compression byte before them
+        // and decompressed size after.
+        let mut sar = vec![0, 2, 0, 0, 0, 34];
+        sar.extend_from_slice(b"a.txt\0\0\0\0\0\0\0\0\x05");
+        sar.extend_from_slice(b"sub/b\0\0\0\0\x05\0\0\0\0");
+        sar.extend_from_slice(b"hello");
+        assert_eq!(build(ArchiveType::SAR, files, &CreateOptions::default()).bytes(), sar);
+
+        let mut nsa = vec![0, 2, 0, 0, 0, 44];
+        nsa.extend_from_slice(b"a.txt\0\0\0\0\0\0\0\0\0\x05\0\0\0\x05");
+        nsa.extend_from_slice(b"sub/b\0\0\0\0\0\x05\0\0\0\0\0\0\0\0");
+        nsa.extend_from_slice(b"hello");
+        assert_eq!(build(ArchiveType::NSA, files, &CreateOptions::default()).bytes(), nsa);
+    }
+
+    #[test]
+    fn offsets_past_4_gib_are_an_error() {
+        let files : &[(&str, &[u8])] = &[("a.txt", b"hello")];
+
+        // Each entry's offset field, with the entry's 5 bytes after it no longer fitting in a u32.
+        for (archive_type, field) in [(ArchiveType::SAR, 6 + 6), (ArchiveType::NSA, 6 + 6 + 1)] {
+            let mut bytes = build(archive_type, files, &CreateOptions::default()).bytes();
+            bytes[field..field + 4].copy_from_slice(&(u32::MAX - 2).to_be_bytes());
+
+            let result = open_bytes(&bytes, archive_type, &ReadOptions::default());
+            assert!(matches!(result, Err(ArchiveError::OffsetOverflow)), "{archive_type:?}");
+        }
+
+        // NS2 entries follow one another, so it's their sizes that add up past u32::MAX.
+        let mut ns2 = 19u32.to_le_bytes().to_vec();
+        for name in ["\"a\"", "\"b\""] {
+            ns2.extend_from_slice(name.as_bytes());
+            ns2.extend_from_slice(&(u32::MAX - 2).to_le_bytes());
+        }
+        ns2.extend_from_slice(b"e");
+        let result = open_bytes(&ns2, ArchiveType::NS2, &ReadOptions::default());
+        assert!(matches!(result, Err(ArchiveError::OffsetOverflow)));
+
+        assert_eq!(archive_position(&[u32::MAX - 5, 5]).unwrap(), u32::MAX);
+        assert!(archive_u32(u32::MAX as usize + 1).is_err());
+    }
+
+    #[test]
+    fn unknown_compression_bytes_only_fail_their_entry() {
+        let files : &[(&str, &[u8])] = &[("a.txt", b"hello"), ("b.txt", b"world")];
+        let mut bytes = build(ArchiveType::NSA, files, &CreateOptions::default()).bytes();
+        bytes[6 + "a.txt".len() + 1] = 3;
+
+        let mut archive = open_bytes(&bytes, ArchiveType::NSA, &ReadOptions::default()).unwrap();
+        assert!(matches!(archive.index.entries[0].compression, Compression::Unknown(3)));
+
+        let result = archive.try_extract(archive.index.entries[0].info());
+        assert!(matches!(result, Err(ArchiveError::UnknownCompression { compression : 3 })));
+        assert_eq!(archive.try_extract(archive.index.entries[1].info()).unwrap(), b"world");
+    }
+
+    #[test]
+    fn sar_entries_take_their_compression_from_their_name() {
+        let payload = b"compressed before it was archived ".repeat(20);
+        let nbz = compress_bz2(&payload);
+        let files : &[(&str, &[u8])] = &[("voice.nbz", &nbz), ("a.txt", b"hello")];
+
+        let mut archive = open_bytes(&build(ArchiveType::SAR, files, &CreateOptions::default()).bytes(), ArchiveType::SAR, &ReadOptions::default()).unwrap();
+        let entries = &archive.index.entries;
+        assert!(matches!(entries[0].compression, Compression::Bzip2));
+        assert_eq!((entries[0].size, entries[0].decompressed_size()), (nbz.len(), None));
+        assert!(matches!(entries[1].compression, Compression::None));
+        assert_eq!(entries[1].decompressed_size(), Some(5));
+
+        assert_eq!(archive.try_extract(archive.index.entries[0].info()).unwrap(), payload);
+        assert_eq!(archive.try_decompressed_size(archive.index.entries[0].info()).unwrap(), payload.len());
+        assert_eq!(archive.try_extract(archive.index.entries[1].info()).unwrap(), b"hello");
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd_entries_round_trip() {
+        let payload = b"zstd me ".repeat(500);
+        let options = CreateOptions { zstd : true, ..Default::default() };
+        let bytes = build(ArchiveType::NSA, &[("a.txt", &payload)], &options).bytes();
+        assert_eq!(bytes[6 + "a.txt".len() + 1], ZSTD_BYTE);
+
+        let mut archive = open_bytes(&bytes, ArchiveType::NSA, &ReadOptions::default()).unwrap();
+        let entry = &archive.index.entries[0];
+        assert!(matches!(entry.compression, Compression::Zstd));
+        assert!(entry.size < payload.len());
+        assert_eq!(archive.try_extract(archive.index.entries[0].info()).unwrap(), payload);
+    }
+
+    #[cfg(not(feature = "zstd"))]
+    #[test]
+    fn zstd_needs_its_feature() {
+        assert!(matches!(compress_zstd(b"hello"), Err(ArchiveError::ZstdDisabled)));
+        assert!(matches!(decompress_zstd(b"hello"), Err(ArchiveError::ZstdDisabled)));
+    }
+
+    #[test]
+    fn spb_entries_record_the_size_of_their_bmp() {
+        let pixel_buffer = (0..5 * 7).map(|i| [i as u8, (i * 3) as u8, 200]).collect();
+        let bmp = decode_spb(encode_spb(Image { pixel_buffer, width : 5, height : 7 }).unwrap()).unwrap();
+        let options = CreateOptions { spb : true, ..Default::default() };
+        let bytes = build(ArchiveType::NSA, &[("bg.bmp", &bmp)], &options).bytes();
+
+        // The entry's compression byte, then its offset, size and decompressed size.
+        let entry = 6 + "bg.bmp".len() + 1;
+        assert_eq!(bytes[entry], 1);
+        assert_eq!(bytes[entry + 9..entry + 13], (bmp.len() as u32).to_be_bytes());
+
+        let mut archive = open_bytes(&bytes, ArchiveType::NSA, &ReadOptions::default()).unwrap();
+        assert!(archive.index.entries[0].size < bmp.len());
+        assert_eq!(archive.try_decompressed_size(archive.index.entries[0].info()).unwrap(), bmp.len());
+        assert_eq!(archive.try_extract(archive.index.entries[0].info()).unwrap(), bmp);
+    }
+
+    #[test]
+    fn spb_entries_can_be_edited_as_bmps() {
+        // Pixel 7 stands out from the rest, so taking it out of the image makes it encode smaller and fit in place.
+        let pixel_buffer = (0..6 * 4).map(|i| if i == 7 { [3, 250, 9] } else { [i as u8 * 10, 100, 255 - i as u8] }).collect();
+        let bmp = decode_spb(encode_spb(Image { pixel_buffer, width : 6, height : 4 }).unwrap()).unwrap();
+        let options = CreateOptions { spb : true, ..Default::default() };
+        let built = build(ArchiveType::NSA, &[("bg.bmp", &bmp), ("after.txt", b"untouched")], &options);
+
+        let file = std::fs::OpenOptions::new().read(true).write(true).open(&built.path).unwrap();
+        let mut archive = Archive::try_open_file_with_options(file, ArchiveType::NSA, 0, &KeyTable::identity(), &ReadOptions::default()).unwrap();
+        let mut image = Image::from_bmp_bytes(&archive.extract_spb_as_bmp("bg.bmp").unwrap()).unwrap();
+        assert_eq!(image.pixel_buffer[7], [3, 250, 9]);
+
+        image.pixel_buffer[7] = [70, 100, 248];
+        let edited = decode_spb(encode_spb(image).unwrap()).unwrap();
+        assert_ne!(edited, bmp);
+        archive.replace_with_spb("bg.bmp", &edited).unwrap();
+        drop(archive);
+
+        let mut archive = Archive::try_open_file_with_options(File::open(&built.path).unwrap(), ArchiveType::NSA, 0, &KeyTable::identity(), &ReadOptions::default()).unwrap();
+        assert_eq!(archive.extract_spb_as_bmp("bg.bmp").unwrap(), edited);
+        assert_eq!(archive.extract_by_name("after.txt").unwrap(), b"untouched");
+        assert!(matches!(archive.extract_spb_as_bmp("after.txt"), Err(ArchiveError::NotSpb { .. })));
+    }
+
+    #[test]
+    fn content_hashes_ignore_how_archives_are_packed() {
+        let script = b"; a script long enough to compress\n".repeat(20);
+        let files : &[(&str, &[u8])] = &[("a.txt", &script), ("sub/b.dat", &[9; 500])];
+        let hash = |archive_type, files : &[(&str, &[u8])], options : &CreateOptions| {
+            let built = build(archive_type, files, options);
+            archive_content_hash(&built.path, archive_type, 0, &KeyTable::identity())
+        };
+
+        let stored = hash(ArchiveType::NSA, files, &CreateOptions::default());
+        let mut overrides = HashMap::new();
+        overrides.insert(PathBuf::from("a.txt"), Compression::Lzss);
+        overrides.insert(PathBuf::from("sub/b.dat"), Compression::Bzip2);
+        let compressed = hash(ArchiveType::NSA, files, &CreateOptions { overrides, ..Default::default() });
+        let reordered = hash(ArchiveType::SAR, &[files[1], files[0]], &CreateOptions::default());
+        assert_eq!(compressed, stored);
+        assert_eq!(reordered, stored);
+
+        let changed = hash(ArchiveType::NSA, &[files[0], ("sub/b.dat", &[9; 499])], &CreateOptions::default());
+        let renamed = hash(ArchiveType::NSA, &[files[0], ("sub/c.dat", &[9; 500])], &CreateOptions::default());
+        assert_ne!(changed, stored);
+        assert_ne!(renamed, stored);
+    }
+
+    #[test]
+    fn archives_written_with_a_key_table_read_back_with_it() {
+        // Unlike an XOR table this isn't its own inverse, so writing has to undo what reading does.
+        let mut bytes = [0; 256];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = (i as u8).wrapping_mul(5).wrapping_add(3);
+        }
+        let key_table = KeyTable::from_bytes(bytes).unwrap();
+        let files : &[(&str, &[u8])] = &[("a.txt", b"plain text"), ("sub/b.dat", &[0, 1, 2, 255])];
+
+        for archive_type in [ArchiveType::SAR, ArchiveType::NSA, ArchiveType::NS2] {
+            let built = build_with_key_table(archive_type, files, &CreateOptions::default(), &key_table);
+            let raw = built.bytes();
+            assert!(!raw.windows(10).any(|window| window == b"plain text"), "{archive_type:?}");
+
+            let file = File::open(&built.path).unwrap();
+            let mut archive = Archive::try_open_file_with_options(file, archive_type, 0, &key_table, &ReadOptions::default()).unwrap();
+            assert_eq!(archive.extract_by_name("a.txt").unwrap(), b"plain text", "{archive_type:?}");
+            assert_eq!(archive.extract_by_name("sub/b.dat").unwrap(), [0, 1, 2, 255], "{archive_type:?}");
+        }
+    }
+
+    #[test]
+    fn duplicate_names_keep_every_entry_reachable() {
+        let files : &[(&str, &[u8])] = &[("a.txt", b"first"), ("b.txt", b"second"), ("c.txt", b"third")];
+        let mut bytes = build(ArchiveType::SAR, files, &CreateOptions::default()).bytes();
+        bytes[6 + 14] = b'a'; // The second entry's name.
+
+        let mut archive = open_bytes(&bytes, ArchiveType::SAR, &ReadOptions::default()).unwrap();
+        assert_eq!(archive.index.duplicate_names(), vec!["a.txt".to_string()]);
+        assert_eq!(archive.extract_by_name("a.txt").unwrap(), b"first");
+        assert_eq!(archive.try_extract(archive.index.entries[1].info()).unwrap(), b"second");
+        assert_eq!(archive.extract_by_name("c.txt").unwrap(), b"third");
+        assert!(archive.extract_by_name("b.txt").is_none());
+    }
+
+    // An NS2 archive whose header is header_fields after the data offset, which is worked out, followed by data.
+    fn ns2_fixture(header_fields : &[&[u8]], data : &[u8]) -> Vec<u8> {
+        let header : Vec<u8> = header_fields.concat();
+        let mut ns2 = (4 + header.len() as u32).to_le_bytes().to_vec();
+        ns2.extend(header);
+        ns2.extend_from_slice(data);
+        ns2
+    }
+
+    #[test]
+    fn ns2_headers_without_their_end_byte_are_read() {
+        let ns2 = ns2_fixture(&[b"\"a.txt\"", &5u32.to_le_bytes(), b"\"b.txt\"", &2u32.to_le_bytes()], b"hellohi");
+        let mut archive = open_bytes(&ns2, ArchiveType::NS2, &ReadOptions::default()).unwrap();
+        assert_eq!(archive.extract_by_name("a.txt").unwrap(), b"hello");
+        assert_eq!(archive.extract_by_name("b.txt").unwrap(), b"hi");
+
+        // The file ends right after the last size.
+        let ns2 = ns2_fixture(&[b"\"empty.txt\"", &0u32.to_le_bytes()], b"");
+        let mut archive = open_bytes(&ns2, ArchiveType::NS2, &ReadOptions::default()).unwrap();
+        assert_eq!(archive.extract_by_name("empty.txt").unwrap(), b"");
+    }
+
+    #[test]
+    fn ns2_headers_with_whitespace_between_fields_are_read() {
+        // The second size starts with a byte that looks like a space.
+        let data = [b"hello".as_slice(), &[7; 32]].concat();
+        let header : &[&[u8]] = &[b" \"a.txt\" ", &5u32.to_le_bytes(), b"\r\n\"b.txt\"\t", &32u32.to_le_bytes(), b"\r\ne"];
+        let mut archive = open_bytes(&ns2_fixture(header, &data), ArchiveType::NS2, &ReadOptions::default()).unwrap();
+
+        let entries : Vec<_> = archive.index.entries.iter().map(|entry| (entry.name.as_str(), entry.size)).collect();
+        assert_eq!(entries, vec![("a.txt", 5), ("b.txt", 32)]);
+        assert_eq!(archive.extract_by_name("a.txt").unwrap(), b"hello");
+        assert_eq!(archive.extract_by_name("b.txt").unwrap(), [7; 32]);
+    }
+
+    #[test]
+    fn archives_behind_a_bom_or_whitespace_open() {
+        let files : &[(&str, &[u8])] = &[("a.txt", b"hello"), ("b.txt", b"world")];
+
+        for archive_type in [ArchiveType::NSA, ArchiveType::SAR] {
+            let archive = build(archive_type, files, &CreateOptions::default()).bytes();
+
+            for preamble in [b"".as_slice(), b"\xEF\xBB\xBF", b"\r\n ", b"\xEF\xBB\xBF\n"] {
+                let mut file = tempfile::tempfile().unwrap();
+                file.write_all(&[preamble, &archive].concat()).unwrap();
+
+                let mut opened = Archive::try_open_auto(file, archive_type, &KeyTable::identity(), &ReadOptions::default()).unwrap();
+                assert_eq!(opened.extract_by_name("a.txt").unwrap(), b"hello", "{archive_type:?} behind {preamble:?}");
+                assert_eq!(opened.extract_by_name("b.txt").unwrap(), b"world");
+            }
+        }
+    }
+
+    #[test]
+    fn compacting_drops_the_slack_left_by_smaller_replacements() {
+        let noisy = Image { pixel_buffer : junk(16 * 16 * 3).chunks(3).map(|pixel| [pixel[0], pixel[1], pixel[2]]).collect(), width : 16, height : 16 };
+        let flat = Image { pixel_buffer : vec![[40, 80, 120]; 16 * 16], width : 16, height : 16 };
+        let noisy = decode_spb(encode_spb(noisy).unwrap()).unwrap();
+        let flat = decode_spb(encode_spb(flat).unwrap()).unwrap();
+
+        let files : &[(&str, &[u8])] = &[("a.txt", b"hello"), ("bg.bmp", &noisy), ("z.txt", b"world")];
+        let options = CreateOptions { spb : true, ..Default::default() };
+        let mut archive = open_bytes(&build(ArchiveType::NSA, files, &options).bytes(), ArchiveType::NSA, &ReadOptions::default()).unwrap();
+        archive.replace_with_spb("bg.bmp", &flat).unwrap();
+        let before = archive.try_extract_all_to_map().unwrap();
+        assert_eq!(before["bg.bmp"], flat);
+
+        let mut out = tempfile::tempfile().unwrap();
+        Archive::compact(&mut archive, out.try_clone().unwrap()).unwrap();
+        assert!(out.metadata().unwrap().len() < archive.file.file.metadata().unwrap().len());
+
+        out.seek(SeekFrom::Start(0)).unwrap();
+        let mut compacted = Archive::try_open_file_with_options(out, ArchiveType::NSA, 0, &KeyTable::identity(), &ReadOptions::default()).unwrap();
+        assert_eq!(compacted.try_extract_all_to_map().unwrap(), before);
+        let names : Vec<_> = compacted.index.entries.iter().map(|entry| entry.name.as_str()).collect();
+        assert_eq!(names, vec!["a.txt", "bg.bmp", "z.txt"]);
+    }
+
+    #[test]
+    fn failed_atomic_writes_leave_the_original_untouched() {
+        let built = build(ArchiveType::NSA, &[("a.txt", b"hello")], &CreateOptions::default());
+        let original = built.bytes();
+        let directory = built.path.parent().unwrap();
+        let listing = || std::fs::read_dir(directory).unwrap().count();
+        let files = listing();
+
+        // Fails partway through writing.
+        let result = write_atomically(&built.path, |mut out| {
+            out.write_all(b"half an archive")?;
+            Err(ArchiveError::OffsetOverflow)
+        });
+        assert!(matches!(result, Err(ArchiveError::OffsetOverflow)));
+        assert_eq!(built.bytes(), original);
+        assert_eq!(listing(), files);
+
+        Archive::compact_file(&built.path, ArchiveType::NSA, 0, &KeyTable::identity()).unwrap();
+        assert_eq!(built.bytes(), original);
+        assert_eq!(listing(), files);
+    }
+
+    #[test]
+    fn extract_layouts_keep_or_flatten_directories() {
+        let files : &[(&str, &[u8])] = &[("a/bg.txt", b"a"), ("c/d/x.txt", b"x"), ("y.txt", b"y")];
+        let archive = open_bytes(&build(ArchiveType::SAR, files, &CreateOptions::default()).bytes(), ArchiveType::SAR, &ReadOptions::default()).unwrap();
+        let out = Path::new("out");
+        let paths = |layout : ExtractLayout| -> Vec<PathBuf> {
+            archive.index.entries.iter().map(|entry| sanitize_entry_path(layout.entry_path(&entry.name), out).unwrap()).collect()
+        };
+
+        assert_eq!(paths(ExtractLayout::PreserveTree), vec![out.join("a").join("bg.txt"), out.join("c").join("d").join("x.txt"), out.join("y.txt")]);
+        assert_eq!(paths(ExtractLayout::Flatten), vec![out.join("bg.txt"), out.join("x.txt"), out.join("y.txt")]);
+
+        // Games made on Windows separate directories with backslashes.
+        assert_eq!(sanitize_entry_path(ExtractLayout::PreserveTree.entry_path("a\\bg.txt"), out), Some(out.join("a").join("bg.txt")));
+        assert_eq!(ExtractLayout::Flatten.entry_path("a\\bg.txt"), "bg.txt");
+    }
+
+    #[test]
+    fn saved_indexes_extract_like_parsed_ones() {
+        let files : &[(&str, &[u8])] = &[("a.txt", &[b'a'; 1000]), ("sub/b.dat", b"stored"), ("c.txt", b"")];
+        let options = CreateOptions { overrides : HashMap::from([(PathBuf::from("a.txt"), Compression::Lzss)]), ..Default::default() };
+        let built = build(ArchiveType::NSA, files, &options);
+
+        let mut parsed = Archive::try_open_file_with_options(File::open(&built.path).unwrap(), ArchiveType::NSA, 0, &KeyTable::identity(), &ReadOptions::default()).unwrap();
+        let saved = parsed.index.serialize();
+        let index = ArchiveIndex::deserialize(&saved).unwrap();
+
+        assert_eq!(index.offset, parsed.index.offset);
+        for (loaded, entry) in index.entries.iter().zip(&parsed.index.entries) {
+            assert_eq!((&loaded.name, loaded.offset, loaded.size, loaded.decompressed_size()), (&entry.name, entry.offset, entry.size, entry.decompressed_size()));
+        }
+
+        let mut loaded = Archive::from_index(File::open(&built.path).unwrap(), index, ArchiveType::NSA, &KeyTable::identity());
+        assert_eq!(loaded.try_extract_all_to_map().unwrap(), parsed.try_extract_all_to_map().unwrap());
+        assert_eq!(loaded.extract_by_name("a.txt").unwrap(), [b'a'; 1000]);
+
+        assert!(ArchiveIndex::deserialize(&saved[..saved.len() - 1]).is_err());
+        assert!(ArchiveIndex::deserialize(b"not an index").is_err());
+    }
+
+    #[test]
+    fn archives_are_verified_against_their_directory() {
+        let files : &[(&str, &[u8])] = &[("a.txt", b"hello"), ("sub/b.txt", b"world"), ("c.txt", b"gone")];
+        let built = build(ArchiveType::NSA, files, &CreateOptions { lzss : true, ..Default::default() });
+        let mut archive = Archive::try_open_file_with_options(File::open(&built.path).unwrap(), ArchiveType::NSA, 0, &KeyTable::identity(), &ReadOptions::default()).unwrap();
+        assert!(archive.verify_against_dir(&built.source).is_empty());
+
+        std::fs::write(built.source.join("sub").join("b.txt"), b"World").unwrap();
+        std::fs::remove_file(built.source.join("c.txt")).unwrap();
+        std::fs::write(built.source.join("new.txt"), b"extra").unwrap();
+
+        let found : Vec<String> = archive.verify_against_dir(&built.source).iter().map(|discrepancy| match discrepancy {
+            Discrepancy::Differs { name } => format!("differs {name}"),
+            Discrepancy::Missing { name } => format!("missing {name}"),
+            Discrepancy::Extra { path } => format!("extra {path}"),
+            Discrepancy::Unreadable { name, .. } => format!("unreadable {name}")
+        }).collect();
+        assert_eq!(found, vec!["differs sub/b.txt", "missing c.txt", "extra new.txt"]);
+    }
+
+    #[test]
+    fn header_bytes_run_up_to_the_data() {
+        let files : &[(&str, &[u8])] = &[("a.txt", b"hello"), ("sub/b.txt", b"world")];
+        let key_table = xor_key_table(0x84);
+
+        for archive_type in [ArchiveType::SAR, ArchiveType::NSA, ArchiveType::NS2] {
+            let built = build_with_key_table(archive_type, files, &CreateOptions::default(), &key_table);
+            let mut archive = Archive::try_open_file_with_options(File::open(&built.path).unwrap(), archive_type, 0, &key_table, &ReadOptions::default()).unwrap();
+
+            let header = archive.try_header_bytes().unwrap();
+            assert_eq!(header.len(), archive.index.offset, "{archive_type:?}");
+            assert_eq!(header.len(), built.bytes().len() - 10);
+
+            // The key table is undone, so the names show.
+            assert!(header.windows(5).any(|window| window == b"a.txt"), "{archive_type:?}");
+        }
+    }
+
+    #[test]
+    fn archives_extract_to_a_map_of_every_entry() {
+        let files : &[(&str, &[u8])] = &[("a.txt", &[b'a'; 1000]), ("sub/b.dat", b"stored"), ("c.txt", b"")];
+        let options = CreateOptions { overrides : HashMap::from([(PathBuf::from("a.txt"), Compression::Lzss)]), ..Default::default() };
+        let built = build(ArchiveType::NSA, files, &options);
+
+        let mut archive = open_bytes(&built.bytes(), ArchiveType::NSA, &ReadOptions::default()).unwrap();
+        let map = archive.try_extract_all_to_map().unwrap();
+        let expected : HashMap<String, Vec<u8>> = files.iter().map(|(name, data)| (name.to_string(), data.to_vec())).collect();
+        assert_eq!(map, expected);
+    }
+
+    #[test]
+    fn peeking_matches_the_start_of_a_full_extract() {
+        let text = b"RIFF peeked at, and then some more that doesn't matter".repeat(20);
+        let files : &[(&str, &[u8])] = &[("a.wav", &text), ("b.txt", &text), ("c.txt", &text), ("d.txt", b"ab")];
+        let overrides = HashMap::from([(PathBuf::from("a.wav"), Compression::Bzip2), (PathBuf::from("b.txt"), Compression::Lzss)]);
+        let built = build(ArchiveType::NSA, files, &CreateOptions { overrides, ..Default::default() });
+        let mut archive = open_bytes(&built.bytes(), ArchiveType::NSA, &ReadOptions::default()).unwrap();
+
+        for (i, (name, _)) in files.iter().enumerate() {
+            let full = archive.try_extract(archive.index.entries[i].info()).unwrap();
+            for n in [0, 4, 100] {
+                let peeked = archive.try_peek(archive.index.entries[i].info(), n).unwrap();
+                assert_eq!(peeked, full[..n.min(full.len())], "{n} bytes of {name}");
+            }
+        }
+        assert_eq!(archive.peek(archive.index.entries[0].info(), 4), b"RIFF");
+    }
+
+    #[test]
+    fn lzss_decodes_as_onscripter_does() {
+        // Written out by hand as ONScripter's decodeLZSS reads it, most significant bit first. A 1 is followed by a
+        // literal byte, a 0 by an 8 bit position in the ring buffer and a 4 bit length, copying length + 2 bytes. Writes
+        // start at 256 - 17 = 239 in a zeroed buffer.
+        //   1 'a', 1 'b', 1 'c'         abc
+        //   0 239 4                     abcabc, read back from the start as it's written
+        //   0 0 1                       three bytes that were never written, so zeroes
+        let stream = vec![0xB0, 0xD8, 0xAC, 0x6E, 0xF4, 0x00, 0x08];
+        let expected = b"abcabcabc\0\0\0";
+
+        assert_eq!(decompress_entry(stream.clone(), Compression::Lzss, Some(12)).unwrap(), expected);
+        assert_eq!(decompress_entry(stream, Compression::Lzss, Some(4)).unwrap(), b"abca");
+
+        let data = b"ab ab ab ab, then the same again: ab ab ab ab".repeat(10);
+        let (compression, compressed) = compress_entry(&data, Compression::Lzss, &CreateOptions::default()).unwrap();
+        assert!(matches!(compression, Compression::Lzss));
+        assert_eq!(decompress_entry(compressed, Compression::Lzss, Some(data.len())).unwrap(), data);
+    }
+
+    #[test]
+    fn decompressed_sizes_are_written_as_configured() {
+        let text = b"compress me, compress me, compress me".repeat(30);
+        let files : &[(&str, &[u8])] = &[("a.txt", &text), ("b.txt", &text), ("c.txt", b"stored")];
+        let overrides = HashMap::from([(PathBuf::from("a.txt"), Compression::Lzss), (PathBuf::from("b.txt"), Compression::Bzip2)]);
+
+        for (decompressed_size, written) in [(DecompressedSize::Actual, [text.len(), text.len(), 6]), (DecompressedSize::Zero, [0; 3])] {
+            let options = CreateOptions { overrides : overrides.clone(), decompressed_size, ..Default::default() };
+            let bytes = build(ArchiveType::NSA, files, &options).bytes();
+
+            // Each entry's decompressed size is the last field of its part of the header.
+            let mut field = 6;
+            for ((name, _), written) in files.iter().zip(written) {
+                field += name.len() + 1 + 9;
+                assert_eq!(bytes[field..field + 4], (written as u32).to_be_bytes(), "{name} with {decompressed_size:?}");
+                field += 4;
+            }
+
+            let mut archive = open_bytes(&bytes, ArchiveType::NSA, &ReadOptions::default()).unwrap();
+            assert_eq!(archive.extract_by_name("a.txt").unwrap(), text);
+            assert_eq!(archive.extract_by_name("b.txt").unwrap(), text);
+            assert_eq!(archive.extract_by_name("c.txt").unwrap(), b"stored");
+        }
+    }
+
+    #[test]
+    fn archive_sequences_stop_at_the_first_gap() {
+        let nsa = archive_sequence_names("arc", &ArchiveType::NSA);
+        assert_eq!(nsa.len(), 10);
+        assert_eq!((nsa[0].as_str(), nsa[1].as_str(), nsa[9].as_str()), ("arc.nsa", "arc1.nsa", "arc9.nsa"));
+        assert_eq!(archive_sequence_names("arc", &ArchiveType::SAR), vec!["arc.sar".to_string()]);
+        let ns2 = archive_sequence_names("", &ArchiveType::NS2);
+        assert_eq!((ns2.len(), ns2[0].as_str(), ns2[99].as_str()), (100, "00.ns2", "99.ns2"));
+
+        let dir = tempfile::tempdir().unwrap();
+        let found = |archive_type : &ArchiveType, base : &str| -> Vec<String> {
+            find_archive_sequence(dir.path(), base, archive_type).unwrap().iter()
+                .map(|path| path.file_name().unwrap().to_str().unwrap().to_string())
+                .collect()
+        };
+
+        // arc1.nsa is missing, so arc2.nsa isn't read.
+        for name in ["ARC.NSA", "arc2.nsa", "arc.sar", "00.ns2", "01.ns2", "03.ns2"] {
+            std::fs::write(dir.path().join(name), b"").unwrap();
+        }
+        assert_eq!(found(&ArchiveType::NSA, "arc"), vec!["ARC.NSA"]);
+        assert_eq!(found(&ArchiveType::SAR, "arc"), vec!["arc.sar"]);
+        assert_eq!(found(&ArchiveType::NS2, ""), vec!["00.ns2", "01.ns2"]);
+
+        std::fs::write(dir.path().join("arc1.nsa"), b"").unwrap();
+        assert_eq!(found(&ArchiveType::NSA, "arc"), vec!["ARC.NSA", "arc1.nsa", "arc2.nsa"]);
+
+        // Without the first there's nothing at all.
+        std::fs::remove_file(dir.path().join("00.ns2")).unwrap();
+        assert!(found(&ArchiveType::NS2, "").is_empty());
+    }
+
+    #[test]
+    fn sar_archives_named_nsa_are_read_as_sar() {
+        let files : &[(&str, &[u8])] = &[("a.txt", b"hello"), ("sub/b.txt", b"world")];
+
+        for archive_type in [ArchiveType::SAR, ArchiveType::NSA] {
+            let bytes = build(archive_type, files, &CreateOptions::default()).bytes();
+            let mut archive = open_bytes(&bytes, ArchiveType::NSA, &ReadOptions::default()).unwrap();
+
+            assert_eq!(archive.archive_type, archive_type);
+            assert_eq!(archive.extract_by_name("a.txt").unwrap(), b"hello");
+            assert_eq!(archive.extract_by_name("sub/b.txt").unwrap(), b"world");
+        }
+    }
+
+    #[test]
+    fn entry_paths_stay_within_the_output_directory() {
+        let out_dir = Path::new("out");
+        assert_eq!(sanitize_entry_path("bg\\title.bmp", out_dir), Some(out_dir.join("bg").join("title.bmp")));
+        assert_eq!(sanitize_entry_path("bg/./../voice//a.wav", out_dir), Some(out_dir.join("voice").join("a.wav")));
+
+        for name in ["../evil.txt", "..\\..\\etc\\passwd", "bg/../../evil.txt", "/etc/passwd", "\\evil.txt", "C:\\evil.txt", "bg/..", ""] {
+            assert_eq!(sanitize_entry_path(name, out_dir), None, "{name}");
+        }
+    }
+
+    // The start of a WAV whose fmt chunk has the format tag, after the chunks given, and then its data chunk.
+    fn wav_header(format : u16, chunks_before : &[(&[u8; 4], &[u8])]) -> Vec<u8> {
+        let mut fmt = [format.to_le_bytes(), 2u16.to_le_bytes()].concat();
+        fmt.extend(22050u32.to_le_bytes());
+        fmt.extend((22050u32 * 4).to_le_bytes());
+        fmt.extend([4u16.to_le_bytes(), 16u16.to_le_bytes()].concat());
+        if format == 0xFFFE {
+            fmt.extend([22u16.to_le_bytes(), 16u16.to_le_bytes()].concat());
+            fmt.extend(3u32.to_le_bytes());
+            fmt.extend(1u16.to_le_bytes()); // The subformat GUID starts with the format it stands for.
+            fmt.extend([0, 0, 0, 0, 0x10, 0, 0x80, 0, 0, 0xAA, 0, 0x38, 0x9B, 0x71]);
+        }
+
+        let mut bytes = b"RIFF\0\0\0\0WAVE".to_vec();
+        for (id, data) in chunks_before.iter().chain(&[(b"fmt ", fmt.as_slice()), (b"data", &[0; 8])]) {
+            bytes.extend(*id);
+            bytes.extend((data.len() as u32).to_le_bytes());
+            bytes.extend(*data);
+            if data.len() % 2 == 1 {
+                bytes.push(0);
+            }
+        }
+        bytes
+    }
+
+    #[test]
+    fn only_wavs_of_uncompressed_samples_are_bzip2ed() {
+        const ADPCM : u16 = 2;
+        let pcm = wav_header(1, &[]);
+        let adpcm = wav_header(ADPCM, &[]);
+
+        assert!(wav_is_pcm(&pcm));
+        assert!(wav_is_pcm(&wav_header(3, &[])));
+        assert!(wav_is_pcm(&wav_header(0xFFFE, &[])));
+        assert!(wav_is_pcm(&wav_header(1, &[(b"LIST", b"odd")])));
+        assert!(!wav_is_pcm(&adpcm));
+        assert!(!wav_is_pcm(&wav_header(0x55, &[]))); // MP3
+        assert!(!wav_is_pcm(&pcm[..20]));
+        assert!(!wav_is_pcm(b"RIFF\0\0\0\0AVI "));
+
+        let options = CreateOptions { bzip2 : true, ..Default::default() };
+        assert!(matches!(encoding_to_use(Path::new("voice.wav"), &pcm, &options).compression, Compression::Bzip2));
+        assert!(matches!(encoding_to_use(Path::new("voice.wav"), &adpcm, &options).compression, Compression::None));
+    }
+
+    #[test]
+    fn already_compressed_data_is_stored_as_is() {
+        let png = [b"\x89PNG\r\n\x1a\n".as_slice(), &[0; 300]].concat();
+        let ogg = [b"OggS".as_slice(), &[0; 300]].concat();
+        let pcm = [wav_header(1, &[]), vec![0; 300]].concat();
+        let files : &[(&str, &[u8])] = &[("cg.png", &png), ("bgm.wav", &ogg), ("voice.wav", &pcm)];
+        let options = CreateOptions { bzip2 : true, lzss : true, ..Default::default() };
+        let bytes = build(ArchiveType::NSA, files, &options).bytes();
+
+        let mut archive = open_bytes(&bytes, ArchiveType::NSA, &ReadOptions::default()).unwrap();
+        let compressions : Vec<Compression> = archive.index.entries.iter().map(|entry| entry.compression).collect();
+        assert!(matches!(compressions[..], [Compression::None, Compression::None, Compression::Bzip2]));
+        assert_eq!(archive.index.entries[0].size, png.len());
+        assert_eq!(archive.extract_by_name("cg.png").unwrap(), png);
+        assert_eq!(archive.extract_by_name("voice.wav").unwrap(), pcm);
+    }
+
+    #[test]
+    fn extensions_are_tallied_ignoring_case() {
+        let files : &[(&str, &[u8])] = &[("bg/a.BMP", &[0; 10]), ("b.bmp", &[0; 5]), ("voice.d/1.wav", &[0; 7]), ("readme", b"hi"), ("0.txt", b"")];
+        let archive = open_bytes(&build(ArchiveType::SAR, files, &CreateOptions::default()).bytes(), ArchiveType::SAR, &ReadOptions::default()).unwrap();
+
+        let histogram : Vec<(String, (usize, usize))> = archive.index.extension_histogram().into_iter().collect();
+        let expected = [("", (1, 2)), ("bmp", (2, 15)), ("txt", (1, 0)), ("wav", (1, 7))].map(|(extension, tally)| (extension.to_string(), tally));
+        assert_eq!(histogram, expected);
+    }
+
+    #[test]
+    fn extracting_into_a_reused_buffer_matches_extract() {
+        let pixel_buffer = (0..4 * 3).map(|i| [i as u8, 50, 90]).collect();
+        let bmp = decode_spb(encode_spb(Image { pixel_buffer, width : 4, height : 3 }).unwrap()).unwrap();
+        let text = b"extract me into a buffer ".repeat(40);
+        let files : &[(&str, &[u8])] = &[("bg.bmp", &bmp), ("a.txt", &text), ("b.dat", &text[..100]), ("c.dat", b""), ("d.txt", b"short")];
+        let overrides = HashMap::from([(PathBuf::from("a.txt"), Compression::Lzss), (PathBuf::from("b.dat"), Compression::Bzip2)]);
+        let options = CreateOptions { spb : true, overrides, ..Default::default() };
+        let mut archive = open_bytes(&build(ArchiveType::NSA, files, &options).bytes(), ArchiveType::NSA, &ReadOptions::default()).unwrap();
+
+        // Starting with leftovers in the buffer, as there would be from an earlier entry.
+        let mut buffer = vec![0xEE; 2000];
+        for (i, (name, data)) in files.iter().enumerate() {
+            archive.try_extract_into(archive.index.entries[i].info(), &mut buffer).unwrap();
+            assert_eq!(buffer, archive.try_extract(archive.index.entries[i].info()).unwrap(), "{name}");
+            assert_eq!(buffer, *data);
+        }
+    }
+
+    #[test]
+    fn names_windows_cant_write_are_made_safe() {
+        assert_eq!(safe_os_filename("bg\\title.bmp"), ("bg\\title.bmp".to_string(), false));
+        assert_eq!(safe_os_filename("what?.txt"), ("what_.txt".to_string(), true));
+        assert_eq!(safe_os_filename("a<b>c:d\"e|f*g"), ("a_b_c_d_e_f_g".to_string(), true));
+        assert_eq!(safe_os_filename("voice\\nul.wav"), ("voice\\_nul.wav".to_string(), true));
+        assert_eq!(safe_os_filename("COM1"), ("_COM1".to_string(), true));
+        assert_eq!(safe_os_filename("console.txt"), ("console.txt".to_string(), false));
+        assert_eq!(safe_os_filename("dir. /end ."), ("dir__/end__".to_string(), true));
+        assert_eq!(safe_os_filename("../tab\there"), ("../tab_here".to_string(), true));
+    }
+
+    #[test]
+    fn raw_bzip2_entries_decompress_to_what_extract_gives() {
+        let text = b"raw and compressed ".repeat(50);
+        let options = CreateOptions { overrides : HashMap::from([(PathBuf::from("a.txt"), Compression::Bzip2)]), ..Default::default() };
+        let key_table = xor_key_table(0x84);
+        let built = build_with_key_table(ArchiveType::NSA, &[("a.txt", &text)], &options, &key_table);
+
+        let file = File::open(&built.path).unwrap();
+        let mut archive = Archive::try_open_file_with_options(file, ArchiveType::NSA, 0, &key_table, &ReadOptions::default()).unwrap();
+        let (compression, raw) = archive.try_extract_raw(archive.index.entries[0].info()).unwrap();
+        assert!(matches!(compression, Compression::Bzip2));
+        assert_eq!(raw.len(), archive.index.entries[0].size);
+        assert_eq!(&raw[4..7], b"BZh");
+
+        let extracted = archive.try_extract(archive.index.entries[0].info()).unwrap();
+        assert_eq!(decompress_entry(raw, compression, None).unwrap(), extracted);
+        assert_eq!(extracted, text);
+    }
+
+    #[test]
+    fn entries_after_an_offset_are_the_ones_whose_data_starts_there() {
+        let files : &[(&str, &[u8])] = &[("a.txt", b"first"), ("b.txt", b"second"), ("c.txt", b"third")];
+        let archive = open_bytes(&build(ArchiveType::NSA, files, &CreateOptions::default()).bytes(), ArchiveType::NSA, &ReadOptions::default()).unwrap();
+        let second = archive.index.entries[1].offset;
+
+        let names = |offset| archive.entries_after(offset).iter().map(|entry| entry.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names(0), ["a.txt", "b.txt", "c.txt"]);
+        assert_eq!(names(second), ["b.txt", "c.txt"]);
+        assert_eq!(names(second + 1), ["c.txt"]);
+        assert!(names(usize::MAX).is_empty());
+    }
+
+    #[test]
+    fn bzip2_entries_start_with_their_size() {
+        let payload = b"bzip2 me ".repeat(200);
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.txt"), &payload).unwrap();
+
+        let path = dir.path().join("arc.nsa");
+        let options = CreateOptions { overrides : HashMap::from([(PathBuf::from("a.txt"), Compression::Bzip2)]), ..Default::default() };
+        Archive::create_nsa_archive(File::create(&path).unwrap(), dir.path(), vec![PathBuf::from("a.txt")], &KeyTable::identity(), &options).unwrap();
+
+        // The original size as a big endian u32, then the bzip2 stream, which extract skips the size to read.
+        let mut archive = Archive::open_file(File::open(&path).unwrap(), ArchiveType::NSA, 0, &KeyTable::identity());
+        let (compression, stored) = archive.extract_raw(archive.index.entries[0].info());
+        assert!(matches!(compression, Compression::Bzip2));
+        assert_eq!(stored[0..4], (payload.len() as u32).to_be_bytes());
+        assert_eq!(&stored[4..7], b"BZh");
+
+        assert_eq!(archive.decompressed_size(archive.index.entries[0].info()), payload.len());
+        assert_eq!(archive.extract(archive.index.entries[0].info()), payload);
+    }
+
+    // Adds delta to every entry offset in an NSA's header.
+    fn shift_nsa_entry_offsets(bytes : &mut [u8], delta : u32) {
+        let count = u16::from_be_bytes([bytes[0], bytes[1]]);
+        let mut at = 6;
+        for _ in 0..count {
+            at += bytes[at..].iter().position(|&byte| byte == 0).unwrap() + 2;
+            let offset = u32::from_be_bytes(bytes[at..at + 4].try_into().unwrap());
+            bytes[at..at + 4].copy_from_slice(&(offset + delta).to_be_bytes());
+            at += 12;
+        }
+    }
+
+    #[test]
+    fn entry_offsets_from_the_start_of_the_archive_are_detected() {
+        let files : &[(&str, &[u8])] = &[("a.bmp", b"BM first"), ("b.wav", b"RIFF second")];
+        let mut bytes = build(ArchiveType::NSA, files, &CreateOptions::default()).bytes();
+        let data_offset = u32::from_be_bytes(bytes[2..6].try_into().unwrap());
+        shift_nsa_entry_offsets(&mut bytes, data_offset);
+
+        let mut archive = open_bytes(&bytes, ArchiveType::NSA, &ReadOptions::default()).unwrap();
+        for (i, (_, data)) in files.iter().enumerate() {
+            assert_eq!(archive.try_extract(archive.index.entries[i].info()).unwrap(), *data);
+        }
+    }
+
+    #[test]
+    fn truncated_archives_keep_offsets_from_the_start_of_the_data() {
+        let files : &[(&str, &[u8])] = &[("a.bmp", b"BM first"), ("b.wav", b"RIFF second")];
+        let mut bytes = build(ArchiveType::NSA, files, &CreateOptions::default()).bytes();
+        bytes.truncate(bytes.len() - 3);
+
+        let mut archive = open_bytes(&bytes, ArchiveType::NSA, &ReadOptions::default()).unwrap();
+        assert_eq!(&archive.try_extract(archive.index.entries[0].info()).unwrap()[0..2], b"BM");
+    }
+
+    #[test]
+    fn names_shift_jis_cant_store_are_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("café.txt"), b"hello").unwrap();
+        let entries = vec![PathBuf::from("café.txt")];
+        let key_table = KeyTable::identity();
+        let options = CreateOptions::default();
+
+        let sar = Archive::create_sar_archive(tempfile::tempfile().unwrap(), dir.path(), entries.clone(), &key_table, &options);
+        assert!(matches!(sar, Err(ArchiveError::UnencodableName { name }) if name == "café.txt"));
+        let nsa = Archive::create_nsa_archive(tempfile::tempfile().unwrap(), dir.path(), entries.clone(), &key_table, &options);
+        assert!(matches!(nsa, Err(ArchiveError::UnencodableName { name }) if name == "café.txt"));
+        let ns2 = Archive::create_ns2_archive(tempfile::tempfile().unwrap(), dir.path(), entries.clone(), &key_table);
+        assert!(matches!(ns2, Err(ArchiveError::UnencodableName { name }) if name == "café.txt"));
+        let plan = plan_archive(dir.path(), &entries, &ArchiveType::NSA, &options);
+        assert!(matches!(plan, Err(ArchiveError::UnencodableName { name }) if name == "café.txt"));
+    }
+
+    #[test]
+    fn streamed_nsas_match_written_ones() {
+        let files : &[(&str, &[u8])] = &[("a.txt", b"hello"), ("b/c.bmp", b"BM not really"), ("d.dat", &junk(5000))];
+        let options = CreateOptions {
+            overrides : HashMap::from([(PathBuf::from("d.dat"), Compression::Bzip2)]),
+            alignment : 16,
+            ..Default::default()
+        };
+        let key_table = xor_key_table(0x5A);
+        let built = build_with_key_table(ArchiveType::NSA, files, &options, &key_table);
+
+        let entries = files.iter().map(|(name, _)| PathBuf::from(name)).collect();
+        let mut streamed = Vec::new();
+        Archive::create_nsa_archive_streaming(&mut streamed, &built.source, entries, &key_table, &options).unwrap();
+        assert_eq!(streamed, built.bytes());
+    }
+
+    #[test]
+    fn parallel_nsas_match_serial_ones() {
+        let files : Vec<(String, Vec<u8>)> = (0..10).map(|i| (format!("{i}.dat"), junk(3000 + i * 100))).collect();
+        let files : Vec<(&str, &[u8])> = files.iter().map(|(name, data)| (name.as_str(), data.as_slice())).collect();
+        let options = CreateOptions {
+            overrides : files.iter().step_by(2).map(|(name, _)| (PathBuf::from(name), Compression::Bzip2)).collect(),
+            alignment : 16,
+            threads : 3,
+            ..Default::default()
+        };
+        let key_table = xor_key_table(0x5A);
+        let built = build_with_key_table(ArchiveType::NSA, &files, &options, &key_table);
+
+        let entries = files.iter().map(|(name, _)| PathBuf::from(name)).collect();
+        let path = built.source.join("parallel.nsa");
+        Archive::create_nsa_archive_parallel(File::create(&path).unwrap(), &built.source, entries, &key_table, &options, |_, _| {}).unwrap();
+        assert_eq!(std::fs::read(path).unwrap(), built.bytes());
+    }
+
+    #[test]
+    fn caches_only_hold_compressed_bodies() {
+        let stored = junk(20_000);
+        let files : &[(&str, &[u8])] = &[("a.txt", &[b'a'; 1000]), ("b.dat", &stored)];
+        let options = CreateOptions { lzss : true, ..Default::default() };
+        let built = build(ArchiveType::NSA, files, &options);
+        let entries : Vec<PathBuf> = files.iter().map(|(name, _)| PathBuf::from(name)).collect();
+        let path = built.source.with_file_name("cached.nsa");
+        let saved = built.source.with_file_name("cache");
+
+        let mut cache = CompressionCache::default();
+        Archive::create_nsa_archive_cached(File::create(&path).unwrap(), &built.source, entries.clone(), &KeyTable::identity(), &options, &mut cache).unwrap();
+        cache.save(&saved).unwrap();
+        assert!(std::fs::metadata(&saved).unwrap().len() < stored.len() as u64);
+
+        // b.dat comes from the file again, rather than from the cache.
+        let mut cache = CompressionCache::load(&saved).unwrap();
+        Archive::create_nsa_archive_cached(File::create(&path).unwrap(), &built.source, entries, &KeyTable::identity(), &options, &mut cache).unwrap();
+        assert_eq!(cache.hits, files.len());
+        assert_eq!(std::fs::read(&path).unwrap(), built.bytes());
+    }
+
+    #[test]
+    fn entries_are_stored_under_the_names_given_for_them() {
+        let options = CreateOptions { names : HashMap::from([(PathBuf::from("a_b.txt"), "a:b.txt".to_string())]), ..Default::default() };
+
+        for archive_type in [ArchiveType::SAR, ArchiveType::NSA] {
+            let built = build(archive_type, &[("a_b.txt", b"renamed"), ("c.txt", b"kept")], &options);
+            let mut archive = open_bytes(&built.bytes(), archive_type, &ReadOptions::default()).unwrap();
+
+            let names : Vec<&str> = archive.index.entries.iter().map(|entry| entry.name.as_str()).collect();
+            assert_eq!(names, ["a:b.txt", "c.txt"]);
+            assert_eq!(archive.try_extract(archive.index.entries[0].info()).unwrap(), b"renamed");
+
+            let entries = [PathBuf::from("a_b.txt"), PathBuf::from("c.txt")];
+            let plan = plan_archive(&built.source, &entries, &archive_type, &options).unwrap();
+            assert_eq!(plan.entries[0].name, "a:b.txt");
+            assert_eq!(plan.total_size, built.bytes().len());
+        }
+    }
+}
@@ -0,0 +1,170 @@
+use std::collections::HashMap;
+use std::io::{ErrorKind, Read, Write};
+use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+
+use crate::archive::{compress_entry, ArchiveError, Compression, CreateOptions};
+
+const MAGIC : &[u8; 8] = b"RNCACHE2";
+// Caches from before the Bzip2 block size was kept, which are started over rather than failing the build.
+const OLD_MAGIC : &[u8; 8] = b"RNCACHE1";
+
+struct CachedEntry {
+    modified : Duration, // Since the unix epoch.
+    size : u64,
+    hash : u32, // CRC32 of the uncompressed file.
+    requested : Compression,
+    bzip2_block_size : u8, // CreateOptions::bzip2_block_size it was compressed with.
+    compression : Compression,
+    data : Vec<u8> // Empty for entries stored as they are, which are read from the file itself.
+}
+
+// Compressed entry bodies from a previous archive build, keyed by the entry's name, so rebuilding an archive
+// only has to recompress the files that changed. A file is considered unchanged if its modification time and
+// size match, or failing that if its contents hash the same, and it's asked for with the same compression, and for
+// Bzip2 the same block size.
+#[derive(Default)]
+pub struct CompressionCache {
+    previous : HashMap<String, CachedEntry>,
+    // Only the entries used by this build are saved, so files that have been removed fall out of the cache.
+    current : HashMap<String, CachedEntry>,
+    pub hits : usize,
+    pub misses : usize
+}
+
+fn read_u32(reader : &mut impl Read) -> std::io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64(reader : &mut impl Read) -> std::io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn read_compression(reader : &mut impl Read) -> std::io::Result<Compression> {
+    let mut byte = [0; 1];
+    reader.read_exact(&mut byte)?;
+    Compression::try_from(byte[0]).map_err(|_| std::io::Error::new(ErrorKind::InvalidData, "unknown compression in cache"))
+}
+
+impl CompressionCache {
+    // A cache file that doesn't exist yet just gives an empty cache.
+    pub fn load(path : &Path) -> std::io::Result<CompressionCache> {
+        let mut cache = CompressionCache::default();
+        let file = match std::fs::File::open(path) {
+            Ok(file) => file,
+            Err(error) if error.kind() == ErrorKind::NotFound => return Ok(cache),
+            Err(error) => return Err(error)
+        };
+        let mut reader = std::io::BufReader::new(file);
+
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic == OLD_MAGIC {
+            return Ok(cache);
+        }
+        if &magic != MAGIC {
+            return Err(std::io::Error::new(ErrorKind::InvalidData, "not a compression cache"));
+        }
+
+        for _ in 0..read_u32(&mut reader)? {
+            let mut name = vec![0; read_u32(&mut reader)? as usize];
+            reader.read_exact(&mut name)?;
+            let name = String::from_utf8(name).map_err(|error| std::io::Error::new(ErrorKind::InvalidData, error))?;
+
+            let modified = Duration::new(read_u64(&mut reader)?, read_u32(&mut reader)?);
+            let size = read_u64(&mut reader)?;
+            let hash = read_u32(&mut reader)?;
+            let requested = read_compression(&mut reader)?;
+            let mut bzip2_block_size = [0; 1];
+            reader.read_exact(&mut bzip2_block_size)?;
+            let bzip2_block_size = bzip2_block_size[0];
+            let compression = read_compression(&mut reader)?;
+
+            let mut data = vec![0; read_u64(&mut reader)? as usize];
+            reader.read_exact(&mut data)?;
+
+            cache.previous.insert(name, CachedEntry { modified, size, hash, requested, bzip2_block_size, compression, data });
+        }
+
+        Ok(cache)
+    }
+
+    pub fn save(&self, path : &Path) -> std::io::Result<()> {
+        let mut writer = std::io::BufWriter::new(std::fs::File::create(path)?);
+
+        writer.write_all(MAGIC)?;
+        writer.write_all(&(self.current.len() as u32).to_le_bytes())?;
+
+        for (name, entry) in &self.current {
+            writer.write_all(&(name.len() as u32).to_le_bytes())?;
+            writer.write_all(name.as_bytes())?;
+            writer.write_all(&entry.modified.as_secs().to_le_bytes())?;
+            writer.write_all(&entry.modified.subsec_nanos().to_le_bytes())?;
+            writer.write_all(&entry.size.to_le_bytes())?;
+            writer.write_all(&entry.hash.to_le_bytes())?;
+            writer.write_all(&[u8::from(entry.requested), entry.bzip2_block_size, u8::from(entry.compression)])?;
+            writer.write_all(&(entry.data.len() as u64).to_le_bytes())?;
+            writer.write_all(&entry.data)?;
+        }
+
+        writer.flush()
+    }
+
+    // Returns the compression actually used, the compressed data, and the size of the file before compression.
+    // verify_spb is only applied to what's compressed now, entries reused from the cache were checked when they were
+    // first built, if they were asked to be.
+    pub fn compress(&mut self, fullpath : &Path, name : &str, requested : Compression, options : &CreateOptions) -> Result<(Compression, Vec<u8>, usize), ArchiveError> {
+        let metadata = std::fs::metadata(fullpath)?;
+        let modified = metadata.modified()?.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let size = metadata.len();
+
+        let bzip2_block_size = options.bzip2_block_size.min(u8::MAX as u32) as u8;
+        let previous = self.previous.remove(name).filter(|entry| {
+            let same_block_size = !matches!(requested, Compression::Bzip2) || entry.bzip2_block_size == bzip2_block_size;
+            u8::from(entry.requested) == u8::from(requested) && same_block_size && entry.size == size
+        });
+
+        let (entry, file) = match previous {
+            Some(entry) if entry.modified == modified => (entry, None),
+            previous => {
+                let data = std::fs::read(fullpath)?;
+                let hash = crc32fast::hash(&data);
+
+                match previous {
+                    Some(entry) if entry.hash == hash => (CachedEntry { modified, ..entry }, Some(data)),
+                    _ => {
+                        self.misses += 1;
+                        let (compression, compressed) = compress_entry(&data, requested, options)?;
+                        let entry = CachedEntry { modified, size, hash, requested, bzip2_block_size, compression, data : compressed };
+                        return self.keep(name, entry, fullpath, Some(data));
+                    }
+                }
+            }
+        };
+
+        self.hits += 1;
+        self.keep(name, entry, fullpath, file)
+    }
+
+    // Only compressed bodies are kept to be saved, there's no point in the cache holding a copy of a file that's stored
+    // as it is. Those are read from fullpath instead, unless file already has its contents.
+    fn keep(&mut self, name : &str, mut entry : CachedEntry, fullpath : &Path, file : Option<Vec<u8>>) -> Result<(Compression, Vec<u8>, usize), ArchiveError> {
+        let data = if matches!(entry.compression, Compression::None) {
+            entry.data = Vec::new();
+            match file {
+                Some(file) => file,
+                None => std::fs::read(fullpath)?
+            }
+        } else {
+            entry.data.clone()
+        };
+
+        let result = (entry.compression, data, entry.size as usize);
+        self.current.insert(name.to_string(), entry);
+        Ok(result)
+    }
+}
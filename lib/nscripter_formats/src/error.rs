@@ -0,0 +1,59 @@
+use crate::archive::ArchiveError;
+use crate::image::SpbError;
+use crate::script::ScriptError;
+
+// Wraps the error of each module, so code working across several of them can use ? on all of them and
+// report failures in one consistent format.
+#[derive(Debug)]
+pub enum Error {
+    Archive(ArchiveError),
+    Image(SpbError),
+    Script(ScriptError),
+    Io(std::io::Error),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Archive(error) => write!(f, "archive error: {error}"),
+            Error::Image(error) => write!(f, "image error: {error}"),
+            Error::Script(error) => write!(f, "script error: {error}"),
+            Error::Io(error) => write!(f, "{error}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Archive(error) => Some(error),
+            Error::Image(error) => Some(error),
+            Error::Script(error) => Some(error),
+            Error::Io(error) => Some(error),
+        }
+    }
+}
+
+impl From<ArchiveError> for Error {
+    fn from(error: ArchiveError) -> Self {
+        Error::Archive(error)
+    }
+}
+
+impl From<SpbError> for Error {
+    fn from(error: SpbError) -> Self {
+        Error::Image(error)
+    }
+}
+
+impl From<ScriptError> for Error {
+    fn from(error: ScriptError) -> Self {
+        Error::Script(error)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(error: std::io::Error) -> Self {
+        Error::Io(error)
+    }
+}
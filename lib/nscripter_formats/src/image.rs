@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+
 // SPB can only encode/decode RGB streams due to an extremely limited header of only width/height,
 // as it's the only unique format we have use of here it would be dishonest to include alpha.
 pub struct Image {
@@ -6,6 +8,262 @@ pub struct Image {
     pub height : u16
 }
 
+// How the pixel array of a BMP we're reading is laid out.
+enum BmpPixels {
+    // 1, 4 or 8 bit indices into the color table, which we keep as BGR.
+    Indexed(Vec<[u8; 3]>),
+    // 16 or 32 bit pixels, with a mask for each of the blue, green and red channels.
+    Masked([u32; 3]),
+    // 24 or 32 bit pixels that already start with BGR bytes.
+    Bgr
+}
+
+// Pulls the channel covered by mask out of a pixel, scaled up to the full 8 bits.
+fn masked_channel(pixel : u32, mask : u32) -> u8 {
+    if mask == 0 {
+        return 0;
+    }
+
+    let max = (mask >> mask.trailing_zeros()) as u64;
+    let value = ((pixel & mask) >> mask.trailing_zeros()) as u64;
+    ((value * 255 + max / 2) / max) as u8
+}
+
+impl Image {
+    // Pixels are stored top row first, in the BGR byte order BMP itself uses, which is the channel order
+    // encode_spb writes its streams in. Paletted and 16bpp BMPs are expanded to 24bpp, and alpha is dropped.
+    pub fn from_bmp_bytes(data : &[u8]) -> Result<Image, SpbError> {
+        const FILE_HEADER_SIZE : usize = 14;
+        const INFO_HEADER_SIZE : usize = 40;
+
+        if data.len() < FILE_HEADER_SIZE + INFO_HEADER_SIZE || !data.starts_with(b"BM") {
+            return Err(SpbError::UnsupportedBmp);
+        }
+
+        let read_u16 = |at : usize| u16::from_le_bytes([data[at], data[at + 1]]);
+        let read_u32 = |at : usize| u32::from_le_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]]);
+
+        let pixel_offset = read_u32(10) as usize;
+        let header_size = read_u32(14) as usize;
+        let width = read_u32(18) as i32;
+        let height = read_u32(22) as i32;
+        let bit_count = read_u16(28) as usize;
+        let compression = read_u32(30);
+
+        // Only uncompressed data is handled. BI_BITFIELDS masks directly follow the 40 byte part of the
+        // header, whether they're a separate table or part of a V4/V5 header.
+        let pixels = match (bit_count, compression) {
+            (1 | 4 | 8, 0) => {
+                let colors = match read_u32(46) {
+                    0 => 1 << bit_count,
+                    colors => colors as usize
+                };
+
+                let table = FILE_HEADER_SIZE + header_size;
+                let Some(table) = data.get(table..table + colors * 4) else {
+                    return Err(SpbError::NotEnoughData);
+                };
+
+                BmpPixels::Indexed(table.chunks_exact(4).map(|color| [color[0], color[1], color[2]]).collect())
+            },
+            (16, 0) => BmpPixels::Masked([0x001F, 0x03E0, 0x7C00]), // 5-5-5
+            (24, 0) | (32, 0) => BmpPixels::Bgr,
+            (16, 3) | (32, 3) => {
+                let masks = FILE_HEADER_SIZE + INFO_HEADER_SIZE;
+                if data.len() < masks + 12 {
+                    return Err(SpbError::NotEnoughData);
+                }
+
+                BmpPixels::Masked([read_u32(masks + 8), read_u32(masks + 4), read_u32(masks)])
+            },
+            _ => return Err(SpbError::UnsupportedBmp)
+        };
+
+        let top_down = height < 0;
+        if width < 0 {
+            return Err(SpbError::UnsupportedBmp);
+        }
+
+        // Image keeps its dimensions as u16s, as that's all SPB can store.
+        let (Ok(width), Ok(height)) = (u16::try_from(width), u16::try_from(height.unsigned_abs())) else {
+            return Err(SpbError::DimensionTooLarge { width : width as u32, height : height.unsigned_abs() });
+        };
+
+        // Rows are padded out to a multiple of 4 bytes.
+        let stride = (width as usize * bit_count).div_ceil(32) * 4;
+        if data.len() < pixel_offset + stride * height as usize {
+            return Err(SpbError::NotEnoughData);
+        }
+
+        let mut pixel_buffer : Vec<[u8; 3]> = Vec::with_capacity(width as usize * height as usize);
+        for y in 0..(height as usize) {
+            let row = if top_down { y } else { (height as usize - 1) - y };
+            let row_start = pixel_offset + row * stride;
+
+            for x in 0..(width as usize) {
+                let bit = x * bit_count;
+                let pixel = row_start + bit / 8;
+
+                pixel_buffer.push(match &pixels {
+                    BmpPixels::Indexed(palette) => {
+                        // Indices are packed most significant bits first.
+                        let shift = 8 - bit_count - (bit % 8);
+                        let index = (data[pixel] >> shift) as usize & ((1 << bit_count) - 1);
+                        *palette.get(index).ok_or(SpbError::UnsupportedBmp)?
+                    },
+                    BmpPixels::Masked(masks) => {
+                        let value = if bit_count == 16 { read_u16(pixel) as u32 } else { read_u32(pixel) };
+                        masks.map(|mask| masked_channel(value, mask))
+                    },
+                    BmpPixels::Bgr => [data[pixel], data[pixel + 1], data[pixel + 2]]
+                });
+            }
+        }
+
+        Ok(Image { pixel_buffer, width, height })
+    }
+}
+
+// How a sprite's transparency is given, after ONScripter's transparency modes for images.
+#[derive(Clone, Copy, Debug)]
+pub enum MaskMode {
+    // The image is twice the sprite's width, colors on the left half and a grayscale mask on the right, with black
+    // in the mask opaque and white transparent. Only the mask's blue channel is read.
+    Alpha,
+    // Every pixel the same color as the top left, or top right, pixel is transparent.
+    TopLeft,
+    TopRight
+}
+
+// Pixels are stored top row first, in RGBA order.
+pub struct RgbaImage {
+    pub pixel_buffer : Vec<[u8; 4]>,
+    pub width : u16,
+    pub height : u16
+}
+
+impl RgbaImage {
+    pub fn to_png(&self) -> std::io::Result<Vec<u8>> {
+        let mut png = Vec::new();
+
+        let mut encoder = png::Encoder::new(&mut png, self.width as u32, self.height as u32);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(self.pixel_buffer.as_flattened())?;
+        writer.finish()?;
+
+        Ok(png)
+    }
+}
+
+impl Image {
+    // The pixels as RGB bytes, top row first, with nothing else around them.
+    pub fn to_rgb(&self) -> Vec<u8> {
+        self.pixel_buffer.iter().flat_map(|&[b, g, r]| [r, g, b]).collect()
+    }
+
+    pub fn to_png(&self) -> std::io::Result<Vec<u8>> {
+        let mut png = Vec::new();
+
+        let mut encoder = png::Encoder::new(&mut png, self.width as u32, self.height as u32);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&self.to_rgb())?;
+        writer.finish()?;
+
+        Ok(png)
+    }
+
+    pub fn to_sprite(&self, mask_mode : MaskMode) -> RgbaImage {
+        let width = self.width as usize;
+        let rgba = |[b, g, r] : [u8; 3], alpha : u8| [r, g, b, alpha];
+
+        match mask_mode {
+            MaskMode::Alpha => {
+                let sprite_width = width / 2;
+                // An image with no width has no pixels in any of its rows, and no rows for chunks_exact to split.
+                let pixel_buffer = if width == 0 {
+                    Vec::new()
+                } else {
+                    self.pixel_buffer.chunks_exact(width)
+                        .flat_map(|row| (0..sprite_width).map(move |x| rgba(row[x], 255 - row[sprite_width + x][0])))
+                        .collect()
+                };
+
+                RgbaImage { pixel_buffer, width : sprite_width as u16, height : self.height }
+            }
+            MaskMode::TopLeft | MaskMode::TopRight => {
+                let key_index = if matches!(mask_mode, MaskMode::TopLeft) { 0 } else { width.saturating_sub(1) };
+                let key = self.pixel_buffer.get(key_index).copied();
+                let pixel_buffer = self.pixel_buffer.iter()
+                    .map(|&pixel| rgba(pixel, if Some(pixel) == key { 0 } else { 255 }))
+                    .collect();
+
+                RgbaImage { pixel_buffer, width : self.width, height : self.height }
+            }
+        }
+    }
+}
+
+// The image as a paletted PNG, if it has few enough colors for every one of them to go in the palette, so nothing is
+// lost. Indices are packed as tightly as the number of colors allows, for 2 colors or fewer each pixel is one bit.
+pub fn try_indexed_png(image : &Image) -> Option<Vec<u8>> {
+    let mut palette : Vec<[u8; 3]> = Vec::new();
+    let mut palette_indices = HashMap::new();
+    let mut indices = Vec::with_capacity(image.pixel_buffer.len());
+
+    for &pixel in &image.pixel_buffer {
+        let index = *palette_indices.entry(pixel).or_insert_with(|| {
+            palette.push(pixel);
+            palette.len() - 1
+        });
+
+        if index > u8::MAX as usize {
+            return None;
+        }
+        indices.push(index as u8);
+    }
+
+    let bit_depth = match palette.len() {
+        0..=2 => png::BitDepth::One,
+        3..=4 => png::BitDepth::Two,
+        5..=16 => png::BitDepth::Four,
+        _ => png::BitDepth::Eight
+    };
+
+    // Each row starts on a byte of its own, with the leftmost pixel in the highest bits.
+    let bits = bit_depth as usize;
+    let width = image.width as usize;
+    let row_size = (width * bits).div_ceil(8);
+    let mut data = vec![0u8; row_size * image.height as usize];
+
+    if width > 0 {
+        for (row, row_indices) in data.chunks_exact_mut(row_size).zip(indices.chunks_exact(width)) {
+            for (x, &index) in row_indices.iter().enumerate() {
+                let bit = x * bits;
+                row[bit / 8] |= index << (8 - bits - bit % 8);
+            }
+        }
+    }
+
+    let mut png = Vec::new();
+
+    let mut encoder = png::Encoder::new(&mut png, image.width as u32, image.height as u32);
+    encoder.set_color(png::ColorType::Indexed);
+    encoder.set_depth(bit_depth);
+    encoder.set_palette(palette.iter().flat_map(|&[b, g, r]| [r, g, b]).collect::<Vec<u8>>());
+
+    let mut writer = encoder.write_header().ok()?;
+    writer.write_image_data(&data).ok()?;
+    writer.finish().ok()?;
+
+    Some(png)
+}
+
 fn min_bits(value : u8) -> u8 {
     if value == 0 {
         return 0
@@ -99,13 +357,19 @@ fn bit_distances(last_byte : u8, channel : usize, bytes : &[[u8; 3]; 4]) -> SpbH
     }
 }
 
-pub fn encode_spb(image : Image) -> Vec<u8> {
+pub fn encode_spb(image : Image) -> Result<Vec<u8>, SpbError> {
+    if image.width == 0 || image.height == 0 || image.pixel_buffer.len() != (image.width as usize) * (image.height as usize) {
+        return Err(SpbError::DimensionMismatch { width : image.width, height : image.height, pixels : image.pixel_buffer.len() });
+    }
+
     let mut output_buffer : Vec<u8> = Vec::new();
     use bitbuffer::{BitWriteStream, BigEndian};
     let mut bitstream = BitWriteStream::new(&mut output_buffer, BigEndian);
     let mut image = image;
 
     // We need to reverse some of the rows as we go left and right across them when writing out data.
+    // Rows are counted from the top, as decode_spb counts them, so which rows are reversed doesn't depend on
+    // whether the height is odd, the last row of an odd height image is even and runs left to right.
     // It's likely faster to do the reversal as we're iterating, (although maybe not due to cache, who knows)
     // but I think it would overcomplicate the code so for now we do a bit of preprocessing here).
     for i in 0..(image.height as usize) {
@@ -113,8 +377,8 @@ pub fn encode_spb(image : Image) -> Vec<u8> {
             continue;
         }
 
-        let start = (i * (image.width as usize)) as usize;
-        let end = ((i + 1) * (image.width as usize)) as usize;
+        let start = i * (image.width as usize);
+        let end = (i + 1) * (image.width as usize);
 
         image.pixel_buffer[start..end].reverse();
     }
@@ -129,8 +393,8 @@ pub fn encode_spb(image : Image) -> Vec<u8> {
     image.pixel_buffer.push(last_pixel);
     
     // I suspect rows or total pixels need to be divisible by 4
-    bitstream.write_int::<u16>(image.width as u16, 16).unwrap();
-    bitstream.write_int::<u16>(image.height as u16, 16).unwrap();
+    bitstream.write_int::<u16>(image.width, 16).unwrap();
+    bitstream.write_int::<u16>(image.height, 16).unwrap();
     
     let total_pixels = (image.width as usize) * (image.height as usize);
 
@@ -144,11 +408,6 @@ pub fn encode_spb(image : Image) -> Vec<u8> {
             // Encoding occurs in 4 byte segments, with roughly 4 interesting cases, with 2 being
             // only a slight variation on each other:
 
-            if (channel == 2) && (i == (76801 - 4)) {
-                let chunk : [[u8; 3]; 4] = image.pixel_buffer[i..i+4].try_into().unwrap();
-                println!("{:?}", chunk)
-            }
-            
             match bit_distances(last_data_byte, channel, &image.pixel_buffer[i..i+4].try_into().unwrap()) {
                 // Case 1:
                 // Next four pixels in this channel are the same as the last byte written. Stamp a control
@@ -211,7 +470,16 @@ pub fn encode_spb(image : Image) -> Vec<u8> {
         }
     }
 
-    output_buffer
+    Ok(output_buffer)
+}
+
+// As encode_spb, for packing CGs where the size matters more than the time taken. There's nothing for it to try
+// differently: each channel has to start from its first pixel, the row order is fixed, every run starts from the last
+// byte of the one before whatever header it used, and the widest header the differences can take, 31 bits, is always
+// cheaper than writing four whole bytes, 35. So the header encode_spb picks for each run is already the smallest, and
+// this gives the same stream.
+pub fn encode_spb_optimized(image : Image) -> Result<Vec<u8>, SpbError> {
+    encode_spb(image)
 }
 
 
@@ -225,80 +493,280 @@ fn header_stamp4(last_byte : u8) -> [u8; 4]
 }
 
 #[inline(always)]
-fn header_read4<'buf>(bitstream : &mut bitbuffer::BitReadStream<'buf, bitbuffer::BigEndian>) -> [u8; 4]
+fn header_read4<'buf>(bitstream : &mut bitbuffer::BitReadStream<'buf, bitbuffer::BigEndian>) -> Result<[u8; 4], SpbError>
 {
-    [ 
-        bitstream.read_int::<u8>(8).unwrap(),
-        bitstream.read_int::<u8>(8).unwrap(),
-        bitstream.read_int::<u8>(8).unwrap(),
-        bitstream.read_int::<u8>(8).unwrap(),
-    ]
+    Ok([
+        bitstream.read_int::<u8>(8)?,
+        bitstream.read_int::<u8>(8)?,
+        bitstream.read_int::<u8>(8)?,
+        bitstream.read_int::<u8>(8)?,
+    ])
 }
 
 #[inline(always)]
-fn header_bit_compressed<'buf>(bits_to_read : u8, last_byte : u8, bitstream : &mut bitbuffer::BitReadStream<'buf, bitbuffer::BigEndian>) -> [u8; 4]
+fn header_bit_compressed<'buf>(bits_to_read : u8, last_byte : u8, bitstream : &mut bitbuffer::BitReadStream<'buf, bitbuffer::BigEndian>) -> Result<[u8; 4], SpbError>
 {
     let mut last_byte = last_byte;
     let mut chunk : [u8;4] = [0;4];
-    let mut i = 0;
 
     let modify_bytes_and_ops = [
-        bitstream.read_int::<u8>(bits_to_read as usize).unwrap(),
-        bitstream.read_int::<u8>(bits_to_read as usize).unwrap(),
-        bitstream.read_int::<u8>(bits_to_read as usize).unwrap(),
-        bitstream.read_int::<u8>(bits_to_read as usize).unwrap(),
+        bitstream.read_int::<u8>(bits_to_read as usize)?,
+        bitstream.read_int::<u8>(bits_to_read as usize)?,
+        bitstream.read_int::<u8>(bits_to_read as usize)?,
+        bitstream.read_int::<u8>(bits_to_read as usize)?,
     ];
 
     // I do wonder how much of this loop could be done as some simd operations. The final if and assignment likely couldn't be
     // but maybe the shifts could...    
-    for modify_byte_and_op in modify_bytes_and_ops {
+    for (i, modify_byte_and_op) in modify_bytes_and_ops.into_iter().enumerate() {
         // The last bit read is used to determine how we'll be modifying the data byte, after
         // determining that we throw away that bit.
         let add = (modify_byte_and_op & 1) > 0;
         let modify_byte = modify_byte_and_op >> 1;
         
-        if add {
-            last_byte += modify_byte + 1;
+        // An encoder never has a reason to run a byte past 0 or 255, so if we do the data is corrupt.
+        last_byte = if add {
+            last_byte.checked_add(modify_byte + 1)
         } else {
-            last_byte -= modify_byte;
-        }
+            last_byte.checked_sub(modify_byte)
+        }.ok_or(SpbError::Overflow)?;
 
         chunk[i] = last_byte;
-        i += 1;
     }
 
-    chunk
+    Ok(chunk)
 }
 
 #[derive(Debug)]
-pub enum Err {
-    NotEnoughData
+pub enum SpbError {
+    // The BMP handed to us is shorter than its own header says it should be.
+    NotEnoughData,
+    // The BMP is compressed, or otherwise not one of the layouts from_bmp_bytes knows how to read.
+    UnsupportedBmp,
+    // The image's pixel buffer doesn't hold width * height pixels, or the image is empty.
+    DimensionMismatch { width : u16, height : u16, pixels : usize },
+    // The BMP is wider or taller than the 65535 pixels SPB can store.
+    DimensionTooLarge { width : u32, height : u32 },
+    // A difference in the SPB stream took a byte out of the [0, 255] range.
+    Overflow,
+    // The SPB stream ended before every pixel was decoded.
+    Truncated,
+    // What encode_spb produced decodes to pixels other than the image it was given.
+    VerificationFailed,
+}
+
+impl std::fmt::Display for SpbError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SpbError::NotEnoughData => write!(f, "BMP is shorter than its header describes"),
+            SpbError::UnsupportedBmp => write!(f, "BMP isn't an uncompressed 1, 4, 8, 16, 24 or 32 bpp image"),
+            SpbError::DimensionMismatch { width, height, pixels } => write!(f, "{width}x{height} image has {pixels} pixels"),
+            SpbError::DimensionTooLarge { width, height } => write!(f, "{width}x{height} image is larger than the {}x{} SPB can store", u16::MAX, u16::MAX),
+            SpbError::Overflow => write!(f, "SPB difference overflowed a byte, the data is corrupt"),
+            SpbError::Truncated => write!(f, "SPB data ended early"),
+            SpbError::VerificationFailed => write!(f, "encoded SPB doesn't decode back to the original image"),
+        }
+    }
+}
+
+impl std::error::Error for SpbError {}
+
+impl From<bitbuffer::BitError> for SpbError {
+    fn from(_ : bitbuffer::BitError) -> Self {
+        SpbError::Truncated
+    }
+}
+
+#[deprecated(note = "renamed to SpbError")]
+pub type Err = SpbError;
+
+// BITMAPFILEHEADER followed by a BITMAPINFOHEADER (v3), the most widely understood BMP variant.
+const BMP_HEADER_SIZE : usize = 14 + 40;
+
+// Rows of a 24bpp BMP are padded out to a multiple of 4 bytes.
+fn bmp_stride(width : usize) -> usize {
+    (width * 3 + 3) & !3
+}
+
+// Allocates the whole output BMP, with its header written and the pixel array zeroed, 24bpp BGR bottom-up. A BMP's
+// sizes are u32s, so an image too large for them to hold is an error.
+fn new_bmp(width : usize, height : usize) -> Result<Vec<u8>, SpbError> {
+    let image_size = bmp_stride(width) * height;
+    let Ok(file_size) = u32::try_from(BMP_HEADER_SIZE + image_size) else {
+        return Err(SpbError::DimensionTooLarge { width : width as u32, height : height as u32 });
+    };
+    let image_size = image_size as u32;
+    let mut bmp : Vec<u8> = Vec::with_capacity(file_size as usize);
+
+    bmp.extend_from_slice(b"BM");
+    bmp.extend_from_slice(&file_size.to_le_bytes());
+    bmp.extend_from_slice(&[0; 4]);
+    bmp.extend_from_slice(&(BMP_HEADER_SIZE as u32).to_le_bytes());
+
+    bmp.extend_from_slice(&40u32.to_le_bytes());
+    bmp.extend_from_slice(&(width as i32).to_le_bytes());
+    bmp.extend_from_slice(&(height as i32).to_le_bytes());
+    bmp.extend_from_slice(&1u16.to_le_bytes()); // Planes
+    bmp.extend_from_slice(&24u16.to_le_bytes());
+    bmp.extend_from_slice(&0u32.to_le_bytes()); // BI_RGB
+    bmp.extend_from_slice(&image_size.to_le_bytes());
+    bmp.extend_from_slice(&3780i32.to_le_bytes()); // 96 DPI
+    bmp.extend_from_slice(&3780i32.to_le_bytes());
+    bmp.extend_from_slice(&[0; 8]); // No color table.
+
+    bmp.resize(file_size as usize, 0);
+    Ok(bmp)
+}
+
+// Size of the BMP decode_spb will produce for this SPB data, worked out from the width and height at the start of it
+// the same way ONScripter does, without decoding anything. This is what NSA headers store as an SPB entry's
+// decompressed size.
+pub fn decoded_spb_size(buffer : &[u8]) -> Result<usize, SpbError> {
+    let Some(dimensions) = buffer.get(0..4) else {
+        return Err(SpbError::Truncated);
+    };
+
+    let width = u16::from_be_bytes([dimensions[0], dimensions[1]]) as usize;
+    let height = u16::from_be_bytes([dimensions[2], dimensions[3]]) as usize;
+    Ok(BMP_HEADER_SIZE + bmp_stride(width) * height)
+}
+
+// Decodes an SPB image as a sprite, with its transparency applied as mask_mode says.
+pub fn decode_spb_sprite(buffer : Vec<u8>, mask_mode : MaskMode) -> Result<RgbaImage, SpbError> {
+    let bmp = decode_spb(buffer)?;
+    Ok(Image::from_bmp_bytes(&bmp)?.to_sprite(mask_mode))
+}
+
+// Decodes spb and checks it holds exactly image's pixels, to catch encode_spb producing something that reads back
+// differently before it ends up in an archive.
+pub fn verify_spb(image : &Image, spb : &[u8]) -> Result<(), SpbError> {
+    let decoded = Image::from_bmp_bytes(&decode_spb(spb.to_vec())?)?;
+
+    if (decoded.width, decoded.height) != (image.width, image.height) || decoded.pixel_buffer != image.pixel_buffer {
+        return Err(SpbError::VerificationFailed);
+    }
+
+    Ok(())
 }
 
-pub fn decode_spb(buffer : Vec<u8>) -> Result<Vec<u8>, Err> {
+// How the encoder spent the bits of one channel of an SPB image, counted by the header each run of four bytes starts
+// with. Lots of read4 where there's little change in the image is a sign the encoder is picking badly.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ChannelStats {
+    pub stamp4 : usize, // Header 0, the previous byte four times over.
+    pub read4 : usize, // Header 6, four whole bytes.
+    // Headers 1 to 5 and 7, four differences from the previous byte, counted by the bits each one takes, 1 to 7.
+    // Index 0 is never used.
+    pub bit_compressed : [usize; 8],
+    pub bits : usize // Everything the channel takes in the stream, its first byte and the headers included.
+}
+
+// Walks spb the way decode_spb does, counting each channel's headers rather than building the image. The channels
+// come in the order they're stored, blue, green and red.
+pub fn spb_channel_stats(buffer : &[u8]) -> Result<[ChannelStats; 3], SpbError> {
     use bitbuffer::{BitReadBuffer, BitReadStream, BigEndian};
-    let buffer = BitReadBuffer::new(&buffer, BigEndian);
+    let buffer = BitReadBuffer::new(buffer, BigEndian);
     let mut bitstream = BitReadStream::new(buffer);
 
-    let width = bitstream.read_int::<u16>(16).unwrap() as usize;
-    let height = bitstream.read_int::<u16>(16).unwrap() as usize;
-    
-    let mut pixel_buffer : Vec<u8> = vec![0; (width * height + 4) * 3];
-
-    // Read each channel of image data, in BGR order.
-    for channel in (0..3).rev() {
-        let start = (width * height + 4) * channel;
-        let end = (width * height + 4) * (channel + 1);
-        let channel_buffer = &mut pixel_buffer[start..end];
-        channel_buffer[0] = bitstream.read_int::<u8>(8).unwrap();
+    let width = bitstream.read_int::<u16>(16)? as usize;
+    let height = bitstream.read_int::<u16>(16)? as usize;
+    let total_pixels = width * height;
+
+    let mut stats = [ChannelStats::default(); 3];
+    if total_pixels == 0 {
+        return Ok(stats);
+    }
+
+    for channel in &mut stats {
+        let start = bitstream.pos();
+        let mut data_byte = bitstream.read_int::<u8>(8)?;
         let mut i : usize = 1;
 
-        while i < (width * height) {
-            let data_byte = channel_buffer[i - 1];
+        while i < total_pixels {
+            let chunk = match bitstream.read_int::<u8>(3)? {
+                0 => {
+                    channel.stamp4 += 1;
+                    header_stamp4(data_byte)
+                }
+                6 => {
+                    channel.read4 += 1;
+                    header_read4(&mut bitstream)?
+                }
+                header => {
+                    let bits_to_read = if header == 7 { bitstream.read_int::<u8>(1)? + 1 } else { header + 2 };
+                    channel.bit_compressed[bits_to_read as usize] += 1;
+                    header_bit_compressed(bits_to_read, data_byte, &mut bitstream)?
+                }
+            };
+
+            data_byte = chunk[3];
+            i += 4;
+        }
+
+        channel.bits = bitstream.pos() - start;
+    }
+
+    Ok(stats)
+}
+
+pub fn decode_spb(buffer : Vec<u8>) -> Result<Vec<u8>, SpbError> {
+    let Some(dimensions) = buffer.get(0..4) else {
+        return Err(SpbError::Truncated);
+    };
 
+    let width = u16::from_be_bytes([dimensions[0], dimensions[1]]);
+    let height = u16::from_be_bytes([dimensions[2], dimensions[3]]);
+    decode_spb_with_dims(buffer, width, height)
+}
+
+// As decode_spb, but the image is taken to be width by height whatever the SPB's first four bytes say, for SPBs whose
+// dimensions were damaged while the rest of them wasn't, when the real ones are known from elsewhere. The data still
+// has to start with four bytes where the dimensions would be, they're skipped.
+pub fn decode_spb_with_dims(buffer : Vec<u8>, width : u16, height : u16) -> Result<Vec<u8>, SpbError> {
+    use bitbuffer::{BitReadBuffer, BitReadStream, BigEndian};
+    let bits = buffer.len() * 8;
+    let buffer = BitReadBuffer::new(&buffer, BigEndian);
+    let mut bitstream = BitReadStream::new(buffer);
+    bitstream.skip_bits(32)?;
+
+    let width = width as usize;
+    let height = height as usize;
+    let total_pixels = width * height;
+
+    // Each channel takes at least its first byte and a 3 bit header for every 4 pixels after it, so data shorter than
+    // that can't hold the image, and there's no point allocating the whole BMP to find out.
+    if total_pixels > 0 && bits < 32 + 3 * (8 + 3 * (total_pixels - 1).div_ceil(4)) {
+        return Err(SpbError::Truncated);
+    }
+
+    // Rather than decoding each channel into its own plane and then interleaving them, each byte we decode
+    // goes straight to its place in the BMP's pixel array, so the output is the only image sized allocation.
+    let mut bmp = new_bmp(width, height)?;
+    if total_pixels == 0 {
+        // There's nothing to place, and no first pixel to start each channel at.
+        return Ok(bmp);
+    }
+    let pixels = &mut bmp[BMP_HEADER_SIZE..];
+
+    // Rows alternate direction, odd rows run right to left, and BMP stores the bottom row first. y counts from the top
+    // of the image, as encode_spb counts them, so the direction doesn't depend on where the BMP's bottom row falls.
+    let stride = bmp_stride(width);
+    let pixel_index = |i : usize| {
+        let y = i / width;
+        let x = if (y & 1) == 1 { (width - 1) - (i % width) } else { i % width };
+        (height - 1 - y) * stride + x * 3
+    };
+
+    // Channels are stored in BGR order, matching the byte order of the BMP's pixels.
+    for channel in 0..3 {
+        let mut data_byte = bitstream.read_int::<u8>(8)?;
+        pixels[pixel_index(0) + channel] = data_byte;
+        let mut i : usize = 1;
+
+        while i < total_pixels {
             // Read a 3 bit header from the stream, 3 bits means range is [0,7]
             // This header helps determine how we stamp the next 4 bytes.
-            let header = bitstream.read_int::<u8>(3).unwrap();
+            let header = bitstream.read_int::<u8>(3)?;
 
 
             let chunk = match header {
@@ -307,53 +775,247 @@ pub fn decode_spb(buffer : Vec<u8>) -> Result<Vec<u8>, Err> {
                     header_stamp4(data_byte)
                 }
                 6 => {
-                    header_read4(&mut bitstream)
+                    header_read4(&mut bitstream)?
                 }
                 // bits_to_read is within  [3,7]
                 1..=5 => {
-                    header_bit_compressed(header + 2, data_byte, &mut bitstream)
+                    header_bit_compressed(header + 2, data_byte, &mut bitstream)?
                 }
                 // bits_to_read is within [1,2], depending on a 1 bit read.
                 // escape sequence in case of adding one or subtracting zero.
                 7 => {
-                    header_bit_compressed(bitstream.read_int::<u8>(1).unwrap() + 1, data_byte, &mut bitstream)
+                    header_bit_compressed(bitstream.read_int::<u8>(1)? + 1, data_byte, &mut bitstream)?
                 }
                 _ => {
                     panic!("Impossible value for n (a 3 bit integer) when decoding SPB:");
                 }
             };
-            
-            channel_buffer[i]     = chunk[0];
-            channel_buffer[i + 1] = chunk[1];
-            channel_buffer[i + 2] = chunk[2];
-            channel_buffer[i + 3] = chunk[3];
+
+            // The final chunk can run past the end of the image, those bytes are simply dropped.
+            for (j, byte) in chunk.into_iter().enumerate() {
+                if i + j < total_pixels {
+                    pixels[pixel_index(i + j) + channel] = byte;
+                }
+            }
+
+            data_byte = chunk[3];
             i += 4;
-            
         }
     }
 
-    let r_buffer = &pixel_buffer[0..(width * height + 4)];
-    let g_buffer = &pixel_buffer[(width * height + 4)..(width * height + 4) * 2];
-    let b_buffer = &pixel_buffer[(width * height + 4) * 2..(width * height + 4) * 3];
+    Ok(bmp)
+}
 
-    // We've read all the channels, we can comfortably blit out a BMP now.
-    let mut bmp_file = bmp_rust::bmp::BMP::new(height as i32, width as u32, None);
-    for y in 0..height {
-        let row_skip = y * width;
-        for x in 0..width {
-            // If we're on an odd row, we read backwards
-            let i = if (y & 1) == 1 {
-                ((width - 1) - x ) + row_skip
-            } else {
-                x + row_skip
-            };
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_u16(data : &[u8], at : usize) -> u16 {
+        u16::from_le_bytes([data[at], data[at + 1]])
+    }
 
-            let r = r_buffer[i];
-            let g = g_buffer[i];
-            let b = b_buffer[i];
-            bmp_file.change_color_of_pixel(x as u16, y as u16, [r,g,b,255]).expect("Failed to change color of pixel");
+    fn read_u32(data : &[u8], at : usize) -> u32 {
+        u32::from_le_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]])
+    }
+
+    // Every pixel differs from its neighbours in each direction, so a row read the wrong way round can't go unnoticed.
+    fn test_image(width : u16, height : u16) -> Image {
+        let pixel_buffer = (0..width as usize * height as usize)
+            .map(|i| [(i * 7) as u8, (i * 13 + 5) as u8, (i * i) as u8])
+            .collect();
+
+        Image { pixel_buffer, width, height }
+    }
+
+    #[test]
+    fn decoded_spbs_are_24bpp_bmps() {
+        // Widths that need 0 to 3 bytes of padding at the end of each row.
+        for width in [4, 5, 6, 7] {
+            let height = 3;
+            let bmp = decode_spb(encode_spb(test_image(width, height)).unwrap()).unwrap();
+            let stride = (width as usize * 3).next_multiple_of(4);
+
+            assert_eq!(read_u16(&bmp, 28), 24);
+            assert_eq!(read_u32(&bmp, 34) as usize, stride * height as usize);
+            assert_eq!(read_u32(&bmp, 2) as usize, bmp.len());
+            assert_eq!(bmp.len(), 54 + stride * height as usize);
+            assert_eq!(decoded_spb_size(&encode_spb(test_image(width, height)).unwrap()).unwrap(), bmp.len());
+
+            for row in bmp[54..].chunks_exact(stride) {
+                assert!(row[width as usize * 3..].iter().all(|&byte| byte == 0), "{width} wide");
+            }
+        }
+    }
+
+    #[test]
+    fn bmp_headers_are_bitmapinfoheaders() {
+        // 5 pixels of 3 bytes are padded out to a 16 byte row.
+        let bmp = new_bmp(5, 7).unwrap();
+        assert_eq!(bmp.len(), 14 + 40 + 16 * 7);
+
+        // BITMAPFILEHEADER
+        assert_eq!(&bmp[0..2], b"BM");
+        assert_eq!(read_u32(&bmp, 2), bmp.len() as u32);
+        assert_eq!(read_u32(&bmp, 6), 0);
+        assert_eq!(read_u32(&bmp, 10), 54);
+
+        // BITMAPINFOHEADER
+        assert_eq!(read_u32(&bmp, 14), 40);
+        assert_eq!(read_u32(&bmp, 18) as i32, 5);
+        assert_eq!(read_u32(&bmp, 22) as i32, 7);
+        assert_eq!(read_u16(&bmp, 26), 1);
+        assert_eq!(read_u16(&bmp, 28), 24);
+        assert_eq!(read_u32(&bmp, 30), 0);
+        assert_eq!(read_u32(&bmp, 34), 16 * 7);
+        assert_eq!(read_u32(&bmp, 38) as i32, 3780);
+        assert_eq!(read_u32(&bmp, 42) as i32, 3780);
+        assert_eq!(read_u32(&bmp, 46), 0);
+        assert_eq!(read_u32(&bmp, 50), 0);
+
+        assert!(bmp[54..].iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn odd_heights_round_trip() {
+        for (width, height) in [(5, 7), (4, 1), (1, 3), (3, 5)] {
+            let image = test_image(width, height);
+            let spb = encode_spb(test_image(width, height)).unwrap();
+            let decoded = Image::from_bmp_bytes(&decode_spb(spb).unwrap()).unwrap();
+
+            assert_eq!((decoded.width, decoded.height), (width, height));
+            assert_eq!(decoded.pixel_buffer, image.pixel_buffer, "{width}x{height}");
+        }
+    }
+
+    // A BMP with a BITMAPINFOHEADER, rows given bottom row first and padded here.
+    fn bmp_fixture(bit_count : u16, width : i32, height : i32, palette : &[[u8; 4]], rows : &[&[u8]]) -> Vec<u8> {
+        let pixel_offset = 14 + 40 + palette.len() * 4;
+        let mut bmp = b"BM".to_vec();
+        bmp.extend_from_slice(&[0; 8]);
+        bmp.extend_from_slice(&(pixel_offset as u32).to_le_bytes());
+
+        bmp.extend_from_slice(&40u32.to_le_bytes());
+        bmp.extend_from_slice(&width.to_le_bytes());
+        bmp.extend_from_slice(&height.to_le_bytes());
+        bmp.extend_from_slice(&1u16.to_le_bytes());
+        bmp.extend_from_slice(&bit_count.to_le_bytes());
+        bmp.extend_from_slice(&[0; 16]); // BI_RGB, no image size and no resolution.
+        bmp.extend_from_slice(&(palette.len() as u32).to_le_bytes());
+        bmp.extend_from_slice(&[0; 4]);
+        palette.iter().for_each(|color| bmp.extend_from_slice(color));
+
+        for row in rows {
+            bmp.extend_from_slice(row);
+            bmp.resize(bmp.len() + (4 - row.len() % 4) % 4, 0);
+        }
+        bmp
+    }
+
+    #[test]
+    fn paletted_and_32bpp_bmps_are_read_as_24bpp() {
+        let (red, green, blue) = ([0, 0, 255], [0, 255, 0], [255, 0, 0]);
+        let palette = [[0, 0, 255, 0], [0, 255, 0, 0], [255, 0, 0, 0]];
+        let paletted = bmp_fixture(8, 3, 2, &palette, &[&[2, 1, 0], &[0, 1, 2]]);
+
+        // Alpha is dropped, whatever it was.
+        let bgra = bmp_fixture(32, 3, 2, &[], &[&[255, 0, 0, 0, 0, 255, 0, 128, 0, 0, 255, 255], &[0, 0, 255, 7, 0, 255, 0, 0, 255, 0, 0, 9]]);
+
+        for bmp in [paletted, bgra] {
+            let image = Image::from_bmp_bytes(&bmp).unwrap();
+            assert_eq!((image.width, image.height), (3, 2));
+            assert_eq!(image.pixel_buffer, vec![red, green, blue, blue, green, red]);
+
+            let decoded = Image::from_bmp_bytes(&decode_spb(encode_spb(image).unwrap()).unwrap()).unwrap();
+            assert_eq!(decoded.pixel_buffer, vec![red, green, blue, blue, green, red]);
+        }
+    }
+
+    #[test]
+    fn images_too_large_for_spb_are_an_error() {
+        // Image can't hold more than u16 dimensions, so it's BMPs that have to be turned away.
+        let wide = bmp_fixture(24, 70_000, 1, &[], &[]);
+        assert!(matches!(Image::from_bmp_bytes(&wide), Err(SpbError::DimensionTooLarge { width : 70_000, height : 1 })));
+
+        let tall = bmp_fixture(24, 1, -70_000, &[], &[]);
+        assert!(matches!(Image::from_bmp_bytes(&tall), Err(SpbError::DimensionTooLarge { width : 1, height : 70_000 })));
+
+        let largest = bmp_fixture(24, 65_535, 1, &[], &[&vec![0; 65_535 * 3]]);
+        assert_eq!(Image::from_bmp_bytes(&largest).unwrap().width, u16::MAX);
+    }
+
+    #[test]
+    fn truncated_spbs_are_an_error() {
+        let spb = encode_spb(test_image(5, 7)).unwrap();
+
+        for length in 0..spb.len() {
+            assert!(matches!(decode_spb(spb[..length].to_vec()), Err(SpbError::Truncated)), "cut to {length} bytes");
         }
+
+        // An image with no pixels has nothing after its dimensions.
+        assert_eq!(decode_spb(vec![0, 5, 0, 0]).unwrap().len(), BMP_HEADER_SIZE);
+    }
+
+    #[test]
+    fn spbs_that_decode_to_other_pixels_fail_verification() {
+        let image = test_image(5, 7);
+        let spb = encode_spb(test_image(5, 7)).unwrap();
+        verify_spb(&image, &spb).unwrap();
+
+        // The first byte of the blue channel is stored whole, so this changes one pixel and nothing else.
+        let mut corrupt = spb.clone();
+        corrupt[4] ^= 0x10;
+        assert!(matches!(verify_spb(&image, &corrupt), Err(SpbError::VerificationFailed)));
+
+        // Everything after the dimensions shifted by a bit.
+        let mut corrupt = spb.clone();
+        for i in (4..corrupt.len()).rev() {
+            corrupt[i] = (corrupt[i] >> 1) | (corrupt[i - 1] << 7);
+        }
+        assert!(verify_spb(&image, &corrupt).is_err());
+
+        // Different dimensions, which leave the channels out of step with the data.
+        let mut corrupt = spb;
+        corrupt[1] = 4;
+        assert!(verify_spb(&image, &corrupt).is_err());
     }
 
-    Ok(bmp_file.contents)
+    #[test]
+    fn spbs_with_damaged_dimensions_decode_at_the_ones_given() {
+        let spb = encode_spb(test_image(6, 5)).unwrap();
+        let bmp = decode_spb(spb.clone()).unwrap();
+
+        let mut damaged = spb;
+        damaged[0..4].copy_from_slice(&[0x00, 0x03, 0x00, 0x0A]);
+        assert_ne!(decode_spb(damaged.clone()).ok(), Some(bmp.clone()));
+        assert_eq!(decode_spb_with_dims(damaged, 6, 5).unwrap(), bmp);
+    }
+
+    #[test]
+    fn optimized_spbs_decode_identically_and_are_never_larger() {
+        for (width, height) in [(1, 1), (4, 4), (5, 7), (16, 9)] {
+            let optimized = encode_spb_optimized(test_image(width, height)).unwrap();
+            let baseline = encode_spb(test_image(width, height)).unwrap();
+
+            assert!(optimized.len() <= baseline.len(), "{width}x{height}");
+            assert_eq!(decode_spb(optimized).unwrap(), decode_spb(baseline).unwrap(), "{width}x{height}");
+        }
+    }
+
+    #[test]
+    fn spbs_too_short_for_their_dimensions_are_truncated() {
+        // 65535x21846 would be a BMP of just over 4 GiB, which used to wrap when its size was taken as a u32.
+        assert!(matches!(decode_spb(vec![0xFF, 0xFF, 0x55, 0x56, 0, 0, 0, 0]), Err(SpbError::Truncated)));
+        assert!(matches!(new_bmp(0xFFFF, 0x5556), Err(SpbError::DimensionTooLarge { width : 0xFFFF, height : 0x5556 })));
+    }
+
+    #[test]
+    fn images_with_no_width_make_empty_sprites() {
+        let image = Image { pixel_buffer : Vec::new(), width : 0, height : 3 };
+
+        for mask_mode in [MaskMode::Alpha, MaskMode::TopLeft, MaskMode::TopRight] {
+            let sprite = image.to_sprite(mask_mode);
+            assert!(sprite.pixel_buffer.is_empty());
+            assert_eq!((sprite.width, sprite.height), (0, 3));
+        }
+    }
 }
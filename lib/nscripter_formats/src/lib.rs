@@ -1,42 +1,209 @@
 use core::panic;
 
 pub mod archive;
+pub mod cache;
 pub mod script;
 pub mod image;
+pub mod vfs;
+pub mod source;
+pub mod salvage;
+pub mod shiftjis;
+pub mod prelude;
+mod error;
 
-pub fn default_keytable() ->  [u8; 256] {
-    let mut key_table : [u8; 256] = [0; 256];
-    for (i, val) in key_table.iter_mut().enumerate() {
-        *val = i as u8;
+pub use error::Error;
+pub use source::{open_any, AssetSource};
+pub use archive::{Archive, ArchiveType, Compression};
+pub use image::{decode_spb, encode_spb, Image};
+pub use script::decode_script;
+
+// The table archives and KeyTable obfuscated scripts are read through, each byte is replaced by the table's entry
+// for it. Tables are always 256 different bytes, as ONScripter finds them that way in key files, which is what
+// lets them be undone, so they can only be made from bytes that are.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct KeyTable([u8; 256]);
+
+impl KeyTable {
+    // The table that leaves every byte as it is, for archives that aren't obfuscated.
+    pub fn identity() -> KeyTable {
+        let mut key_table : [u8; 256] = [0; 256];
+        for (i, val) in key_table.iter_mut().enumerate() {
+            *val = i as u8;
+        }
+        KeyTable(key_table)
+    }
+
+    // The table from a game's key file or executable, see find_keytable_in_pe.
+    pub fn from_file(path : &std::path::Path) -> Option<KeyTable> {
+        find_keytable_in_pe(path)
+    }
+
+    // None if the bytes aren't all different.
+    pub fn from_bytes(bytes : [u8; 256]) -> Option<KeyTable> {
+        KeyTable::validate(&bytes).then_some(KeyTable(bytes))
+    }
+
+    // Whether every byte value appears once in bytes, which is all a table needs to be.
+    pub fn validate(bytes : &[u8; 256]) -> bool {
+        let mut seen = [false; 256];
+        bytes.iter().all(|&byte| !std::mem::replace(&mut seen[byte as usize], true))
+    }
+
+    pub fn apply(&self, byte : u8) -> u8 {
+        self.0[byte as usize]
+    }
+
+    // The table that undoes this one, which is what archives are written through so that reading them back through
+    // this one gives the bytes that were written.
+    pub fn invert(&self) -> KeyTable {
+        let mut inverse : [u8; 256] = [0; 256];
+        for (i, &val) in self.0.iter().enumerate() {
+            inverse[val as usize] = i as u8;
+        }
+        KeyTable(inverse)
     }
-    key_table
 }
 
-pub fn create_keytable(file : &str) ->  [u8; 256] {
+impl std::ops::Deref for KeyTable {
+    type Target = [u8; 256];
+
+    fn deref(&self) -> &[u8; 256] {
+        &self.0
+    }
+}
+
+pub fn default_keytable() -> KeyTable {
+    KeyTable::identity()
+}
+
+// The first run of 256 bytes that are all different, which is how ONScripter finds the key table in a key file.
+fn find_keytable_in(buffer : &[u8]) -> Option<KeyTable> {
+    // Where each byte value was last seen, a run can only start after the last repeat of anything within it.
+    let mut last_seen : [Option<usize>; 256] = [None; 256];
+    let mut start = 0;
+
+    for (i, &byte) in buffer.iter().enumerate() {
+        if let Some(previous) = last_seen[byte as usize] {
+            start = start.max(previous + 1);
+        }
+        last_seen[byte as usize] = Some(i);
+
+        if i + 1 - start == 256 {
+            let mut table : [u8; 256] = [0; 256];
+            table.copy_from_slice(&buffer[start..=i]);
+            return Some(KeyTable(table));
+        }
+    }
+
+    None
+}
+
+pub fn create_keytable(file : &str) -> KeyTable {
     let buffer = std::fs::read(file).unwrap();
-    let mut table : [u8; 256] = [0; 256];
-    let mut found_table = false;
-
-    for i in 0..buffer.len() {
-        found_table = false;
-        'sequence: for (table_index, buffer_index) in (i..buffer.len()).enumerate() {
-            let byte_read : u8 = buffer[buffer_index]; 
-            for table_val in table {
-                if table_val == byte_read {
-                    break 'sequence;
-                }
+
+    match find_keytable_in(&buffer) {
+        Some(table) => table,
+        None => panic!("Couldn't find a table in the key file!")
+    }
+}
+
+// Game executables keep their key table in one of their data sections, scanning only those avoids picking up a run
+// of distinct bytes in the code first. Anything that doesn't parse as a PE, or has no table in its data, is
+// scanned whole like create_keytable does.
+pub fn find_keytable_in_pe(path : &std::path::Path) -> Option<KeyTable> {
+    use goblin::pe::{section_table::IMAGE_SCN_CNT_INITIALIZED_DATA, PE};
+
+    let buffer = std::fs::read(path).ok()?;
+
+    if let Ok(pe) = PE::parse(&buffer) {
+        let data_sections = pe.sections.iter().filter(|section| section.characteristics & IMAGE_SCN_CNT_INITIALIZED_DATA != 0);
+
+        for section in data_sections {
+            let start = section.pointer_to_raw_data as usize;
+            let end = start.saturating_add(section.size_of_raw_data as usize).min(buffer.len());
+
+            if let Some(table) = buffer.get(start..end).and_then(find_keytable_in) {
+                return Some(table);
             }
-            table[table_index] = byte_read;
         }
+    }
+
+    find_keytable_in(&buffer)
+}
 
-        if found_table {
-            break;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A PE32 image with a .text section and a .data section, each 0x200 bytes from file offset 0x200 on, and nothing
+    // else a loader would need.
+    fn minimal_pe(text : &[u8], data : &[u8]) -> Vec<u8> {
+        let mut pe = vec![0; 0x40];
+        pe[0..2].copy_from_slice(b"MZ");
+        pe[0x3C..0x40].copy_from_slice(&0x40u32.to_le_bytes());
+
+        // COFF header: i386, two sections, a 224 byte optional header, an executable image.
+        pe.extend_from_slice(b"PE\0\0");
+        for field in [0x14Cu16, 2] {
+            pe.extend_from_slice(&field.to_le_bytes());
         }
-    }
+        pe.extend_from_slice(&[0; 12]);
+        for field in [224u16, 0x0102] {
+            pe.extend_from_slice(&field.to_le_bytes());
+        }
+
+        // Optional header, with every data directory empty.
+        pe.extend_from_slice(&0x10Bu16.to_le_bytes());
+        pe.extend_from_slice(&[0; 2]);
+        for field in [0x200u32, 0x200, 0, 0x1000, 0x1000, 0x2000, 0x40_0000, 0x1000, 0x200] {
+            pe.extend_from_slice(&field.to_le_bytes());
+        }
+        for field in [4u16, 0, 0, 0, 4, 0] {
+            pe.extend_from_slice(&field.to_le_bytes());
+        }
+        for field in [0u32, 0x3000, 0x200, 0] {
+            pe.extend_from_slice(&field.to_le_bytes());
+        }
+        for field in [2u16, 0] {
+            pe.extend_from_slice(&field.to_le_bytes());
+        }
+        for field in [0x10_0000u32, 0x1000, 0x10_0000, 0x1000, 0, 16] {
+            pe.extend_from_slice(&field.to_le_bytes());
+        }
+        pe.extend_from_slice(&[0; 16 * 8]);
 
-    if !found_table {
-        panic!("Couldn't find a table in the key file!")
+        // Section headers: name, virtual size and address, raw size and offset, relocations and line numbers, flags.
+        for (name, address, offset, characteristics) in [(b".text\0\0\0", 0x1000u32, 0x200u32, 0x6000_0020u32), (b".data\0\0\0", 0x2000, 0x400, 0xC000_0040)] {
+            pe.extend_from_slice(name);
+            for field in [0x200, address, 0x200, offset, 0, 0] {
+                pe.extend_from_slice(&field.to_le_bytes());
+            }
+            pe.extend_from_slice(&[0; 4]);
+            pe.extend_from_slice(&characteristics.to_le_bytes());
+        }
+
+        for section in [text, data] {
+            pe.resize(pe.len().next_multiple_of(0x200), 0);
+            pe.extend_from_slice(section);
+        }
+        pe.resize(0x600, 0);
+        pe
     }
 
-    table
+    #[test]
+    fn key_tables_are_found_in_pe_data_sections() {
+        // The code happens to hold 256 distinct bytes in a row before the data does.
+        let decoy : Vec<u8> = (0..=255).collect();
+        let table : Vec<u8> = (0..=255).map(|byte : u8| byte ^ 0x84).collect();
+        let pe = minimal_pe(&[[0xCC; 16].as_slice(), &decoy].concat(), &[[0; 16].as_slice(), &table].concat());
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("game.exe");
+        std::fs::write(&path, &pe).unwrap();
+        assert_eq!(find_keytable_in_pe(&path).map(|found| found.to_vec()), Some(table.clone()));
+
+        // Anything that isn't a PE is scanned whole, as create_keytable does.
+        std::fs::write(&path, &pe[0x200..]).unwrap();
+        assert_eq!(find_keytable_in_pe(&path).map(|found| found.to_vec()), Some(decoy));
+    }
 }
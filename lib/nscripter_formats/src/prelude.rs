@@ -0,0 +1,7 @@
+// What most users of the crate need, for bringing in with a single use nscripter_formats::prelude::*: reading and
+// writing archives, decoding their SPB images and scripts, and the key table all of them are read through.
+pub use crate::archive::{Archive, ArchiveError, ArchiveIndex, ArchiveType, Compression, CreateOptions, ReadOptions};
+pub use crate::image::{decode_spb, encode_spb, encode_spb_optimized, Image, SpbError};
+pub use crate::script::{decode_script, Encoding, Obfuscation, ScriptError};
+pub use crate::source::{open_any, AssetSource};
+pub use crate::{default_keytable, Error, KeyTable};
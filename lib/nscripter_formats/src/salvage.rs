@@ -0,0 +1,113 @@
+// A file found by its magic bytes in data with no index to say where anything is, such as the data region of an
+// archive whose header is too broken to read.
+#[derive(Debug, Clone, Copy)]
+pub struct EmbeddedFile {
+    pub offset : usize,
+    pub size : usize,
+    pub extension : &'static str
+}
+
+fn u16_le(data : &[u8], at : usize) -> Option<u16> {
+    Some(u16::from_le_bytes(data.get(at..at + 2)?.try_into().unwrap()))
+}
+
+fn u32_le(data : &[u8], at : usize) -> Option<usize> {
+    Some(u32::from_le_bytes(data.get(at..at + 4)?.try_into().unwrap()) as usize)
+}
+
+// BMPs carry their own size, and the fields around it are checked so a stray "BM" isn't taken for one.
+fn bmp_size(data : &[u8]) -> Option<usize> {
+    if !data.starts_with(b"BM") {
+        return None;
+    }
+
+    let size = u32_le(data, 2)?;
+    let reserved = u32_le(data, 6)?;
+    let pixel_offset = u32_le(data, 10)?;
+    let info_size = u32_le(data, 14)?;
+    let planes = u16_le(data, 26)?;
+
+    let valid = reserved == 0
+        && matches!(info_size, 12 | 40 | 52 | 56 | 108 | 124)
+        && pixel_offset >= 14 + info_size
+        && pixel_offset <= size
+        && planes == 1
+        && size <= data.len();
+    valid.then_some(size)
+}
+
+// RIFF chunks carry the size of everything after the first 8 bytes, only the forms a game would use are looked for.
+fn riff_size(data : &[u8]) -> Option<(usize, &'static str)> {
+    if !data.starts_with(b"RIFF") {
+        return None;
+    }
+
+    let extension = match data.get(8..12)? {
+        b"WAVE" => "wav",
+        b"AVI " => "avi",
+        _ => return None
+    };
+
+    let size = u32_le(data, 4)?.checked_add(8)?;
+    (size <= data.len()).then_some((size, extension))
+}
+
+// Ogg has no overall size, so the pages of a stream are walked from its first page until the one marking its end.
+// A stream that's cut off stops before its last page.
+fn ogg_size(data : &[u8]) -> Option<usize> {
+    const BEGINNING_OF_STREAM : u8 = 2;
+    const END_OF_STREAM : u8 = 4;
+
+    let is_page = |at : usize| data.get(at..at + 4) == Some(b"OggS") && data.get(at + 4) == Some(&0);
+    if !is_page(0) || data.get(5)? & BEGINNING_OF_STREAM == 0 {
+        return None;
+    }
+
+    let mut position = 0;
+    while is_page(position) {
+        let (Some(&header_type), Some(&segments)) = (data.get(position + 5), data.get(position + 26)) else {
+            break;
+        };
+        let Some(table) = data.get(position + 27..position + 27 + segments as usize) else {
+            break;
+        };
+
+        // Without checking page CRCs, a page is only believed if another follows it or it ends the stream.
+        let end = position + 27 + segments as usize + table.iter().map(|&length| length as usize).sum::<usize>();
+        let ends_stream = header_type & END_OF_STREAM != 0;
+        if end > data.len() || !(ends_stream || is_page(end)) {
+            break;
+        }
+        position = end;
+
+        if ends_stream {
+            break;
+        }
+    }
+
+    (position > 0).then_some(position)
+}
+
+// Looks through data for BMP, WAV/AVI and Ogg files, front to back. Anything found is skipped over whole, so a
+// file's contents aren't searched for more files.
+pub fn find_embedded_files(data : &[u8]) -> Vec<EmbeddedFile> {
+    let mut files = Vec::new();
+    let mut offset = 0;
+
+    while offset < data.len() {
+        let rest = &data[offset..];
+        let found = bmp_size(rest).map(|size| (size, "bmp"))
+            .or_else(|| riff_size(rest))
+            .or_else(|| ogg_size(rest).map(|size| (size, "ogg")));
+
+        match found {
+            Some((size, extension)) => {
+                files.push(EmbeddedFile { offset, size, extension });
+                offset += size;
+            }
+            None => offset += 1
+        }
+    }
+
+    files
+}
@@ -1,12 +1,57 @@
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
-use crate::default_keytable;
+use crate::archive::{Archive, ArchiveError};
+use crate::{default_keytable, find_keytable_in_pe, KeyTable};
+use crate::shiftjis::{self, ShiftJisTable};
 
 // Does encoding_rs have an enum for this? Should we just use that?
+#[derive(Clone, Copy)]
 pub enum Encoding {
     CP1252,
     ShiftJIS,
-    Utf8
+    // Shift-JIS decoded with Microsoft's table, see ShiftJisTable for the handful of bytes where that matters.
+    Cp932,
+    Utf8,
+    // A leading byte order mark takes precedence over the endianness picked here.
+    Utf16Le,
+    Utf16Be
+}
+
+#[derive(Debug)]
+pub enum ScriptError {
+    Io(std::io::Error),
+    UnknownFileName(String), // Not one of the script names we know the encoding and obfuscation of.
+    InvalidText, // The deobfuscated bytes aren't valid in the script's encoding.
+    KeyTableRequired, // The script is obfuscated with a key table and none was given.
+    NoKeyTable(PathBuf), // The key file given doesn't have a key table in it.
+    Archive(ArchiveError), // The script couldn't be read out of the archive it's in.
+}
+
+impl std::fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ScriptError::Io(error) => write!(f, "{error}"),
+            ScriptError::UnknownFileName(name) => write!(f, "unknown script name {name}, can't guess its encoding or obfuscation scheme"),
+            ScriptError::InvalidText => write!(f, "couldn't read a string from this script"),
+            ScriptError::KeyTableRequired => write!(f, "this script is obfuscated with a key table, the game's key file or executable is needed to decode it"),
+            ScriptError::NoKeyTable(path) => write!(f, "couldn't find a key table in {}", path.display()),
+            ScriptError::Archive(error) => write!(f, "couldn't read the script from its archive: {error}"),
+        }
+    }
+}
+
+impl std::error::Error for ScriptError {}
+
+impl From<std::io::Error> for ScriptError {
+    fn from(error: std::io::Error) -> Self {
+        ScriptError::Io(error)
+    }
+}
+
+impl From<ArchiveError> for ScriptError {
+    fn from(error: ArchiveError) -> Self {
+        ScriptError::Archive(error)
+    }
 }
 
 pub enum Obfuscation {
@@ -16,8 +61,8 @@ pub enum Obfuscation {
     KeyTable // Uses a provided key table.
 }
 
-pub fn file_name_to_decode_info(file_name : &Path) -> (Encoding, Obfuscation) {
-    match file_name.to_str().unwrap() {
+pub fn file_name_to_decode_info(file_name : &Path) -> Result<(Encoding, Obfuscation), ScriptError> {
+    let info = match file_name.file_name().and_then(|name| name.to_str()).unwrap_or_default() {
         "nscript.___" => {
             (Encoding::ShiftJIS, Obfuscation::KeyTable)
         }
@@ -43,18 +88,20 @@ pub fn file_name_to_decode_info(file_name : &Path) -> (Encoding, Obfuscation) {
             (Encoding::Utf8, Obfuscation::Xor132)
         }
         _ => { 
-            panic!("Unknown filename, can't guess it's encoding or obfuscation scheme.")
+            return Err(ScriptError::UnknownFileName(file_name.display().to_string()))
         }
-    }
+    };
+
+    Ok(info)
 }
 
-fn decode_xor132(data : &mut Vec<u8>) {
+fn decode_xor132(data : &mut [u8]) {
     for byte in data.iter_mut() {
         *byte ^= 132;
     }
 }
 
-fn decode_ywreturn(data : &mut Vec<u8>) {
+fn decode_ywreturn(data : &mut [u8]) {
     let magic : [u8; 5] = [ 121, 87, 13, 128, 4 ];
 
     for (i, byte) in data.iter_mut().enumerate() {
@@ -62,14 +109,14 @@ fn decode_ywreturn(data : &mut Vec<u8>) {
     }
 }
 
-fn decode_keytable(data : &mut Vec<u8>, key_table : &[u8; 256]) {
+fn decode_keytable(data : &mut [u8], key_table : &KeyTable) {
     for byte in data.iter_mut() {
         *byte = key_table[*byte as usize];
     }
 }
 
-//// Returns a 
-pub fn decode_script(data : Vec<u8>, encoding : Encoding, obfuscation : Obfuscation, key_table : &[u8; 256]) -> String {
+/// Deobfuscates a script's raw bytes and decodes them into text.
+pub fn decode_script(data : Vec<u8>, encoding : Encoding, obfuscation : Obfuscation, key_table : &KeyTable) -> Result<String, ScriptError> {
     let mut data = data;
     match obfuscation {
         Obfuscation::Xor132 => {
@@ -87,32 +134,183 @@ pub fn decode_script(data : Vec<u8>, encoding : Encoding, obfuscation : Obfuscat
     }
 
     match encoding {
-        Encoding::ShiftJIS => {
-            use encoding_rs::SHIFT_JIS;
-            let (res, _enc, errors) = SHIFT_JIS.decode(&data);
+        Encoding::Utf16Le | Encoding::Utf16Be => {
+            use encoding_rs::{UTF_16BE, UTF_16LE};
+
+            // decode sniffs the BOM, swapping to the other endianness if it disagrees, and strips it.
+            let encoding = if matches!(encoding, Encoding::Utf16Le) { UTF_16LE } else { UTF_16BE };
+            let (res, _enc, errors) = encoding.decode(&data);
             if errors {
-                panic!("Couldn't read a string from this file.");
+                return Err(ScriptError::InvalidText);
             }
 
-            return res.to_string();
+            Ok(res.to_string())
+        }
+        Encoding::ShiftJIS | Encoding::Cp932 => {
+            let table = if matches!(encoding, Encoding::Cp932) { ShiftJisTable::Cp932 } else { ShiftJisTable::Whatwg };
+            let (res, errors) = shiftjis::decode(&data, table);
+            if errors {
+                return Err(ScriptError::InvalidText);
+            }
+
+            Ok(res)
         }
         _ => {
             use encoding_rs::UTF_8;
 
+            // decode sniffs for a BOM here too, so UTF-16 scripts with one that go by the UTF-8 names still decode.
             let (res, _enc, errors) = UTF_8.decode(&data);
             if errors {
-                panic!("Couldn't read a string from this file.");
+                return Err(ScriptError::InvalidText);
             }
         
-            return res.to_string();
+            Ok(res.to_string())
         }
     }
 }
 
-pub fn decode_script_file(name : &str) -> String {
+// Whether data, the raw bytes of a script, isn't already plain text in encoding. Every script has a *define or
+// *start label, which no obfuscation leaves readable, so text that decodes cleanly and has one is taken as plain.
+pub fn looks_obfuscated(data : &[u8], encoding : Encoding) -> bool {
+    let Ok(text) = decode_script(data.to_vec(), encoding, Obfuscation::None, &default_keytable()) else {
+        return true;
+    };
+
+    let text = text.to_lowercase();
+    !["*define", "*start"].iter().any(|label| text.contains(label))
+}
+
+pub fn decode_script_file(name : &str) -> Result<String, ScriptError> {
+    decode_script_file_with_key(name, None)
+}
+
+// As decode_script_file, taking the key table for scripts obfuscated with one from key_file, the game's key file or
+// executable, see find_keytable_in_pe. Other scripts don't need it.
+pub fn decode_script_file_with_key(name : &str, key_file : Option<&Path>) -> Result<String, ScriptError> {
     let file_path = Path::new(name);
-    let (encoding, obfuscation) = file_name_to_decode_info(&file_path);
+    let (encoding, obfuscation) = file_name_to_decode_info(file_path)?;
+    let file_data = std::fs::read(file_path)?;
+
+    // A script that was decoded before and kept its name is read as it is, rather than obfuscated a second time.
+    let obfuscation = if looks_obfuscated(&file_data, encoding) { obfuscation } else { Obfuscation::None };
+
+    let key_table = match (&obfuscation, key_file) {
+        (Obfuscation::KeyTable, None) => return Err(ScriptError::KeyTableRequired),
+        (Obfuscation::KeyTable, Some(key_file)) => find_keytable_in_pe(key_file).ok_or_else(|| ScriptError::NoKeyTable(key_file.to_path_buf()))?,
+        _ => default_keytable()
+    };
+
+    decode_script(file_data, encoding, obfuscation, &key_table)
+}
+
+// As decode_script_file_with_key, for a script that's an entry of archive rather than a file on disk, such as an
+// nscript.dat packed into arc.nsa. The entry's file name picks the encoding and obfuscation, and key_table is what
+// a KeyTable obfuscated script is read with, usually the archive's own.
+pub fn decode_from_archive(archive : &mut Archive, name : &str, key_table : &KeyTable) -> Result<String, ScriptError> {
+    let (encoding, obfuscation) = file_name_to_decode_info(Path::new(name.rsplit(['\\', '/']).next().unwrap_or(name)))?;
+
+    let Some(&i) = archive.index.entries_map.get(name) else {
+        return Err(ArchiveError::EntryNotFound { name : name.to_string() }.into());
+    };
+    let data = archive.try_extract(archive.index.entries[i].info())?;
+
+    let obfuscation = if looks_obfuscated(&data, encoding) { obfuscation } else { Obfuscation::None };
+    decode_script(data, encoding, obfuscation, key_table)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCRIPT : &str = "*define\ngame\n*start\n「こんにちは」\n";
+
+    fn utf16(text : &str, big_endian : bool) -> Vec<u8> {
+        text.encode_utf16().flat_map(|unit| if big_endian { unit.to_be_bytes() } else { unit.to_le_bytes() }).collect()
+    }
+
+    #[test]
+    fn utf16_scripts_decode_with_or_without_a_bom() {
+        let key_table = default_keytable();
+        let decode = |data : Vec<u8>, encoding| decode_script(data, encoding, Obfuscation::None, &key_table).unwrap();
+
+        assert_eq!(decode(utf16(SCRIPT, false), Encoding::Utf16Le), SCRIPT);
+        assert_eq!(decode(utf16(SCRIPT, true), Encoding::Utf16Be), SCRIPT);
+
+        // The BOM wins over the endianness asked for, and isn't part of the text.
+        let little = [[0xFF, 0xFE].as_slice(), &utf16(SCRIPT, false)].concat();
+        let big = [[0xFE, 0xFF].as_slice(), &utf16(SCRIPT, true)].concat();
+        for encoding in [Encoding::Utf16Le, Encoding::Utf16Be, Encoding::Utf8] {
+            assert_eq!(decode(little.clone(), encoding), SCRIPT);
+            assert_eq!(decode(big.clone(), encoding), SCRIPT);
+        }
+    }
 
-    let file_data = std::fs::read(&file_path).unwrap();
-    decode_script(file_data, encoding, obfuscation, &default_keytable())
+    #[test]
+    fn key_table_scripts_decode_with_the_games_key_file() {
+        // Reading a byte looks it up in the table, so the script is stored as the bytes that look up to it.
+        let table : Vec<u8> = (0..=255).map(|byte : u8| byte.wrapping_add(1)).collect();
+        let (script, _, _) = encoding_rs::SHIFT_JIS.encode(SCRIPT);
+        let obfuscated : Vec<u8> = script.iter().map(|byte| byte.wrapping_sub(1)).collect();
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("nscript.___");
+        let key_file = dir.path().join("game.exe");
+        std::fs::write(&path, &obfuscated).unwrap();
+        std::fs::write(&key_file, [b"not a PE, ".as_slice(), &table].concat()).unwrap();
+
+        let name = path.to_str().unwrap();
+        assert_eq!(decode_script_file_with_key(name, Some(&key_file)).unwrap(), SCRIPT);
+        assert!(matches!(decode_script_file(name), Err(ScriptError::KeyTableRequired)));
+
+        let no_table = dir.path().join("readme.txt");
+        std::fs::write(&no_table, b"no table in here").unwrap();
+        assert!(matches!(decode_script_file_with_key(name, Some(&no_table)), Err(ScriptError::NoKeyTable(_))));
+    }
+
+    #[test]
+    fn plain_scripts_are_told_apart_from_obfuscated_ones() {
+        let (plain, _, _) = encoding_rs::SHIFT_JIS.encode(SCRIPT);
+        let mut xor132 = plain.to_vec();
+        decode_xor132(&mut xor132);
+        let mut ywreturn = plain.to_vec();
+        decode_ywreturn(&mut ywreturn);
+
+        assert!(!looks_obfuscated(&plain, Encoding::ShiftJIS));
+        assert!(!looks_obfuscated(SCRIPT.as_bytes(), Encoding::Utf8));
+        assert!(looks_obfuscated(&xor132, Encoding::ShiftJIS));
+        assert!(looks_obfuscated(&ywreturn, Encoding::ShiftJIS));
+        assert!(looks_obfuscated(b"hello, no labels here", Encoding::Utf8));
+
+        // Decoding nscript.dat a second time leaves it as it is.
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("nscript.dat");
+        for data in [xor132, plain.to_vec()] {
+            std::fs::write(&path, data).unwrap();
+            assert_eq!(decode_script_file(path.to_str().unwrap()).unwrap(), SCRIPT);
+        }
+    }
+
+    #[test]
+    fn scripts_decode_from_the_archive_they_are_in() {
+        let (plain, _, _) = encoding_rs::SHIFT_JIS.encode(SCRIPT);
+        let mut xor132 = plain.to_vec();
+        decode_xor132(&mut xor132);
+
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("sub")).unwrap();
+        std::fs::write(dir.path().join("nscript.dat"), &xor132).unwrap();
+        std::fs::write(dir.path().join("sub").join("0.utf"), SCRIPT).unwrap();
+        std::fs::write(dir.path().join("readme.txt"), "not a script").unwrap();
+
+        let path = dir.path().join("arc.nsa");
+        let entries = vec!["nscript.dat".into(), "sub/0.utf".into(), "readme.txt".into()];
+        Archive::create_nsa_archive(std::fs::File::create(&path).unwrap(), dir.path(), entries, &KeyTable::identity(), &Default::default()).unwrap();
+        let mut archive = Archive::open_file(std::fs::File::open(&path).unwrap(), crate::archive::ArchiveType::NSA, 0, &KeyTable::identity());
+
+        let key_table = default_keytable();
+        assert_eq!(decode_from_archive(&mut archive, "nscript.dat", &key_table).unwrap(), SCRIPT);
+        assert_eq!(decode_from_archive(&mut archive, "sub/0.utf", &key_table).unwrap(), SCRIPT);
+        assert!(matches!(decode_from_archive(&mut archive, "readme.txt", &key_table), Err(ScriptError::UnknownFileName(_))));
+        assert!(matches!(decode_from_archive(&mut archive, "00.utf", &key_table), Err(ScriptError::Archive(ArchiveError::EntryNotFound { .. }))));
+    }
 }
@@ -0,0 +1,65 @@
+use encoding_rs::SHIFT_JIS;
+
+// Which table Shift-JIS text is decoded with. encoding_rs's Shift_JIS is the WHATWG one, which for every two byte
+// character is already Microsoft's CP932, NEC and IBM extensions and the user defined area included. They differ on
+// four single bytes WHATWG leaves undefined, which Windows maps into the private use area, and so games made on
+// Windows can have them in names and scripts:
+//   0xA0 -> U+F8F0, 0xFD -> U+F8F1, 0xFE -> U+F8F2, 0xFF -> U+F8F3
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ShiftJisTable {
+    #[default]
+    Whatwg,
+    Cp932
+}
+
+fn cp932_single_byte(byte : u8) -> Option<char> {
+    match byte {
+        0xA0 => Some('\u{F8F0}'),
+        0xFD => Some('\u{F8F1}'),
+        0xFE => Some('\u{F8F2}'),
+        0xFF => Some('\u{F8F3}'),
+        _ => None
+    }
+}
+
+fn is_lead_byte(byte : u8) -> bool {
+    matches!(byte, 0x81..=0x9F | 0xE0..=0xFC)
+}
+
+// The decoded text, and whether anything in it wasn't valid and became a replacement character.
+pub fn decode(buffer : &[u8], table : ShiftJisTable) -> (String, bool) {
+    if table == ShiftJisTable::Whatwg {
+        let (res, _enc, errors) = SHIFT_JIS.decode(buffer);
+        return (res.into_owned(), errors);
+    }
+
+    // The bytes only CP932 defines are picked out where a character starts, the rest goes through encoding_rs.
+    let mut text = String::with_capacity(buffer.len());
+    let mut any_errors = false;
+    let mut start = 0;
+    let mut position = 0;
+
+    while position < buffer.len() {
+        let byte = buffer[position];
+
+        if let Some(character) = cp932_single_byte(byte) {
+            let (res, errors) = SHIFT_JIS.decode_without_bom_handling(&buffer[start..position]);
+            any_errors |= errors;
+            text.push_str(&res);
+            text.push(character);
+
+            position += 1;
+            start = position;
+        } else if is_lead_byte(byte) {
+            // Whatever follows is part of this character, or an error encoding_rs will find.
+            position += 2;
+        } else {
+            position += 1;
+        }
+    }
+
+    let (res, errors) = SHIFT_JIS.decode_without_bom_handling(&buffer[start..]);
+    text.push_str(&res);
+
+    (text, any_errors || errors)
+}
@@ -0,0 +1,129 @@
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use crate::archive::{compression_from_name, decompress_entry, Archive, ArchiveType, ReadOptions};
+use crate::vfs::collect_loose_files;
+use crate::{Error, KeyTable};
+
+// Anything entries can be listed and read from by name, so callers don't have to care whether they're reading an
+// archive or files on disk. Entries are read as a game would see them, files named .nbz or .spb come back
+// decompressed whether they're loose or in an archive.
+pub trait AssetSource {
+    fn list(&self) -> Vec<String>;
+    fn read(&mut self, name : &str) -> Result<Vec<u8>, Error>;
+}
+
+fn not_found(name : &str) -> Error {
+    Error::Io(std::io::Error::new(ErrorKind::NotFound, format!("{name} isn't in this source")))
+}
+
+impl AssetSource for Archive {
+    fn list(&self) -> Vec<String> {
+        self.index.entries.iter().map(|entry| entry.name.clone()).collect()
+    }
+
+    fn read(&mut self, name : &str) -> Result<Vec<u8>, Error> {
+        let Some(&i) = self.index.entries_map.get(name) else {
+            return Err(not_found(name));
+        };
+
+        let info = self.index.entries[i].info();
+        Ok(self.try_extract(info)?)
+    }
+}
+
+// Files on disk, either every file under a directory, named by their path within it, or a single file, named by its
+// file name. The key table is applied to them as it is to archives.
+pub struct LooseFiles {
+    root : PathBuf,
+    files : Vec<String>,
+    key_table : KeyTable
+}
+
+impl LooseFiles {
+    pub fn directory(root : &Path, key_table : &KeyTable) -> LooseFiles {
+        let mut files = Vec::new();
+        collect_loose_files(root, root, &mut files);
+        files.sort();
+
+        LooseFiles { root : root.to_path_buf(), files, key_table : *key_table }
+    }
+
+    pub fn file(path : &Path, key_table : &KeyTable) -> LooseFiles {
+        let root = path.parent().unwrap_or(Path::new("")).to_path_buf();
+        let files = path.file_name().and_then(|name| name.to_str()).map(str::to_string).into_iter().collect();
+
+        LooseFiles { root, files, key_table : *key_table }
+    }
+}
+
+impl AssetSource for LooseFiles {
+    fn list(&self) -> Vec<String> {
+        self.files.clone()
+    }
+
+    // Only listed files can be read, so a name can't reach outside of the root.
+    fn read(&mut self, name : &str) -> Result<Vec<u8>, Error> {
+        if !self.files.iter().any(|file| file == name) {
+            return Err(not_found(name));
+        }
+
+        let mut data = std::fs::read(self.root.join(name))?;
+        for byte in &mut data {
+            *byte = self.key_table[*byte as usize];
+        }
+
+        Ok(decompress_entry(data, compression_from_name(name), None)?)
+    }
+}
+
+pub(crate) fn archive_type_from_extension(path : &Path) -> Option<ArchiveType> {
+    match path.extension()?.to_str()?.to_lowercase().as_str() {
+        "sar" => Some(ArchiveType::SAR),
+        "nsa" => Some(ArchiveType::NSA),
+        "ns2" => Some(ArchiveType::NS2),
+        _ => None
+    }
+}
+
+// Opens a directory of loose files, an archive, recognized by its extension, or any other single file.
+pub fn open_any(path : &Path, key_table : &KeyTable) -> Result<Box<dyn AssetSource>, Error> {
+    if path.is_dir() {
+        return Ok(Box::new(LooseFiles::directory(path, key_table)));
+    }
+
+    match archive_type_from_extension(path) {
+        Some(archive_type) => {
+            let file = std::fs::File::open(path)?;
+            let archive = Archive::try_open_file_with_options(file, archive_type, 0, key_table, &ReadOptions::default())?;
+            Ok(Box::new(archive))
+        }
+        None => Ok(Box::new(LooseFiles::file(path, key_table)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::compress_bz2;
+    use crate::image::{decode_spb, encode_spb, Image};
+
+    #[test]
+    fn loose_nbz_and_spb_files_decode_to_what_was_encoded() {
+        let dir = tempfile::tempdir().unwrap();
+        let wav = [b"RIFF".as_slice(), &[0; 4], b"WAVEfmt ", &b"pcm".repeat(300)].concat();
+        std::fs::write(dir.path().join("voice.nbz"), compress_bz2(&wav)).unwrap();
+
+        let pixel_buffer = (0..4 * 3).map(|i| [i as u8, 0, 255 - i as u8]).collect();
+        let spb = encode_spb(Image { pixel_buffer, width : 4, height : 3 }).unwrap();
+        std::fs::write(dir.path().join("bg.spb"), &spb).unwrap();
+
+        let mut nbz = open_any(&dir.path().join("voice.nbz"), &KeyTable::identity()).unwrap();
+        assert_eq!(nbz.list(), vec!["voice.nbz".to_string()]);
+        assert_eq!(nbz.read("voice.nbz").unwrap(), wav);
+
+        let mut loose = open_any(dir.path(), &KeyTable::identity()).unwrap();
+        assert_eq!(loose.read("bg.spb").unwrap(), decode_spb(spb).unwrap());
+        assert_eq!(loose.read("voice.nbz").unwrap(), wav);
+    }
+}
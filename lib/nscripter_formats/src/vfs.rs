@@ -0,0 +1,322 @@
+use std::{cell::RefCell, collections::{BTreeMap, HashMap}, path::{Path, PathBuf}};
+
+use crate::archive::{find_archive_sequence, Archive, ArchiveError, ArchiveType, Compression, ARCHIVE_BASES};
+use crate::source::archive_type_from_extension;
+use crate::KeyTable;
+
+// Resolves files the way ONScripter does when a game has loose files alongside its archives: a loose
+// file in the game directory always wins, then the archives are searched in the order they were given,
+// which should be the engine's own order (arc.nsa, arc1.nsa, ..., then arc.sar).
+//
+// Like the engine, lookups ignore case and treat '/' and '\' as the same separator. Loose files are listed once, when
+// the file system is made, so they can be looked up the same way.
+pub struct VirtualFileSystem {
+    loose_root : Option<PathBuf>,
+    loose_names : BTreeMap<String, String>, // Lookup name -> path within loose_root, first in sorted order wins.
+    archives : Vec<Archive>,
+    names : HashMap<String, (usize, usize)> // Lookup name -> (archive, entry), first archive to have it wins.
+}
+
+fn lookup_name(name : &str) -> String {
+    name.replace('/', "\\").to_lowercase()
+}
+
+pub(crate) fn collect_loose_files(root : &Path, directory : &Path, files : &mut Vec<String>) {
+    let Ok(read_dir) = std::fs::read_dir(directory) else {
+        return;
+    };
+
+    for entry in read_dir.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            collect_loose_files(root, &path, files);
+        } else if let Some(relative_path) = path.strip_prefix(root).ok().and_then(Path::to_str) {
+            // Names that aren't valid UTF-8 can't be looked up by a str, so they're left out.
+            files.push(relative_path.to_string());
+        }
+    }
+}
+
+// Every archive of a game in dir, in the order ONScripter searches them.
+pub fn open_game_archives(dir : &Path, key_table : &KeyTable) -> Result<Vec<Archive>, ArchiveError> {
+    let mut archives = Vec::new();
+
+    for (archive_type, base) in &ARCHIVE_BASES {
+        for path in find_archive_sequence(dir, base, archive_type)? {
+            let file = std::fs::File::open(path)?;
+            archives.push(Archive::try_open_file_with_options(file, *archive_type, 0, key_table, &Default::default())?);
+        }
+    }
+
+    Ok(archives)
+}
+
+// The offset a game's archives start at, for games that put something in front of their headers, so their directory
+// can be read without being told it. These are checked in order:
+//   ons.cfg, ONScripter-EN's options file, for a line giving ONScripter's nsa-offset option as "nsa-offset=<bytes>".
+//   The first of the game's NSA or SAR archives, in ONScripter's order, searched for a header as
+//   Archive::find_embedded_archive does, if it doesn't read as an archive from its start.
+// None if the config doesn't give one and the archives open as they are, or no header could be found.
+pub fn detect_offset_from_dir(dir : &Path, key_table : &KeyTable) -> Option<u32> {
+    if let Ok(config) = std::fs::read_to_string(dir.join("ons.cfg")) {
+        let offset = config.lines()
+            .filter_map(|line| line.trim().strip_prefix("nsa-offset="))
+            .find_map(|value| value.trim().parse().ok());
+
+        if offset.is_some() {
+            return offset;
+        }
+    }
+
+    let path = ARCHIVE_BASES.iter()
+        .filter(|(archive_type, _)| !matches!(archive_type, ArchiveType::NS2))
+        .find_map(|(archive_type, base)| find_archive_sequence(dir, base, archive_type).ok()?.into_iter().next())?;
+
+    let mut file = std::fs::File::open(path).ok()?;
+    if matches!(Archive::detect_archive_type(&file, key_table), Ok(Some(_))) {
+        return None;
+    }
+
+    Archive::find_embedded_archive(&mut file, key_table).map(|(_, offset)| offset)
+}
+
+// One entry of one archive, as catalog_archives lists them.
+#[derive(Debug, Clone)]
+pub struct CatalogEntry {
+    pub archive : PathBuf,
+    pub name : String,
+    pub size : usize,
+    pub compression : Compression,
+    pub decompressed_size : Option<usize>
+}
+
+// Every entry of every archive in dir and the directories under it, archives being recognized by their extension.
+// Only headers are read, nothing is extracted. They're read as try_salvage does so a broken one can't stop the rest,
+// but any archive whose header doesn't read all the way through is reported and left out. Entry names that aren't
+// valid Shift-JIS are listed with replacement characters.
+pub fn catalog_archives(dir : &Path, key_table : &KeyTable) -> Vec<CatalogEntry> {
+    let mut files = Vec::new();
+    collect_loose_files(dir, dir, &mut files);
+    files.sort();
+
+    let mut catalog = Vec::new();
+    for file in files {
+        let path = dir.join(&file);
+        let Some(archive_type) = archive_type_from_extension(&path) else {
+            continue;
+        };
+
+        let archive = std::fs::File::open(&path).map_err(ArchiveError::from).and_then(|file| {
+            match Archive::try_salvage(file, archive_type, 0, key_table) {
+                (_, Some(error)) => Err(error),
+                (archive, None) => Ok(archive)
+            }
+        });
+
+        match archive {
+            Ok(archive) => catalog.extend(archive.index.entries.iter().map(|entry| CatalogEntry {
+                archive : path.clone(),
+                name : entry.name.clone(),
+                size : entry.size,
+                compression : entry.compression,
+                decompressed_size : entry.decompressed_size()
+            })),
+            Err(error) => log::warn!("Couldn't read {}: {}", path.display(), error)
+        }
+    }
+
+    catalog
+}
+
+thread_local! {
+    // Archives opened by find_in_archives, by directory and key table, so looking up one asset after another doesn't
+    // open and parse every archive again each time.
+    static GAME_ARCHIVES : RefCell<HashMap<(PathBuf, KeyTable), VirtualFileSystem>> = RefCell::new(HashMap::new());
+}
+
+// The named file from whichever of the game's archives in dir has it first, as ONScripter would find it but without
+// looking at loose files. The archives are kept open for later calls, per thread. None if no archive has it, or the
+// archives can't be opened.
+pub fn find_in_archives(dir : &Path, name : &str, key_table : &KeyTable) -> Option<Vec<u8>> {
+    GAME_ARCHIVES.with(|cache| {
+        let mut cache = cache.borrow_mut();
+        let key = (dir.to_path_buf(), *key_table);
+
+        if !cache.contains_key(&key) {
+            let archives = open_game_archives(dir, key_table).ok()?;
+            cache.insert(key.clone(), VirtualFileSystem::new(None, archives));
+        }
+
+        cache.get_mut(&key)?.open(name)
+    })
+}
+
+impl VirtualFileSystem {
+    pub fn new(loose_root : Option<PathBuf>, archives : Vec<Archive>) -> VirtualFileSystem {
+        let mut names : HashMap<String, (usize, usize)> = HashMap::new();
+
+        for (archive_index, archive) in archives.iter().enumerate() {
+            for (entry_index, entry) in archive.index.entries.iter().enumerate() {
+                names.entry(lookup_name(&entry.name)).or_insert((archive_index, entry_index));
+            }
+        }
+
+        let mut loose_names = BTreeMap::new();
+        if let Some(root) = &loose_root {
+            let mut files = Vec::new();
+            collect_loose_files(root, root, &mut files);
+            files.sort();
+
+            for file in files {
+                loose_names.entry(lookup_name(&file)).or_insert(file);
+            }
+        }
+
+        VirtualFileSystem { loose_root, loose_names, archives, names }
+    }
+
+    fn loose_path(&self, name : &str) -> Option<PathBuf> {
+        let file = self.loose_names.get(&lookup_name(name))?;
+        Some(self.loose_root.as_ref()?.join(file))
+    }
+
+    pub fn open(&mut self, name : &str) -> Option<Vec<u8>> {
+        if let Some(path) = self.loose_path(name) {
+            return std::fs::read(path).ok();
+        }
+
+        let (archive_index, entry_index) = *self.names.get(&lookup_name(name))?;
+        let archive = &mut self.archives[archive_index];
+        let info = archive.index.entries[entry_index].info();
+        archive.try_extract(info).ok()
+    }
+
+    // Every name that can be opened, sorted, with names that differ only in case or separators listed once
+    // under the spelling of whichever source takes precedence.
+    pub fn list(&self) -> Vec<String> {
+        let mut names = self.loose_names.clone();
+
+        for archive in &self.archives {
+            for entry in &archive.index.entries {
+                names.entry(lookup_name(&entry.name)).or_insert_with(|| entry.name.clone());
+            }
+        }
+
+        names.into_values().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::CreateOptions;
+
+    #[test]
+    fn loose_files_are_found_ignoring_case_and_separators() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir(root.path().join("Sub")).unwrap();
+        std::fs::write(root.path().join("Sub").join("BG.bmp"), b"loose").unwrap();
+
+        let mut vfs = VirtualFileSystem::new(Some(root.path().to_path_buf()), Vec::new());
+        assert_eq!(vfs.open("sub\\bg.BMP"), Some(b"loose".to_vec()));
+        assert_eq!(vfs.open("SUB/BG.BMP"), Some(b"loose".to_vec()));
+        assert_eq!(vfs.open("sub/missing.bmp"), None);
+        assert_eq!(vfs.list(), vec!["Sub/BG.bmp".to_string()]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn loose_files_with_names_that_arent_utf8_are_skipped() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let root = tempfile::tempdir().unwrap();
+        std::fs::write(root.path().join(std::ffi::OsStr::from_bytes(b"\x82\xa0.txt")), b"").unwrap();
+        std::fs::write(root.path().join("a.txt"), b"").unwrap();
+
+        let mut files = Vec::new();
+        collect_loose_files(root.path(), root.path(), &mut files);
+        assert_eq!(files, vec!["a.txt".to_string()]);
+    }
+
+    #[test]
+    fn catalogs_list_the_entries_of_every_archive() {
+        let source = tempfile::tempdir().unwrap();
+        std::fs::write(source.path().join("a.txt"), b"hello").unwrap();
+        std::fs::write(source.path().join("b.dat"), [7; 300]).unwrap();
+
+        let games = tempfile::tempdir().unwrap();
+        std::fs::create_dir(games.path().join("sub")).unwrap();
+        std::fs::write(games.path().join("readme.txt"), b"not an archive").unwrap();
+        std::fs::write(games.path().join("broken.nsa"), [0, 9]).unwrap();
+
+        let options = CreateOptions { overrides : HashMap::from([(PathBuf::from("b.dat"), Compression::Lzss)]), ..Default::default() };
+        let file = std::fs::File::create(games.path().join("arc.nsa")).unwrap();
+        Archive::create_nsa_archive(file, source.path(), vec!["a.txt".into(), "b.dat".into()], &KeyTable::identity(), &options).unwrap();
+        let file = std::fs::File::create(games.path().join("sub").join("data.sar")).unwrap();
+        Archive::create_sar_archive(file, source.path(), vec!["a.txt".into()], &KeyTable::identity(), &options).unwrap();
+
+        let catalog = catalog_archives(games.path(), &KeyTable::identity());
+        let listed : Vec<_> = catalog.iter()
+            .map(|entry| (entry.archive.strip_prefix(games.path()).unwrap().to_path_buf(), entry.name.as_str(), entry.decompressed_size))
+            .collect();
+
+        assert_eq!(listed, vec![
+            (PathBuf::from("arc.nsa"), "a.txt", Some(5)),
+            (PathBuf::from("arc.nsa"), "b.dat", Some(300)),
+            (PathBuf::from("sub/data.sar"), "a.txt", Some(5))
+        ]);
+        assert!(matches!(catalog[1].compression, Compression::Lzss));
+        assert!(catalog[1].size < 300);
+        assert!(matches!(catalog[2].compression, Compression::None));
+        assert_eq!(catalog[2].size, 5);
+    }
+
+    #[test]
+    fn assets_are_found_in_whichever_archive_has_them_first() {
+        let game = tempfile::tempdir().unwrap();
+        let source = tempfile::tempdir().unwrap();
+
+        for (archive, files) in [("arc.nsa", [("a.txt", "first")].as_slice()), ("arc1.nsa", &[("a.txt", "second"), ("sub/bg.bmp", "only here")])] {
+            let root = source.path().join(archive);
+            for (name, data) in files {
+                std::fs::create_dir_all(root.join(name).parent().unwrap()).unwrap();
+                std::fs::write(root.join(name), data).unwrap();
+            }
+
+            let entries = files.iter().map(|(name, _)| PathBuf::from(name)).collect();
+            let file = std::fs::File::create(game.path().join(archive)).unwrap();
+            Archive::create_nsa_archive(file, &root, entries, &KeyTable::identity(), &CreateOptions::default()).unwrap();
+        }
+
+        let key_table = KeyTable::identity();
+        assert_eq!(find_in_archives(game.path(), "sub\\BG.bmp", &key_table), Some(b"only here".to_vec()));
+        assert_eq!(find_in_archives(game.path(), "a.txt", &key_table), Some(b"first".to_vec()));
+        assert_eq!(find_in_archives(game.path(), "missing.txt", &key_table), None);
+    }
+
+    #[test]
+    fn archive_offsets_are_found_from_the_config_or_the_archive() {
+        let game = tempfile::tempdir().unwrap();
+        let source = tempfile::tempdir().unwrap();
+        std::fs::write(source.path().join("a.txt"), b"hello").unwrap();
+
+        let plain = game.path().join("plain.nsa");
+        let file = std::fs::File::create(&plain).unwrap();
+        Archive::create_nsa_archive(file, source.path(), vec!["a.txt".into()], &KeyTable::identity(), &CreateOptions::default()).unwrap();
+        let archive = std::fs::read(&plain).unwrap();
+        std::fs::remove_file(&plain).unwrap();
+
+        let key_table = KeyTable::identity();
+        std::fs::write(game.path().join("arc.nsa"), &archive).unwrap();
+        assert_eq!(detect_offset_from_dir(game.path(), &key_table), None);
+
+        std::fs::write(game.path().join("arc.nsa"), [b"a launcher's stub".as_slice(), &archive].concat()).unwrap();
+        assert_eq!(detect_offset_from_dir(game.path(), &key_table), Some(17));
+
+        std::fs::write(game.path().join("ons.cfg"), "fullscreen\n nsa-offset=24 \n").unwrap();
+        assert_eq!(detect_offset_from_dir(game.path(), &key_table), Some(24));
+
+        std::fs::write(game.path().join("ons.cfg"), "fullscreen\nnsa-offset=lots\n").unwrap();
+        assert_eq!(detect_offset_from_dir(game.path(), &key_table), Some(17));
+    }
+}